@@ -1,4 +1,7 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
 
 use flume::Sender;
 use tracing::warn;
@@ -7,16 +10,23 @@ use crate::{
     broadcast::Broadcast,
     domain::{
         account::{Account, AccountErrors},
-        events::AllEvents,
+        dispute_policy::DisputePolicy,
+        events::{AllEvents, OperationKind},
         money::Money,
         DomainResult,
     },
     gen_client_extension_methods,
+    store::journal::Journal,
 };
 
-use super::{Actor, CommandEnvelope};
+use super::{
+    clock::{Clock, TokioClock},
+    deadline::Deadline,
+    remote::{self, Address},
+    Actor, ActorError, CommandEnvelope, Lifecycle,
+};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct DepositRequest {
     pub account_id: u32,
     pub transaction_id: u32,
@@ -29,7 +39,7 @@ pub enum DepositResponse {
     Error(AccountErrors),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct WithdrawRequest {
     pub account_id: u32,
     pub transaction_id: u32,
@@ -78,34 +88,115 @@ pub enum ChargebackResponse {
     Error(AccountErrors),
 }
 
+#[derive(Clone, Debug)]
+pub struct FreezeRequest {
+    pub account_id: u32,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug)]
+pub enum FreezeResponse {
+    Ok,
+    Error(AccountErrors),
+}
+
+#[derive(Clone, Debug)]
+pub struct UnfreezeRequest {
+    pub account_id: u32,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug)]
+pub enum UnfreezeResponse {
+    Ok,
+    Error(AccountErrors),
+}
+
+#[derive(Clone, Debug)]
+pub struct CloseRequest {
+    pub account_id: u32,
+}
+
+#[derive(Clone, Debug)]
+pub enum CloseResponse {
+    Ok,
+    Error(AccountErrors),
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Accept;
 
+#[derive(Clone, Debug)]
+pub struct PassivateRequest {
+    pub account_id: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct SnapshotRequest {
+    pub account_id: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct SnapshotResponse(pub Account);
+
 #[derive(Clone)]
 pub struct AccountClient(Sender<Envelope>);
 
+impl AccountClient {
+    // Client of an account served by another process.
+    pub async fn connect(
+        address: &Address,
+        dispute_policy: Arc<dyn DisputePolicy>,
+    ) -> std::io::Result<Self> {
+        Ok(Self(remote::connect(address, dispute_policy).await?))
+    }
+}
+
 gen_client_extension_methods! {
-    impl Account for AccountClient {
+    remote impl Account for AccountClient {
         fn deposit(_: DepositRequest) -> DepositResponse;
         fn withdraw(_: WithdrawRequest) -> WithdrawResponse;
         fn dispute(_: DisputeRequest) -> DisputeResponse;
         fn resolve(_: ResolveRequest) -> ResolveResponse;
         fn chargeback(_: ChargebackRequest) -> ChargebackResponse;
+        fn freeze(_: FreezeRequest) -> FreezeResponse;
+        fn unfreeze(_: UnfreezeRequest) -> UnfreezeResponse;
+        fn close(_: CloseRequest) -> CloseResponse;
         fn accept_request(_: Accept) -> Accept;
+        fn passivate(_: PassivateRequest) -> Account;
+        fn snapshot(_: SnapshotRequest) -> SnapshotResponse;
     }
 }
 
 impl AccountRequests {
     pub fn get_account_id(&self) -> u32 {
+        self.account_id()
+            .expect("This message does not have account_id.")
+    }
+
+    // None for messages meant for the actor itself, not for an account.
+    pub fn account_id(&self) -> Option<u32> {
         match self {
-            AccountRequests::DepositRequest(x) => x.account_id,
-            AccountRequests::WithdrawRequest(x) => x.account_id,
-            AccountRequests::DisputeRequest(x) => x.account_id,
-            AccountRequests::ResolveRequest(x) => x.account_id,
-            AccountRequests::ChargebackRequest(x) => x.account_id,
-            AccountRequests::AcceptRequestRequest(_) => {
-                panic!("This message does not have account_id.")
-            }
+            AccountRequests::DepositRequest(x) => Some(x.account_id),
+            AccountRequests::WithdrawRequest(x) => Some(x.account_id),
+            AccountRequests::DisputeRequest(x) => Some(x.account_id),
+            AccountRequests::ResolveRequest(x) => Some(x.account_id),
+            AccountRequests::ChargebackRequest(x) => Some(x.account_id),
+            AccountRequests::FreezeRequest(x) => Some(x.account_id),
+            AccountRequests::UnfreezeRequest(x) => Some(x.account_id),
+            AccountRequests::CloseRequest(x) => Some(x.account_id),
+            AccountRequests::PassivateRequest(x) => Some(x.account_id),
+            AccountRequests::SnapshotRequest(x) => Some(x.account_id),
+            AccountRequests::AcceptRequestRequest(_) | AccountRequests::Lifecycle(_) => None,
+        }
+    }
+
+    pub fn is_replay_of(&self, other: &AccountRequests) -> bool {
+        use AccountRequests::*;
+        match (self, other) {
+            (DepositRequest(l), DepositRequest(r)) => l == r,
+            (WithdrawRequest(l), WithdrawRequest(r)) => l == r,
+            _ => false,
         }
     }
 
@@ -116,26 +207,134 @@ impl AccountRequests {
             AccountRequests::DisputeRequest(x) => x.transaction_id,
             AccountRequests::ResolveRequest(x) => x.transaction_id,
             AccountRequests::ChargebackRequest(x) => x.transaction_id,
-            AccountRequests::AcceptRequestRequest(_) => {
+            AccountRequests::FreezeRequest(_)
+            | AccountRequests::UnfreezeRequest(_)
+            | AccountRequests::CloseRequest(_)
+            | AccountRequests::PassivateRequest(_)
+            | AccountRequests::SnapshotRequest(_)
+            | AccountRequests::AcceptRequestRequest(_)
+            | AccountRequests::Lifecycle(_) => {
                 panic!("This message does not have transaction_id.")
             }
         }
     }
 }
 
+impl AccountResponses {
+    // Rejections by the account become `ActorError::Domain`, so callers
+    // handle them the same way as failures to deliver the request.
+    pub fn into_result(self) -> Result<Self, ActorError> {
+        match self {
+            AccountResponses::DepositResponse(DepositResponse::Error(err))
+            | AccountResponses::WithdrawResponse(WithdrawResponse::Error(err))
+            | AccountResponses::DisputeResponse(DisputeResponse::Error(err))
+            | AccountResponses::ResolveResponse(ResolveResponse::Error(err))
+            | AccountResponses::ChargebackResponse(ChargebackResponse::Error(err))
+            | AccountResponses::FreezeResponse(FreezeResponse::Error(err))
+            | AccountResponses::UnfreezeResponse(UnfreezeResponse::Error(err))
+            | AccountResponses::CloseResponse(CloseResponse::Error(err)) => {
+                Err(ActorError::Domain(err))
+            }
+            AccountResponses::Error(err) => Err(err),
+            response => Ok(response),
+        }
+    }
+
+    // The error the account rejected the request with, if it did.
+    fn rejection(&self) -> Option<AccountErrors> {
+        match self.clone().into_result() {
+            Err(ActorError::Domain(err)) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 enum Disputes {
-    Dispute(DisputeRequest, Sender<AccountResponses>),
-    Resolve(ResolveRequest, Sender<AccountResponses>),
-    Chargeback(ChargebackRequest, Sender<AccountResponses>),
+    Dispute(DisputeRequest, Sender<AccountResponses>, Deadline),
+    Resolve(ResolveRequest, Sender<AccountResponses>, Deadline),
+    Chargeback(ChargebackRequest, Sender<AccountResponses>, Deadline),
+}
+
+// What happens to a request that arrives after newer requests were applied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LateArrivalPolicy {
+    // Applied as it comes, unless it is older than the last checkpoint.
+    Reject,
+    // Always applied as it comes.
+    ApplyAnyway,
+    // Inserted at its correct position and everything after it is re-executed,
+    // unless it is older than the last checkpoint.
+    Replay,
+}
+
+// When requests are accepted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scheduling {
+    // After waiting the reorder window.
+    ReorderWindow,
+    // As soon as they arrive. The sender is responsible for sending
+    // them ordered by transaction id and waiting for each response.
+    Deterministic,
 }
 
+#[derive(Clone, Debug)]
+pub struct AccountActorConfig {
+    pub scheduling: Scheduling,
+    // How long requests wait to be accepted, so they can be reordered.
+    pub reorder_window: std::time::Duration,
+    pub clock: Arc<dyn Clock>,
+    // Requests waiting to be accepted. When full, the oldest is accepted
+    // right away to make room.
+    pub max_pending_requests: usize,
+    // Applied requests kept before a new checkpoint is taken.
+    // Nothing older than the last checkpoint can be reordered.
+    pub checkpoint_interval: usize,
+    pub late_arrival: LateArrivalPolicy,
+    // Where applied requests are written before they are applied.
+    pub journal: Option<Arc<Journal>>,
+    // Of each account actor. Unbounded when None.
+    pub mailbox_capacity: Option<usize>,
+}
+
+impl Default for AccountActorConfig {
+    fn default() -> Self {
+        Self {
+            scheduling: Scheduling::ReorderWindow,
+            reorder_window: std::time::Duration::from_millis(100),
+            clock: Arc::new(TokioClock),
+            max_pending_requests: 1024,
+            checkpoint_interval: 1024,
+            late_arrival: LateArrivalPolicy::ApplyAnyway,
+            journal: None,
+            mailbox_capacity: None,
+        }
+    }
+}
+
+// Requests are ordered by transaction id; for the same transaction
+// the operation itself comes before its disputes; then arrival order.
+type HistoryKey = (u32, u8, u64);
+
+// Callers of a scheduled request, replays included
+type Waiting = Vec<(Sender<AccountResponses>, Deadline)>;
+
 pub struct AccountActor {
     account: Account,
-    requests: BTreeMap<u32, (AccountRequests, Sender<AccountResponses>)>,
+    requests: BTreeMap<u32, (AccountRequests, Waiting)>,
     disputes: BTreeMap<u32, Vec<Disputes>>,
+    // Administrative operations, after the highest transaction id known when they arrived
+    admin: VecDeque<(u32, AccountRequests, Sender<AccountResponses>, Deadline)>,
     broadcast: Broadcast<AllEvents>,
     sender: flume::Sender<CommandEnvelope<AccountRequests, AccountResponses>>,
+    config: AccountActorConfig,
+    checkpoint: Account,
+    checkpoint_key: Option<HistoryKey>,
+    // Requests applied since the checkpoint, and what they were rejected with
+    history: BTreeMap<HistoryKey, (AccountRequests, Option<AccountErrors>)>,
+    sequence: u64,
+    last_transaction_id: u32,
+    stopped: bool,
 }
 
 impl std::fmt::Debug for AccountActor {
@@ -143,6 +342,8 @@ impl std::fmt::Debug for AccountActor {
         f.debug_struct("AccountActor")
             .field("account", &self.account)
             .field("broadcast", &"...")
+            .field("config", &self.config)
+            .field("history", &self.history.len())
             .finish()
     }
 }
@@ -165,24 +366,70 @@ impl Actor<AccountRequests, AccountResponses> for AccountActor {
         self.sender = sender;
     }
 
+    fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    fn mailbox_capacity(&self) -> Option<usize> {
+        self.config.mailbox_capacity
+    }
+
     #[tracing::instrument(skip(self))]
     async fn handle_request(
         &mut self,
         request: AccountRequests,
         callback: Sender<AccountResponses>,
+        deadline: Deadline,
     ) {
+        // Slow subscribers hold back new events instead of losing them
+        self.broadcast.ready(self.account.id()).await;
+        if Self::expire(&callback, &deadline).await {
+            return;
+        }
+
         use AccountRequests::*;
         match request {
             AcceptRequestRequest(_) => self.accept_request().await,
+            PassivateRequest(_) => {
+                let account = self.passivate().await;
+                let _ = callback.send_async(account.into()).await;
+            }
+            SnapshotRequest(_) => {
+                let account = self.snapshot().await;
+                let _ = callback.send_async(SnapshotResponse(account).into()).await;
+            }
 
-            DepositRequest(_) => self.schedule_request(request, callback),
-            WithdrawRequest(_) => self.schedule_request(request, callback),
+            DepositRequest(_) => self.schedule_request(request, callback, deadline).await,
+            WithdrawRequest(_) => self.schedule_request(request, callback, deadline).await,
 
-            DisputeRequest(r) => self.add_dispute(r.transaction_id, Disputes::Dispute(r, callback)),
-            ResolveRequest(r) => self.add_dispute(r.transaction_id, Disputes::Resolve(r, callback)),
+            DisputeRequest(r) => {
+                let transaction_id = r.transaction_id;
+                let dispute = Disputes::Dispute(r, callback, deadline);
+                self.add_dispute(transaction_id, dispute).await
+            }
+            ResolveRequest(r) => {
+                let transaction_id = r.transaction_id;
+                let resolve = Disputes::Resolve(r, callback, deadline);
+                self.add_dispute(transaction_id, resolve).await
+            }
             ChargebackRequest(r) => {
-                self.add_dispute(r.transaction_id, Disputes::Chargeback(r, callback))
+                let transaction_id = r.transaction_id;
+                let chargeback = Disputes::Chargeback(r, callback, deadline);
+                self.add_dispute(transaction_id, chargeback).await
             }
+
+            FreezeRequest(_) | UnfreezeRequest(_) | CloseRequest(_) => {
+                self.schedule_admin(request, callback, deadline).await
+            }
+
+            Lifecycle(_) => unreachable!("Lifecycle messages are handled by Actor::handle"),
+        }
+    }
+
+    async fn handle_lifecycle(&mut self, lifecycle: Lifecycle) {
+        self.snapshot().await;
+        if lifecycle == Lifecycle::Stop {
+            self.stopped = true;
         }
     }
 }
@@ -190,13 +437,62 @@ impl Actor<AccountRequests, AccountResponses> for AccountActor {
 impl AccountActor {
     pub fn new(account: Account, broadcast: Broadcast<AllEvents>) -> Self {
         let (sender, _) = flume::unbounded();
+        broadcast.resume(account.id(), account.sequence());
         Self {
+            checkpoint: account.clone(),
             account,
             broadcast,
             requests: BTreeMap::new(),
             sender,
             disputes: BTreeMap::new(),
+            admin: VecDeque::new(),
+            config: AccountActorConfig::default(),
+            checkpoint_key: None,
+            history: BTreeMap::new(),
+            sequence: 0,
+            last_transaction_id: 0,
+            stopped: false,
+        }
+    }
+
+    // Restored accounts have no history,
+    // so nothing older than their newest transaction can be reordered.
+    // Disputes of restored transactions still can.
+    pub fn restore(account: Account, broadcast: Broadcast<AllEvents>) -> Self {
+        let last_transaction_id = account.last_transaction_id();
+        Self {
+            checkpoint_key: last_transaction_id.map(|id| (id, 0, u64::MAX)),
+            last_transaction_id: last_transaction_id.unwrap_or(0),
+            ..Self::new(account, broadcast)
+        }
+    }
+
+    // Accepts everything pending and stops the actor.
+    #[tracing::instrument(skip(self))]
+    pub async fn passivate(&mut self) -> Account {
+        let account = self.snapshot().await;
+        self.stopped = true;
+        account
+    }
+
+    // Accepts everything pending, so the copy has every request received so far.
+    pub async fn snapshot(&mut self) -> Account {
+        while self.pending_len() > 0 {
+            self.accept_request().await;
         }
+        self.account.clone()
+    }
+
+    pub fn with_config(mut self, config: AccountActorConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    // Where events go from now on.
+    pub fn with_broadcast(mut self, broadcast: Broadcast<AllEvents>) -> Self {
+        broadcast.resume(self.account.id(), self.account.sequence());
+        self.broadcast = broadcast;
+        self
     }
 
     #[tracing::instrument(skip(self), ret)]
@@ -207,10 +503,13 @@ impl AccountActor {
     ) -> DepositResponse {
         match self.account.deposit(transaction_id, deposit.amount) {
             DomainResult::Ok { mut events, .. } => {
-                self.broadcast.broadcast_all(events.drain(..));
+                self.publish(events.drain(..));
                 DepositResponse::Ok
             }
-            DomainResult::Err(err) => DepositResponse::Error(err),
+            DomainResult::Err(err) => {
+                self.broadcast_rejected(Some(transaction_id), OperationKind::Deposit, &err);
+                DepositResponse::Error(err)
+            }
         }
     }
 
@@ -222,10 +521,13 @@ impl AccountActor {
     ) -> WithdrawResponse {
         match self.account.withdraw(transaction_id, withdraw.amount) {
             DomainResult::Ok { mut events, .. } => {
-                self.broadcast.broadcast_all(events.drain(..));
+                self.publish(events.drain(..));
                 WithdrawResponse::Ok
             }
-            DomainResult::Err(err) => WithdrawResponse::Error(err),
+            DomainResult::Err(err) => {
+                self.broadcast_rejected(Some(transaction_id), OperationKind::Withdraw, &err);
+                WithdrawResponse::Error(err)
+            }
         }
     }
 
@@ -237,10 +539,13 @@ impl AccountActor {
     ) -> DisputeResponse {
         match self.account.dispute(transaction_id) {
             DomainResult::Ok { mut events, .. } => {
-                self.broadcast.broadcast_all(events.drain(..));
+                self.publish(events.drain(..));
                 DisputeResponse::Ok
             }
-            DomainResult::Err(err) => DisputeResponse::Error(err),
+            DomainResult::Err(err) => {
+                self.broadcast_rejected(Some(transaction_id), OperationKind::Dispute, &err);
+                DisputeResponse::Error(err)
+            }
         }
     }
 
@@ -252,10 +557,13 @@ impl AccountActor {
     ) -> ResolveResponse {
         match self.account.resolve(transaction_id) {
             DomainResult::Ok { mut events, .. } => {
-                self.broadcast.broadcast_all(events.drain(..));
+                self.publish(events.drain(..));
                 ResolveResponse::Ok
             }
-            DomainResult::Err(err) => ResolveResponse::Error(err),
+            DomainResult::Err(err) => {
+                self.broadcast_rejected(Some(transaction_id), OperationKind::Resolve, &err);
+                ResolveResponse::Error(err)
+            }
         }
     }
 
@@ -267,99 +575,254 @@ impl AccountActor {
     ) -> ChargebackResponse {
         match self.account.chargeback(transaction_id) {
             DomainResult::Ok { mut events, .. } => {
-                self.broadcast.broadcast_all(events.drain(..));
+                self.publish(events.drain(..));
                 ChargebackResponse::Ok
             }
-            DomainResult::Err(err) => ChargebackResponse::Error(err),
+            DomainResult::Err(err) => {
+                self.broadcast_rejected(Some(transaction_id), OperationKind::Chargeback, &err);
+                ChargebackResponse::Error(err)
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self), ret)]
+    pub fn handle_freeze(&mut self, freeze: FreezeRequest) -> FreezeResponse {
+        match self.account.freeze(freeze.reason) {
+            DomainResult::Ok { mut events, .. } => {
+                self.publish(events.drain(..));
+                FreezeResponse::Ok
+            }
+            DomainResult::Err(err) => {
+                self.broadcast_rejected(None, OperationKind::Freeze, &err);
+                FreezeResponse::Error(err)
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self), ret)]
+    pub fn handle_unfreeze(&mut self, unfreeze: UnfreezeRequest) -> UnfreezeResponse {
+        match self.account.unfreeze(unfreeze.reason) {
+            DomainResult::Ok { mut events, .. } => {
+                self.publish(events.drain(..));
+                UnfreezeResponse::Ok
+            }
+            DomainResult::Err(err) => {
+                self.broadcast_rejected(None, OperationKind::Unfreeze, &err);
+                UnfreezeResponse::Error(err)
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self), ret)]
+    pub fn handle_close(&mut self, close: CloseRequest) -> CloseResponse {
+        match self.account.close() {
+            DomainResult::Ok { mut events, .. } => {
+                self.publish(events.drain(..));
+                CloseResponse::Ok
+            }
+            DomainResult::Err(err) => {
+                self.broadcast_rejected(None, OperationKind::Close, &err);
+                CloseResponse::Error(err)
+            }
         }
     }
 
     // To allow out of order delivery of accounts operations, when a request
-    // arrives, we wait 100ms before accepting it.
+    // arrives, we wait the reorder window before accepting it.
     // I am not 100% sure of optimize is to one spawn per message here. Tokio
     // correctly implements Timing Wheel (https://github.com/tokio-rs/tokio/blob/master/tokio/src/time/driver/wheel/mod.rs),
     // SO my bet this is fine.
     #[tracing::instrument(skip(self))]
-    pub fn schedule_request(
+    pub async fn schedule_request(
         &mut self,
         request: AccountRequests,
         callback: Sender<AccountResponses>,
+        deadline: Deadline,
     ) {
+        self.make_room().await;
+
+        use std::collections::btree_map::Entry;
         let current_transaction_id = request.get_transaction_id();
-        let _ = self
-            .requests
-            .insert(current_transaction_id, (request, callback));
+        match self.requests.entry(current_transaction_id) {
+            Entry::Vacant(entry) => {
+                entry.insert((request, vec![(callback, deadline)]));
+            }
+            // Replays are answered together with the pending request.
+            // It is already scheduled, so there is nothing else to do.
+            Entry::Occupied(mut entry) if entry.get().0.is_replay_of(&request) => {
+                entry.get_mut().1.push((callback, deadline));
+                return;
+            }
+            Entry::Occupied(_) => {
+                let response = self.reject(&request, AccountErrors::DuplicateTransactionId);
+                let _ = callback.send_async(response).await;
+                return;
+            }
+        }
+
+        self.schedule_accept().await;
+    }
+
+    async fn schedule_accept(&mut self) {
+        if self.config.scheduling == Scheduling::Deterministic {
+            self.accept_request().await;
+            return;
+        }
 
         let sender = self.sender.clone();
+        let sleep = self.config.clock.sleep(self.config.reorder_window);
         tokio::task::spawn(async move {
-            tokio::time::sleep(std::time::Duration::from_millis(100)).await; //TODO magic number
+            sleep.await;
             let (callback_sender, callback_recv) = flume::bounded(1);
             let _ = sender
-                .send_async(CommandEnvelope {
-                    payload: AccountRequests::AcceptRequestRequest(Accept),
-                    callback: callback_sender,
-                })
+                .send_async(CommandEnvelope::new(
+                    AccountRequests::AcceptRequestRequest(Accept),
+                    callback_sender,
+                ))
                 .await;
             let _ = callback_recv.recv_async().await;
         });
     }
 
+    // Accepts the oldest pending requests until there is room for one more.
+    // Their scheduled accepts will later find nothing to do.
+    async fn make_room(&mut self) {
+        while self.pending_len() >= self.config.max_pending_requests.max(1) {
+            self.accept_request().await;
+        }
+    }
+
+    fn pending_len(&self) -> usize {
+        self.requests.len() + self.disputes.values().map(Vec::len).sum::<usize>() + self.admin.len()
+    }
+
+    // Administrative operations have no transaction id. They wait the reorder
+    // window too, and happen after every operation known when they arrive,
+    // pending ones included.
+    async fn schedule_admin(
+        &mut self,
+        request: AccountRequests,
+        callback: Sender<AccountResponses>,
+        deadline: Deadline,
+    ) {
+        self.make_room().await;
+
+        let after = [
+            Some(self.last_transaction_id),
+            self.requests.keys().next_back().copied(),
+            self.disputes.keys().next_back().copied(),
+            self.admin.back().map(|x| x.0),
+        ]
+        .into_iter()
+        .flatten()
+        .max()
+        .unwrap_or(0);
+        self.admin.push_back((after, request, callback, deadline));
+
+        self.schedule_accept().await;
+    }
+
+    fn admin_before(&self, transaction_id: u32) -> bool {
+        self.admin
+            .front()
+            .is_some_and(|(after, ..)| *after < transaction_id)
+    }
+
+    async fn accept_admin(&mut self) {
+        let (after, request, callback, deadline) = match self.admin.pop_front() {
+            Some(admin) => admin,
+            None => return,
+        };
+        if Self::expire(&callback, &deadline).await {
+            return;
+        }
+
+        // Its place in the history is after the operations it came after
+        self.last_transaction_id = self.last_transaction_id.max(after);
+        let r = self.execute(request);
+        let _ = callback.send_async(r).await;
+    }
+
     // Now we pop the earlier request, order by transaction id,
     // and accept it.
     #[tracing::instrument(skip(self))]
     pub async fn accept_request(&mut self) {
+        let next = [
+            self.requests.keys().next().copied(),
+            self.disputes.keys().next().copied(),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        if !self.admin.is_empty() && next.is_none_or(|key| self.admin_before(key)) {
+            self.accept_admin().await;
+            return;
+        }
+
         // Ideally ```self.requests.pop_first()```, but it is still unstable.
+        // Disputes can be pending alone, so an empty queue is not the end.
         let item = match self.requests.iter().next().map(|x| *x.0) {
-            None => return,
-            Some(key) => self.requests.remove(&key),
+            Some(key) if !self.admin_before(key) => self.requests.remove(&key),
+            _ => None,
         };
 
-        if let Some((request, callback)) = item {
-            let transaction_id = request.get_transaction_id();
-            use AccountRequests::*;
-            let response: AccountResponses = match request {
-                DepositRequest(r) => self.handle_deposit(transaction_id, r).into(),
-                WithdrawRequest(r) => self.handle_withdraw(transaction_id, r).into(),
-                _ => unreachable!("Should never postpone non account operations"),
-            };
+        if let Some((request, callbacks)) = item {
+            let mut waiting = vec![];
+            for (callback, deadline) in callbacks {
+                if !Self::expire(&callback, &deadline).await {
+                    waiting.push(callback);
+                }
+            }
 
-            let _ = callback.send_async(response).await;
+            // Nobody waits for it anymore, so it is skipped
+            if !waiting.is_empty() {
+                let response = self.execute(request);
+                for callback in waiting {
+                    let _ = callback.send_async(response.clone()).await;
+                }
+            }
         }
 
         // Now run all known disputes into this transaction
         // disputes here can be out of order, so...
         let disputes = match self.disputes.iter().next().map(|x| *x.0) {
-            None => return,
-            Some(key) => self.disputes.remove(&key),
+            Some(key) if !self.admin_before(key) => self.disputes.remove(&key),
+            _ => return,
         };
 
         if let Some(disputes) = disputes {
             // we first accept disputes...
             for dispute in disputes.iter() {
-                match dispute {
-                    Disputes::Dispute(r, callback) => {
-                        let r = self.handle_dispute(r.transaction_id, r.clone());
-                        let _ = callback.send_async(r.into()).await;
+                if let Disputes::Dispute(r, callback, deadline) = dispute {
+                    if Self::expire(callback, deadline).await {
+                        continue;
                     }
-                    _ => {}
+                    let r = self.execute(r.clone().into());
+                    let _ = callback.send_async(r).await;
                 }
             }
 
             // then we solve them.
             for dispute in disputes {
                 match dispute {
-                    Disputes::Resolve(r, callback) => {
-                        let r = self.handle_resolve(r.transaction_id, r);
-                        let _ = callback.send_async(r.into()).await;
+                    Disputes::Resolve(r, callback, deadline) => {
+                        if Self::expire(&callback, &deadline).await {
+                            continue;
+                        }
+                        let r = self.execute(r.into());
+                        let _ = callback.send_async(r).await;
                     }
                     // If the chargeback arrives before the real operation
                     // we have a problem. This will lock the account and all
                     // subsequent operations will fail.
                     // On real life this would never happen, because the operation,
                     // the dispute and the carhgeback would need to happen in 100ms or less.
-                    Disputes::Chargeback(r, callback) => {
-                        let r = self.handle_chargeback(r.transaction_id, r);
-                        let _ = callback.send_async(r.into()).await;
+                    Disputes::Chargeback(r, callback, deadline) => {
+                        if Self::expire(&callback, &deadline).await {
+                            continue;
+                        }
+                        let r = self.execute(r.into());
+                        let _ = callback.send_async(r).await;
                     }
                     _ => {}
                 }
@@ -367,97 +830,958 @@ impl AccountActor {
         }
     }
 
-    fn add_dispute(&mut self, transaction_id: u32, dispute: Disputes) {
-        let disputes = self.disputes.entry(transaction_id).or_default();
-        disputes.push(dispute);
+    // Answers requests whose deadline expired, so they are not executed late.
+    async fn expire(callback: &Sender<AccountResponses>, deadline: &Deadline) -> bool {
+        match deadline.check() {
+            Ok(()) => false,
+            Err(err) => {
+                let _ = callback.send_async(AccountResponses::Error(err)).await;
+                true
+            }
+        }
+    }
 
-        let sender = self.sender.clone();
-        tokio::task::spawn(async move {
-            tokio::time::sleep(std::time::Duration::from_millis(100)).await; //TODO magic number
-            let (callback_sender, callback_recv) = flume::bounded(1);
-            let _ = sender
-                .send_async(CommandEnvelope {
-                    payload: AccountRequests::AcceptRequestRequest(Accept),
-                    callback: callback_sender,
-                })
-                .await;
-            let _ = callback_recv.recv_async().await;
-        });
+    // Journals the request before executing it.
+    // Requests that cannot be journaled are not executed,
+    // otherwise they would be lost after a crash.
+    fn execute(&mut self, request: AccountRequests) -> AccountResponses {
+        if let Some(journal) = &self.config.journal {
+            if let Err(err) = journal.append(&request) {
+                tracing::error!("Request not journaled {:?}: {:?}", request, err);
+                return AccountResponses::Error(err.into());
+            }
+        }
+        self.execute_journaled(request)
     }
-}
 
-#[cfg(test)]
-mod tests {
+    // Applies the request and records it in the history.
+    // What happens to late requests depends on the late arrival policy.
+    // Executing a journal in order rebuilds the same account.
+    pub fn execute_journaled(&mut self, request: AccountRequests) -> AccountResponses {
+        let policy = self.config.late_arrival;
 
-    use crate::{
-        actors::{
-            account::{DepositResponse, WithdrawResponse},
-            init_log, Actor, Spawn,
-        },
-        broadcast::Broadcast,
-        domain::{account::Account, money::Currency::*},
-    };
+        // Replays and conflicting ids never change the account,
+        // so there is no need to remember them.
+        let already_applied = matches!(
+            request,
+            AccountRequests::DepositRequest(_) | AccountRequests::WithdrawRequest(_)
+        ) && self
+            .account
+            .get_transaction(request.get_transaction_id())
+            .is_some();
+        if already_applied {
+            return self.apply_request(request);
+        }
 
-    use super::{AccountActor, DepositRequest, WithdrawRequest};
+        let key = self.history_key(&request);
+        let too_late = self
+            .checkpoint_key
+            .is_some_and(|checkpoint| key < checkpoint);
+        if too_late {
+            warn!("Request older than last checkpoint: {:?}", request);
+            return match policy {
+                LateArrivalPolicy::ApplyAnyway => self.apply_request(request),
+                LateArrivalPolicy::Reject | LateArrivalPolicy::Replay => {
+                    self.reject(&request, AccountErrors::LateArrival)
+                }
+            };
+        }
 
-    #[tokio::test]
-    pub async fn err_incorrectly_waiting_on_out_of_order() {
-        init_log();
+        let is_late = self
+            .history
+            .keys()
+            .next_back()
+            .is_some_and(|last| key < *last);
+        self.history.insert(key, (request.clone(), None));
 
-        let broadcast = Broadcast::new();
-        let account = Account::new(0);
-        let account = AccountActor::new(account, broadcast.clone()).spawn();
+        let response = if is_late && policy == LateArrivalPolicy::Replay {
+            self.reexecute_from(key)
+        } else {
+            self.apply_request(request)
+        };
+        if let Some(entry) = self.history.get_mut(&key) {
+            entry.1 = response.rejection();
+        }
 
-        // Because account accepts out of order delivery
-        // we cannot .await this sends, otherwise we will be
-        // waiting them to be accepted
-        // This is why this part fails
-        let response = account
-            .send_withdraw_async(WithdrawRequest {
-                account_id: 0,
-                transaction_id: 1,
-                amount: 0.5 * Bitcoin,
-            })
-            .await;
-        assert!(matches!(response, Ok(WithdrawResponse::Error(_))));
+        if self.history.len() >= self.config.checkpoint_interval {
+            self.checkpoint();
+        }
 
-        let response = account
-            .send_deposit_async(DepositRequest {
-                account_id: 0,
-                transaction_id: 0,
-                amount: 1 * Bitcoin,
-            })
-            .await;
-        assert!(matches!(response, Ok(DepositResponse::Ok)));
+        response
     }
 
-    #[tokio::test]
-    pub async fn ok_correctly_sending_out_of_order() {
-        init_log();
+    fn history_key(&mut self, request: &AccountRequests) -> HistoryKey {
+        use AccountRequests::*;
+        self.sequence += 1;
+        match request {
+            DepositRequest(_) | WithdrawRequest(_) => {
+                let transaction_id = request.get_transaction_id();
+                self.last_transaction_id = self.last_transaction_id.max(transaction_id);
+                (transaction_id, 0, self.sequence)
+            }
+            // Disputes of known transactions happen after everything known so far,
+            // otherwise a checkpoint would make them look late. Unknown ones never
+            // go past it either, or a checkpoint would turn every lower transaction away.
+            DisputeRequest(_) | ResolveRequest(_) | ChargebackRequest(_) => {
+                let transaction_id = request.get_transaction_id();
+                if self.account.get_transaction(transaction_id).is_some() {
+                    (self.last_transaction_id, 1, self.sequence)
+                } else {
+                    (
+                        transaction_id.min(self.last_transaction_id),
+                        1,
+                        self.sequence,
+                    )
+                }
+            }
+            // Administrative operations happen after everything known so far
+            _ => (self.last_transaction_id, 2, self.sequence),
+        }
+    }
 
-        let broadcast = Broadcast::new();
-        let account = Account::new(0);
-        let account = AccountActor::new(account, broadcast.clone()).spawn();
+    // Rebuilds the account up to `key` and re-executes the suffix.
+    // Aggregators are told to forget what they saw after that point,
+    // and callers of requests whose outcome changed are told the new one.
+    fn reexecute_from(&mut self, key: HistoryKey) -> AccountResponses {
+        let mut account = self.checkpoint.clone();
+        for (request, _) in self.history.range(..key).map(|x| x.1) {
+            let _ = Self::run(&mut account, request);
+        }
+        self.account = account;
+        self.broadcast_rewound(key.0);
 
-        // see [err_incorrectly_waiting_on_out_of_order]
-        // Now we correctly send and deal with out of order.
-        let response1 = account
-            .send_withdraw_async(WithdrawRequest {
-                account_id: 0,
-                transaction_id: 1,
-                amount: 0.5 * Bitcoin,
-            })
-            .spawn();
+        let suffix: Vec<_> = self
+            .history
+            .range(key..)
+            .map(|(k, (r, rejection))| (*k, r.clone(), rejection.clone()))
+            .collect();
+        let mut response = None;
+        for (k, request, before) in suffix {
+            let r = self.apply_request(request.clone());
+            if k == key {
+                response = Some(r);
+                continue;
+            }
 
-        let response2 = account
-            .send_deposit_async(DepositRequest {
-                account_id: 0,
-                transaction_id: 0,
+            let after = r.rejection();
+            if after != before {
+                self.broadcast_changed(&request, after.clone());
+                if let Some(entry) = self.history.get_mut(&k) {
+                    entry.1 = after;
+                }
+            }
+        }
+
+        response.expect("Late request is in the history")
+    }
+
+    // Subscribers take the account as it is now, whatever they saw before.
+    fn broadcast_rewound(&mut self, transaction_id: u32) {
+        let parts = self.account.to_parts();
+        self.publish(std::iter::once(AllEvents::HistoryRewound {
+            account_id: parts.id,
+            transaction_id,
+            balances: self.account.balances(),
+            transactions: parts.transactions,
+            locked: parts.locked,
+            closed: parts.closed,
+        }));
+    }
+
+    fn broadcast_changed(&mut self, request: &AccountRequests, error: Option<AccountErrors>) {
+        let (operation, transaction_id) = Self::operation(request);
+        self.publish(std::iter::once(AllEvents::OutcomeChanged {
+            account_id: self.account.id(),
+            transaction_id,
+            operation,
+            error,
+        }));
+    }
+
+    // What the request does, and to which transaction if any.
+    fn operation(request: &AccountRequests) -> (OperationKind, Option<u32>) {
+        use AccountRequests::*;
+        match request {
+            DepositRequest(r) => (OperationKind::Deposit, Some(r.transaction_id)),
+            WithdrawRequest(r) => (OperationKind::Withdraw, Some(r.transaction_id)),
+            DisputeRequest(r) => (OperationKind::Dispute, Some(r.transaction_id)),
+            ResolveRequest(r) => (OperationKind::Resolve, Some(r.transaction_id)),
+            ChargebackRequest(r) => (OperationKind::Chargeback, Some(r.transaction_id)),
+            FreezeRequest(_) => (OperationKind::Freeze, None),
+            UnfreezeRequest(_) => (OperationKind::Unfreeze, None),
+            CloseRequest(_) => (OperationKind::Close, None),
+            AcceptRequestRequest(_) | PassivateRequest(_) | SnapshotRequest(_) | Lifecycle(_) => {
+                unreachable!("Not an account operation")
+            }
+        }
+    }
+
+    // For accounts that come back without their events, like the ones
+    // rebuilt from the journal. Untouched accounts have nothing to tell.
+    pub fn announce(&mut self) {
+        if self.account.to_parts() != Account::new(self.account.id()).to_parts() {
+            self.broadcast_rewound(self.last_transaction_id);
+        }
+    }
+
+    // The current state becomes the new starting point for re-executions.
+    // Nothing older than the newest request applied so far can be reordered anymore.
+    fn checkpoint(&mut self) {
+        self.checkpoint = self.account.clone();
+        if let Some(key) = self.history.keys().next_back() {
+            self.checkpoint_key = Some(*key);
+        }
+        self.history.clear();
+    }
+
+    fn apply_request(&mut self, request: AccountRequests) -> AccountResponses {
+        use AccountRequests::*;
+        match request {
+            DepositRequest(r) => self.handle_deposit(r.transaction_id, r).into(),
+            WithdrawRequest(r) => self.handle_withdraw(r.transaction_id, r).into(),
+            DisputeRequest(r) => self.handle_dispute(r.transaction_id, r).into(),
+            ResolveRequest(r) => self.handle_resolve(r.transaction_id, r).into(),
+            ChargebackRequest(r) => self.handle_chargeback(r.transaction_id, r).into(),
+            FreezeRequest(r) => self.handle_freeze(r).into(),
+            UnfreezeRequest(r) => self.handle_unfreeze(r).into(),
+            CloseRequest(r) => self.handle_close(r).into(),
+            AcceptRequestRequest(_) | PassivateRequest(_) | SnapshotRequest(_) | Lifecycle(_) => {
+                unreachable!("Not an account operation")
+            }
+        }
+    }
+
+    // Same as `apply_request`, but without broadcasting anything.
+    fn run(
+        account: &mut Account,
+        request: &AccountRequests,
+    ) -> DomainResult<(), AccountErrors, AllEvents> {
+        use AccountRequests::*;
+        match request {
+            DepositRequest(r) => account.deposit(r.transaction_id, r.amount),
+            WithdrawRequest(r) => account.withdraw(r.transaction_id, r.amount),
+            DisputeRequest(r) => account.dispute(r.transaction_id),
+            ResolveRequest(r) => account.resolve(r.transaction_id),
+            ChargebackRequest(r) => account.chargeback(r.transaction_id),
+            FreezeRequest(r) => account.freeze(r.reason.clone()),
+            UnfreezeRequest(r) => account.unfreeze(r.reason.clone()),
+            CloseRequest(_) => account.close(),
+            AcceptRequestRequest(_) | PassivateRequest(_) | SnapshotRequest(_) | Lifecycle(_) => {
+                unreachable!("Not an account operation")
+            }
+        }
+    }
+
+    fn reject(&mut self, request: &AccountRequests, err: AccountErrors) -> AccountResponses {
+        use AccountRequests::*;
+        let (operation, transaction_id) = Self::operation(request);
+        self.broadcast_rejected(transaction_id, operation, &err);
+        match request {
+            DepositRequest(_) => DepositResponse::Error(err).into(),
+            WithdrawRequest(_) => WithdrawResponse::Error(err).into(),
+            DisputeRequest(_) => DisputeResponse::Error(err).into(),
+            ResolveRequest(_) => ResolveResponse::Error(err).into(),
+            ChargebackRequest(_) => ChargebackResponse::Error(err).into(),
+            FreezeRequest(_) => FreezeResponse::Error(err).into(),
+            UnfreezeRequest(_) => UnfreezeResponse::Error(err).into(),
+            CloseRequest(_) => CloseResponse::Error(err).into(),
+            AcceptRequestRequest(_) | PassivateRequest(_) | SnapshotRequest(_) | Lifecycle(_) => {
+                unreachable!("Not an account operation")
+            }
+        }
+    }
+
+    fn broadcast_rejected(
+        &mut self,
+        transaction_id: Option<u32>,
+        operation: OperationKind,
+        error: &AccountErrors,
+    ) {
+        self.publish(std::iter::once(AllEvents::OperationRejected {
+            account_id: self.account.id(),
+            transaction_id,
+            operation,
+            error: error.clone(),
+        }));
+    }
+
+    // The account keeps the sequence of its next event,
+    // so it goes on from there wherever it is loaded again.
+    fn publish(&mut self, events: impl Iterator<Item = AllEvents>) {
+        self.broadcast.broadcast_all(events);
+        let sequence = self.broadcast.sequence(self.account.id());
+        self.account.set_sequence(sequence);
+    }
+
+    async fn add_dispute(&mut self, transaction_id: u32, dispute: Disputes) {
+        self.make_room().await;
+
+        let disputes = self.disputes.entry(transaction_id).or_default();
+        disputes.push(dispute);
+
+        self.schedule_accept().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::Arc;
+
+    use crate::{
+        actors::{
+            account::{DepositResponse, DisputeResponse, WithdrawResponse},
+            aggregators::{accounts_state_aggregator::AccountsStateAggregator, Aggregator},
+            clock::VirtualClock,
+            init_log, Actor, ActorError, Spawn,
+        },
+        broadcast::Broadcast,
+        domain::{
+            account::{Account, AccountErrors},
+            events::{AllEvents, OperationKind},
+            money::Currency::*,
+        },
+        store::journal::{FsyncPolicy, Journal},
+    };
+
+    use super::{
+        AccountActor, AccountActorConfig, CloseRequest, CloseResponse, DepositRequest,
+        DisputeRequest, FreezeRequest, FreezeResponse, LateArrivalPolicy, Scheduling,
+        SnapshotRequest, SnapshotResponse, UnfreezeRequest, UnfreezeResponse, WithdrawRequest,
+    };
+
+    #[tokio::test]
+    pub async fn err_incorrectly_waiting_on_out_of_order() {
+        init_log();
+
+        let broadcast = Broadcast::new();
+        let account = Account::new(0);
+        let account = AccountActor::new(account, broadcast.clone()).spawn();
+
+        // Because account accepts out of order delivery
+        // we cannot .await this sends, otherwise we will be
+        // waiting them to be accepted
+        // This is why this part fails
+        let response = account
+            .send_withdraw_async(WithdrawRequest {
+                account_id: 0,
+                transaction_id: 1,
+                amount: 0.5 * Bitcoin,
+            })
+            .await;
+        assert!(matches!(response, Ok(WithdrawResponse::Error(_))));
+
+        let response = account
+            .send_deposit_async(DepositRequest {
+                account_id: 0,
+                transaction_id: 0,
                 amount: 1 * Bitcoin,
             })
             .await;
+        assert!(matches!(response, Ok(DepositResponse::Ok)));
+    }
+
+    #[tokio::test]
+    pub async fn ok_correctly_sending_out_of_order() {
+        init_log();
+
+        let clock = VirtualClock::new();
+        let broadcast = Broadcast::new();
+        let account = Account::new(0);
+        let account = AccountActor::new(account, broadcast.clone())
+            .with_config(AccountActorConfig {
+                clock: Arc::new(clock.clone()),
+                ..Default::default()
+            })
+            .spawn();
+
+        // see [err_incorrectly_waiting_on_out_of_order]
+        // Now we correctly send and deal with out of order.
+        let response1 = account
+            .send_withdraw_async(WithdrawRequest {
+                account_id: 0,
+                transaction_id: 1,
+                amount: 0.5 * Bitcoin,
+            })
+            .spawn();
+
+        let response2 = account
+            .send_deposit_async(DepositRequest {
+                account_id: 0,
+                transaction_id: 0,
+                amount: 1 * Bitcoin,
+            })
+            .spawn();
+
+        // Both are waiting the reorder window
+        while clock.pending_sleeps() < 2 {
+            tokio::task::yield_now().await;
+        }
+        clock.advance(AccountActorConfig::default().reorder_window);
 
         assert!(matches!(response1.await, Ok(Ok(WithdrawResponse::Ok))));
-        assert!(matches!(response2, Ok(DepositResponse::Ok)));
+        assert!(matches!(response2.await, Ok(Ok(DepositResponse::Ok))));
+    }
+
+    #[tokio::test]
+    pub async fn ok_deterministic_scheduling_never_waits() {
+        init_log();
+
+        let broadcast = Broadcast::new();
+        let account = Account::new(0);
+        let account = AccountActor::new(account, broadcast.clone())
+            .with_config(AccountActorConfig {
+                scheduling: Scheduling::Deterministic,
+                // Would never be accepted if the actor waited
+                clock: Arc::new(VirtualClock::new()),
+                ..Default::default()
+            })
+            .spawn();
+
+        let response = account
+            .send_deposit_async(DepositRequest {
+                account_id: 0,
+                transaction_id: 0,
+                amount: 1 * Bitcoin,
+            })
+            .await;
+        assert!(matches!(response, Ok(DepositResponse::Ok)));
+
+        let response = account
+            .send_dispute_async(DisputeRequest {
+                account_id: 0,
+                transaction_id: 0,
+            })
+            .await;
+        assert!(matches!(response, Ok(DisputeResponse::Ok)));
+
+        let response = account
+            .send_withdraw_async(WithdrawRequest {
+                account_id: 0,
+                transaction_id: 1,
+                amount: 0.5 * Bitcoin,
+            })
+            .await;
+        assert!(matches!(
+            response,
+            Ok(WithdrawResponse::Error(AccountErrors::NegativeAmount))
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn ok_pending_replay_and_err_pending_conflict() {
+        init_log();
+
+        let clock = VirtualClock::new();
+        let broadcast = Broadcast::new();
+        let account = Account::new(0);
+        let account = AccountActor::new(account, broadcast.clone())
+            .with_config(AccountActorConfig {
+                clock: Arc::new(clock.clone()),
+                ..Default::default()
+            })
+            .spawn();
+
+        let deposit = DepositRequest {
+            account_id: 0,
+            transaction_id: 0,
+            amount: 1 * Bitcoin,
+        };
+        let response1 = account.send_deposit_async(deposit.clone()).spawn();
+        let response2 = account.send_deposit_async(deposit).spawn();
+        // The deposit is pending before the conflicting one arrives.
+        // Its replay joins it whenever it arrives.
+        while clock.pending_sleeps() < 1 {
+            tokio::task::yield_now().await;
+        }
+
+        let response3 = account
+            .send_deposit_async(DepositRequest {
+                account_id: 0,
+                transaction_id: 0,
+                amount: 2 * Bitcoin,
+            })
+            .await;
+
+        assert!(matches!(
+            response3,
+            Ok(DepositResponse::Error(
+                AccountErrors::DuplicateTransactionId
+            ))
+        ));
+        clock.advance(AccountActorConfig::default().reorder_window);
+        assert!(matches!(response1.await, Ok(Ok(DepositResponse::Ok))));
+        assert!(matches!(response2.await, Ok(Ok(DepositResponse::Ok))));
+
+        // Only one deposit was applied
+        let response = account
+            .send_withdraw_async(WithdrawRequest {
+                account_id: 0,
+                transaction_id: 1,
+                amount: 2 * Bitcoin,
+            })
+            .spawn();
+        while clock.pending_sleeps() < 1 {
+            tokio::task::yield_now().await;
+        }
+        clock.advance(AccountActorConfig::default().reorder_window);
+        assert!(matches!(
+            response.await,
+            Ok(Ok(WithdrawResponse::Error(AccountErrors::NegativeAmount)))
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn ok_admin_operations() {
+        init_log();
+
+        let broadcast = Broadcast::new();
+        let account = Account::new(0);
+        let account = AccountActor::new(account, broadcast.clone()).spawn();
+
+        let response = account
+            .send_freeze_async(FreezeRequest {
+                account_id: 0,
+                reason: "fraud investigation".into(),
+            })
+            .await;
+        assert!(matches!(response, Ok(FreezeResponse::Ok)));
+
+        let response = account
+            .send_deposit_async(DepositRequest {
+                account_id: 0,
+                transaction_id: 0,
+                amount: 1 * Bitcoin,
+            })
+            .await;
+        assert!(matches!(
+            response,
+            Ok(DepositResponse::Error(AccountErrors::AccountLocked))
+        ));
+
+        let response = account
+            .send_unfreeze_async(UnfreezeRequest {
+                account_id: 0,
+                reason: "cleared".into(),
+            })
+            .await;
+        assert!(matches!(response, Ok(UnfreezeResponse::Ok)));
+
+        let response = account
+            .send_close_async(CloseRequest { account_id: 0 })
+            .await;
+        assert!(matches!(response, Ok(CloseResponse::Ok)));
+
+        let response = account
+            .send_deposit_async(DepositRequest {
+                account_id: 0,
+                transaction_id: 0,
+                amount: 1 * Bitcoin,
+            })
+            .await;
+        assert!(matches!(
+            response,
+            Ok(DepositResponse::Error(AccountErrors::AccountClosed))
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn ok_admin_operations_are_reordered() {
+        init_log();
+
+        let clock = VirtualClock::new();
+        let broadcast = Broadcast::new();
+        let account = Account::new(0);
+        let account = AccountActor::new(account, broadcast.clone())
+            .with_config(AccountActorConfig {
+                clock: Arc::new(clock.clone()),
+                late_arrival: LateArrivalPolicy::Replay,
+                ..Default::default()
+            })
+            .spawn();
+        let deposit = |transaction_id| DepositRequest {
+            account_id: 0,
+            transaction_id,
+            amount: 1 * Bitcoin,
+        };
+        let arrived = |count| {
+            let clock = clock.clone();
+            async move {
+                while clock.pending_sleeps() < count {
+                    tokio::task::yield_now().await;
+                }
+            }
+        };
+
+        // The freeze comes after the deposit that arrived before it,
+        // even while that deposit still waits its reorder window
+        let deposited = account.send_deposit_async(deposit(6)).spawn();
+        arrived(1).await;
+        let frozen = account
+            .send_freeze_async(FreezeRequest {
+                account_id: 0,
+                reason: "fraud investigation".into(),
+            })
+            .spawn();
+        arrived(2).await;
+        let locked = account.send_deposit_async(deposit(7)).spawn();
+        arrived(3).await;
+        clock.advance(AccountActorConfig::default().reorder_window);
+        assert!(matches!(deposited.await, Ok(Ok(DepositResponse::Ok))));
+        assert!(matches!(frozen.await, Ok(Ok(FreezeResponse::Ok))));
+        assert!(matches!(
+            locked.await,
+            Ok(Ok(DepositResponse::Error(AccountErrors::AccountLocked)))
+        ));
+
+        // Replayed before the freeze, which still holds after it
+        let late = account.send_deposit_async(deposit(3)).spawn();
+        arrived(1).await;
+        clock.advance(AccountActorConfig::default().reorder_window);
+        assert!(matches!(late.await, Ok(Ok(DepositResponse::Ok))));
+
+        let SnapshotResponse(account) = account
+            .send_snapshot_async(SnapshotRequest { account_id: 0 })
+            .await
+            .unwrap();
+        assert!(account.available() == 2);
+        assert!(account.is_locked());
+    }
+
+    async fn withdraw_then_late_deposit(
+        config: AccountActorConfig,
+    ) -> (DepositResponse, Vec<AllEvents>) {
+        let clock = VirtualClock::new();
+        let reorder_window = config.reorder_window;
+        let broadcast = Broadcast::new();
+        let recorder = broadcast.clone().spawn_recorder();
+        let account = Account::new(0);
+        let account = AccountActor::new(account, broadcast.clone())
+            .with_config(AccountActorConfig {
+                clock: Arc::new(clock.clone()),
+                ..config
+            })
+            .spawn();
+
+        // The withdraw is already applied when the deposit arrives
+        let response = account
+            .send_withdraw_async(WithdrawRequest {
+                account_id: 0,
+                transaction_id: 5,
+                amount: 3 * Bitcoin,
+            })
+            .spawn();
+        accept_pending(&clock, reorder_window).await;
+        assert!(matches!(
+            response.await,
+            Ok(Ok(WithdrawResponse::Error(AccountErrors::NegativeAmount)))
+        ));
+
+        let response = account
+            .send_deposit_async(DepositRequest {
+                account_id: 0,
+                transaction_id: 2,
+                amount: 5 * Bitcoin,
+            })
+            .spawn();
+        accept_pending(&clock, reorder_window).await;
+        let response = response.await.unwrap().unwrap();
+
+        (response, recorder.stop().await)
+    }
+
+    async fn accept_pending(clock: &VirtualClock, reorder_window: std::time::Duration) {
+        while clock.pending_sleeps() < 1 {
+            tokio::task::yield_now().await;
+        }
+        clock.advance(reorder_window);
+    }
+
+    fn aggregate(events: &[AllEvents]) -> AccountsStateAggregator {
+        let mut state = AccountsStateAggregator::default();
+        for event in events {
+            state.handle(event.clone());
+        }
+        state
+    }
+
+    fn config(checkpoint_interval: usize, late_arrival: LateArrivalPolicy) -> AccountActorConfig {
+        AccountActorConfig {
+            checkpoint_interval,
+            late_arrival,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    pub async fn ok_late_request_reexecutes_history() {
+        init_log();
+
+        let (response, events) =
+            withdraw_then_late_deposit(config(16, LateArrivalPolicy::Replay)).await;
+        assert!(matches!(response, DepositResponse::Ok));
+        let state = aggregate(&events);
+        let state = &state.accounts[&0];
+        assert_eq!(state.available, 2.into());
+        assert_eq!(state.total, 2.into());
+
+        // The withdraw was answered with an error, but now went through
+        assert!(events.iter().any(|event| matches!(
+            event,
+            AllEvents::OutcomeChanged {
+                account_id: 0,
+                transaction_id: Some(5),
+                operation: OperationKind::Withdraw,
+                error: None,
+            }
+        )));
+    }
+
+    #[tokio::test]
+    pub async fn ok_late_request_applied_as_it_comes() {
+        init_log();
+
+        let (response, events) =
+            withdraw_then_late_deposit(config(16, LateArrivalPolicy::ApplyAnyway)).await;
+        assert!(matches!(response, DepositResponse::Ok));
+        assert_eq!(aggregate(&events).accounts[&0].available, 5.into());
+
+        // Older than the checkpoint, but still applied
+        let (response, events) =
+            withdraw_then_late_deposit(config(1, LateArrivalPolicy::ApplyAnyway)).await;
+        assert!(matches!(response, DepositResponse::Ok));
+        assert_eq!(aggregate(&events).accounts[&0].available, 5.into());
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, AllEvents::OutcomeChanged { .. })));
+    }
+
+    #[tokio::test]
+    pub async fn err_request_older_than_checkpoint() {
+        init_log();
+
+        // The withdraw is already checkpointed when the deposit arrives
+        for policy in [LateArrivalPolicy::Replay, LateArrivalPolicy::Reject] {
+            let (response, events) = withdraw_then_late_deposit(config(1, policy)).await;
+            assert!(matches!(
+                response,
+                DepositResponse::Error(AccountErrors::LateArrival)
+            ));
+            assert!(!aggregate(&events).accounts.contains_key(&0));
+        }
+    }
+
+    #[tokio::test]
+    pub async fn ok_dispute_after_checkpoint() {
+        init_log();
+
+        let broadcast = Broadcast::new();
+        let account = Account::new(0);
+        let account = AccountActor::new(account, broadcast.clone())
+            .with_config(AccountActorConfig {
+                scheduling: Scheduling::Deterministic,
+                ..config(1, LateArrivalPolicy::Replay)
+            })
+            .spawn();
+
+        for transaction_id in [0, 1] {
+            let response = account
+                .send_deposit_async(DepositRequest {
+                    account_id: 0,
+                    transaction_id,
+                    amount: 1 * Bitcoin,
+                })
+                .await;
+            assert!(matches!(response, Ok(DepositResponse::Ok)));
+        }
+
+        // Both deposits are checkpointed, but the dispute is not late
+        let response = account
+            .send_dispute_async(DisputeRequest {
+                account_id: 0,
+                transaction_id: 0,
+            })
+            .await;
+        assert!(matches!(response, Ok(DisputeResponse::Ok)));
+    }
+
+    #[tokio::test]
+    pub async fn ok_unknown_dispute_does_not_move_checkpoint() {
+        init_log();
+
+        let broadcast = Broadcast::new();
+        let account = Account::new(0);
+        let account = AccountActor::new(account, broadcast.clone())
+            .with_config(AccountActorConfig {
+                scheduling: Scheduling::Deterministic,
+                ..config(1, LateArrivalPolicy::Replay)
+            })
+            .spawn();
+
+        let response = account
+            .send_dispute_async(DisputeRequest {
+                account_id: 0,
+                transaction_id: 1_000_000,
+            })
+            .await;
+        assert!(matches!(
+            response,
+            Ok(DisputeResponse::Error(AccountErrors::TransactionNotFound))
+        ));
+
+        // Rejected requests are not a reason to turn anything away
+        let response = account
+            .send_deposit_async(DepositRequest {
+                account_id: 0,
+                transaction_id: 1,
+                amount: 1 * Bitcoin,
+            })
+            .await;
+        assert!(matches!(response, Ok(DepositResponse::Ok)));
+    }
+
+    #[tokio::test]
+    pub async fn ok_freeze_after_unknown_dispute() {
+        init_log();
+
+        let broadcast = Broadcast::new();
+        let account = Account::new(0);
+        let account = AccountActor::new(account, broadcast.clone())
+            .with_config(AccountActorConfig {
+                scheduling: Scheduling::Deterministic,
+                ..config(1, LateArrivalPolicy::Reject)
+            })
+            .spawn();
+
+        let response = account
+            .send_dispute_async(DisputeRequest {
+                account_id: 0,
+                transaction_id: 1_000_000,
+            })
+            .await;
+        assert!(matches!(
+            response,
+            Ok(DisputeResponse::Error(AccountErrors::TransactionNotFound))
+        ));
+
+        // Administrative operations are never late
+        let response = account
+            .send_freeze_async(FreezeRequest {
+                account_id: 0,
+                reason: "audit".to_string(),
+            })
+            .await;
+        assert!(matches!(response, Ok(FreezeResponse::Ok)));
+    }
+
+    #[tokio::test]
+    pub async fn ok_dispute_after_restore() {
+        init_log();
+
+        let mut account = Account::new(0);
+        for transaction_id in [1, 2] {
+            let _ = account.deposit(transaction_id, 1 * Bitcoin);
+        }
+
+        let broadcast = Broadcast::new();
+        let account = AccountActor::restore(account, broadcast.clone())
+            .with_config(AccountActorConfig {
+                scheduling: Scheduling::Deterministic,
+                ..config(16, LateArrivalPolicy::Reject)
+            })
+            .spawn();
+
+        let response = account
+            .send_dispute_async(DisputeRequest {
+                account_id: 0,
+                transaction_id: 1,
+            })
+            .await;
+        assert!(matches!(response, Ok(DisputeResponse::Ok)));
+
+        // Older transactions still cannot be reordered
+        let response = account
+            .send_deposit_async(DepositRequest {
+                account_id: 0,
+                transaction_id: 0,
+                amount: 1 * Bitcoin,
+            })
+            .await;
+        assert!(matches!(
+            response,
+            Ok(DepositResponse::Error(AccountErrors::LateArrival))
+        ));
+    }
+
+    // Writing to /dev/full always fails
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    pub async fn err_requests_not_journaled_are_not_executed() {
+        init_log();
+
+        let journal = Journal::open("/dev/full", FsyncPolicy::Never).unwrap();
+        let broadcast = Broadcast::new();
+        let account = Account::new(0);
+        let account = AccountActor::new(account, broadcast.clone())
+            .with_config(AccountActorConfig {
+                scheduling: Scheduling::Deterministic,
+                journal: Some(Arc::new(journal)),
+                ..Default::default()
+            })
+            .spawn();
+
+        let response = account
+            .send_deposit_async(DepositRequest {
+                account_id: 0,
+                transaction_id: 0,
+                amount: 1 * Bitcoin,
+            })
+            .await;
+        assert!(matches!(response, Err(ActorError::Store(_))));
+
+        let SnapshotResponse(account) = account
+            .send_snapshot_async(SnapshotRequest { account_id: 0 })
+            .await
+            .unwrap();
+        assert!(account.get_transaction(0).is_none());
+    }
+
+    #[tokio::test]
+    pub async fn ok_full_pending_requests_are_accepted_early() {
+        init_log();
+
+        let clock = VirtualClock::new();
+        let broadcast = Broadcast::new();
+        let account = Account::new(0);
+        let account = AccountActor::new(account, broadcast.clone())
+            .with_config(AccountActorConfig {
+                clock: Arc::new(clock.clone()),
+                max_pending_requests: 1,
+                ..Default::default()
+            })
+            .spawn();
+
+        let response1 = account
+            .send_deposit_async(DepositRequest {
+                account_id: 0,
+                transaction_id: 0,
+                amount: 1 * Bitcoin,
+            })
+            .spawn();
+        // Pending until the clock moves, which it never does
+        while clock.pending_sleeps() < 1 {
+            tokio::task::yield_now().await;
+        }
+
+        // Never answered, but makes the first one be accepted
+        let _response2 = account
+            .send_deposit_async(DepositRequest {
+                account_id: 0,
+                transaction_id: 1,
+                amount: 1 * Bitcoin,
+            })
+            .spawn();
+
+        assert!(matches!(response1.await, Ok(Ok(DepositResponse::Ok))));
     }
 }
@@ -1,21 +1,57 @@
 use super::Actor;
 use super::{
-    account::{AccountClient, AccountResponses},
-    CommandEnvelope,
+    account::{
+        AccountActorConfig, AccountClient, AccountResponses, PassivateRequest, SnapshotRequest,
+        SnapshotResponse,
+    },
+    deadline::Deadline,
+    remote::{self, Address},
+    supervisor::{self, Decision, Exit, RestartPolicy, Supervisor},
+    ActorError, CommandEnvelope, Lifecycle,
 };
 use crate::broadcast::Broadcast;
-use crate::domain::account::Account;
+use crate::domain::account::{Account, AccountParts};
+use crate::domain::dispute_policy::{DepositsOnly, DisputePolicy};
 use crate::domain::events::AllEvents;
+use crate::store::journal::JournaledAccount;
+use crate::store::memory::InMemoryAccountStore;
+use crate::store::{AccountStore, StoreError};
 use crate::{
     actors::account::{AccountActor, AccountRequests},
     gen_client_extension_methods,
 };
 use flume::Sender;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Clone)]
 pub struct AccountManagerClient(Sender<Envelope>, u64);
 
+impl AccountManagerClient {
+    pub fn id(&self) -> u64 {
+        self.1
+    }
+
+    // Client of a manager served by another process.
+    pub async fn connect(
+        address: &Address,
+        id: u64,
+        dispute_policy: Arc<dyn DisputePolicy>,
+    ) -> std::io::Result<Self> {
+        Ok(Self(remote::connect(address, dispute_policy).await?, id))
+    }
+}
+
+impl std::fmt::Debug for AccountManagerClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("AccountManagerClient")
+            .field(&self.1)
+            .finish()
+    }
+}
+
 impl std::hash::Hash for AccountManagerClient {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.1.hash(state);
@@ -23,15 +59,132 @@ impl std::hash::Hash for AccountManagerClient {
 }
 
 gen_client_extension_methods! {
-    impl AccountManager for AccountManagerClient {
+    remote impl AccountManager for AccountManagerClient {
         fn account(_: AccountRequests) -> AccountResponses;
+        fn metrics(_: Metrics) -> PassivationMetrics;
+        fn passivate_idle(_: PassivateIdle) -> PassivateIdle;
+        fn snapshot_accounts(_: SnapshotAccountsRequest) -> SnapshotAccountsResponse;
+        fn restore_accounts(_: RestoreAccountsRequest) -> RestoreAccountsResponse;
+        fn account_ids(_: AccountIdsRequest) -> AccountIdsResponse;
+        fn release_accounts(_: ReleaseAccountsRequest) -> ReleaseAccountsResponse;
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotAccountsRequest;
+
+#[derive(Clone, Debug)]
+pub enum SnapshotAccountsResponse {
+    Ok(Vec<Account>),
+    Error(ActorError),
+}
+
+#[derive(Clone, Debug)]
+pub struct RestoreAccountsRequest {
+    pub accounts: Vec<Account>,
+}
+
+#[derive(Clone, Debug)]
+pub enum RestoreAccountsResponse {
+    Ok,
+    Error(ActorError),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AccountIdsRequest;
+
+#[derive(Clone, Debug)]
+pub enum AccountIdsResponse {
+    Ok(Vec<u32>),
+    Error(ActorError),
+}
+
+#[derive(Clone, Debug)]
+pub struct ReleaseAccountsRequest {
+    pub account_ids: Vec<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub enum ReleaseAccountsResponse {
+    Ok(Vec<Account>),
+    Error(ActorError),
+}
+
+// Account actors are "virtual": they are passivated into the account store when idle
+// or when there are too many of them, and transparently activated again on their next request.
+// By default they are never passivated.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PassivationConfig {
+    // Active account actors kept in memory. Least recently used idle ones go first.
+    pub max_active_accounts: Option<usize>,
+    // Accounts without requests for one to two idle timeouts are passivated.
+    pub idle_timeout: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PassivationMetrics {
+    pub activations: u64,
+    pub passivations: u64,
+    // Activations of accounts found in the store
+    pub restored: u64,
+    // Account actors restarted after crashing
+    pub restarts: u64,
+    pub active: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Metrics;
+
+// Sent to the manager when the task of an account actor ends.
+// Only the manager has the sender, so exits cannot be forged.
+#[derive(Clone, Debug)]
+struct AccountExited {
+    account_id: u32,
+    // Tells apart actors of the same account
+    generation: u64,
+    exit: Exit,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PassivateIdle;
+
+struct ActiveAccount {
+    client: AccountClient,
+    generation: u64,
+    // Requests sent to the actor and not answered yet.
+    // Only accounts without any can be passivated.
+    in_flight: Arc<AtomicUsize>,
+    last_used: u64,
+    // Used since the last idle sweep
+    used: bool,
+}
+
+impl ActiveAccount {
+    fn is_idle(&self) -> bool {
+        self.in_flight.load(Ordering::SeqCst) == 0
     }
 }
 
 pub struct AccountManagerActor {
     id: u64,
-    accounts: HashMap<u32, AccountClient>,
+    accounts: HashMap<u32, ActiveAccount>,
+    store: Arc<dyn AccountStore>,
+    // Active accounts by last use
+    lru: BTreeMap<u64, u32>,
+    tick: u64,
     broadcast: Broadcast<AllEvents>,
+    dispute_policy: Arc<dyn DisputePolicy>,
+    config: AccountActorConfig,
+    passivation: PassivationConfig,
+    metrics: PassivationMetrics,
+    sender: Option<Sender<Envelope>>,
+    mailbox_capacity: Option<usize>,
+    supervisor: Supervisor<u32>,
+    exits: Sender<AccountExited>,
+    exits_receiver: flume::Receiver<AccountExited>,
+    // Why the manager gave up. Account requests fail with
+    // `ActorError::Escalated` until its accounts are moved elsewhere.
+    escalated: Option<String>,
 }
 
 impl std::fmt::Debug for AccountManagerActor {
@@ -40,6 +193,11 @@ impl std::fmt::Debug for AccountManagerActor {
             .field("id", &self.id)
             .field("accounts", &"[..]")
             .field("broadcast", &"...")
+            .field("dispute_policy", &self.dispute_policy)
+            .field("store", &self.store)
+            .field("config", &self.config)
+            .field("passivation", &self.passivation)
+            .field("metrics", &self.metrics)
             .finish()
     }
 }
@@ -55,57 +213,1159 @@ impl Actor<AccountManagerRequests, AccountManagerResponses> for AccountManagerAc
         AccountManagerClient(sender, self.id)
     }
 
+    fn set_sender(
+        &mut self,
+        sender: flume::Sender<CommandEnvelope<AccountManagerRequests, AccountManagerResponses>>,
+    ) {
+        self.sender = Some(sender);
+        self.schedule_idle_sweep();
+    }
+
+    fn mailbox_capacity(&self) -> Option<usize> {
+        self.mailbox_capacity
+    }
+
+    // Exits of account actors are handled as soon as they arrive.
+    async fn next_request(
+        &mut self,
+        receiver: &flume::Receiver<
+            CommandEnvelope<AccountManagerRequests, AccountManagerResponses>,
+        >,
+    ) -> Result<CommandEnvelope<AccountManagerRequests, AccountManagerResponses>, flume::RecvError>
+    {
+        let exits = self.exits_receiver.clone();
+        loop {
+            tokio::select! {
+                Ok(exited) = exits.recv_async() => self.account_exited(exited),
+                request = receiver.recv_async() => return request,
+            }
+        }
+    }
+
     async fn handle_request(
         &mut self,
         request: AccountManagerRequests,
         callback: Sender<AccountManagerResponses>,
+        deadline: Deadline,
     ) {
+        if let Err(err) = deadline.check() {
+            let _ = callback
+                .send_async(AccountManagerResponses::Error(err))
+                .await;
+            return;
+        }
+
         match request {
             AccountManagerRequests::AccountRequest(request) => {
-                self.handle_account_request(request, callback)
+                self.handle_account_request(request, callback, deadline)
+                    .await
+            }
+            AccountManagerRequests::MetricsRequest(_) => {
+                let _ = callback.send_async(self.metrics().into()).await;
+            }
+            AccountManagerRequests::PassivateIdleRequest(_) => {
+                self.passivate_idle().await;
+                let _ = callback.send_async(PassivateIdle.into()).await;
+            }
+            AccountManagerRequests::SnapshotAccountsRequest(_) => {
+                let response = match self.snapshot_accounts().await {
+                    Ok(accounts) => SnapshotAccountsResponse::Ok(accounts),
+                    Err(err) => SnapshotAccountsResponse::Error(err.into()),
+                };
+                let _ = callback.send_async(response.into()).await;
+            }
+            AccountManagerRequests::RestoreAccountsRequest(request) => {
+                let response = match self.restore_accounts(request.accounts).await {
+                    Ok(()) => RestoreAccountsResponse::Ok,
+                    Err(err) => RestoreAccountsResponse::Error(err.into()),
+                };
+                let _ = callback.send_async(response.into()).await;
+            }
+            AccountManagerRequests::AccountIdsRequest(_) => {
+                let response = match self.account_ids() {
+                    Ok(account_ids) => AccountIdsResponse::Ok(account_ids),
+                    Err(err) => AccountIdsResponse::Error(err.into()),
+                };
+                let _ = callback.send_async(response.into()).await;
+            }
+            AccountManagerRequests::ReleaseAccountsRequest(request) => {
+                let response = match self.release_accounts(request.account_ids).await {
+                    Ok(accounts) => ReleaseAccountsResponse::Ok(accounts),
+                    Err(err) => ReleaseAccountsResponse::Error(err.into()),
+                };
+                let _ = callback.send_async(response.into()).await;
+            }
+            AccountManagerRequests::Lifecycle(_) => {
+                unreachable!("Lifecycle messages are handled by Actor::handle")
+            }
+        }
+    }
+
+    async fn handle_lifecycle(&mut self, lifecycle: Lifecycle) {
+        match lifecycle {
+            Lifecycle::Drain => {
+                for (account_id, account) in self.accounts.iter() {
+                    if let Err(err) = account.client.send_drain_async().await {
+                        tracing::error!("Account {} not drained: {:?}", account_id, err);
+                    }
+                }
+            }
+            // Passivation drains the accounts and saves them, so nothing is lost
+            Lifecycle::Stop => {
+                let account_ids: Vec<_> = self.accounts.keys().copied().collect();
+                for account_id in account_ids {
+                    self.passivate(account_id).await;
+                }
             }
         }
     }
 }
 
 impl AccountManagerActor {
-    pub fn new(id: u64, broadcast: Broadcast<AllEvents>) -> Self {
+    pub fn new(id: u64, broadcast: Broadcast<AllEvents>, config: AccountActorConfig) -> Self {
+        let (exits, exits_receiver) = flume::unbounded();
+        let supervisor = Supervisor::new(RestartPolicy::default(), config.clock.clone());
         Self {
             id,
             accounts: HashMap::new(),
-            broadcast,
+            store: Arc::new(InMemoryAccountStore::new()),
+            lru: BTreeMap::new(),
+            tick: 0,
+            // Events of its accounts are tagged with the manager
+            broadcast: broadcast.with_manager(id),
+            dispute_policy: Arc::new(DepositsOnly),
+            config,
+            passivation: PassivationConfig::default(),
+            metrics: PassivationMetrics::default(),
+            sender: None,
+            mailbox_capacity: None,
+            supervisor,
+            exits,
+            exits_receiver,
+            escalated: None,
+        }
+    }
+
+    // Policy used by every account created by this manager.
+    pub fn with_dispute_policy(mut self, dispute_policy: Arc<dyn DisputePolicy>) -> Self {
+        self.dispute_policy = dispute_policy;
+        self
+    }
+
+    // Where passivated accounts are kept and activated accounts are looked for.
+    pub fn with_store(mut self, store: Arc<dyn AccountStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    pub fn with_passivation(mut self, passivation: PassivationConfig) -> Self {
+        self.passivation = passivation;
+        self
+    }
+
+    // How crashed account actors are restarted. Accounts that keep crashing,
+    // or cannot be rebuilt because there is no journal, are escalated:
+    // the manager stops taking account requests.
+    pub fn with_restart_policy(mut self, policy: RestartPolicy) -> Self {
+        self.supervisor = Supervisor::new(policy, self.config.clock.clone());
+        self
+    }
+
+    // Of the manager itself. Accounts take theirs from `AccountActorConfig`.
+    pub fn with_mailbox_capacity(mut self, capacity: usize) -> Self {
+        self.mailbox_capacity = Some(capacity);
+        self
+    }
+
+    // Executes the journal, if any, so accounts are exactly as they were
+    // before a crash. Accounts start as they were last saved in the journal,
+    // or else as they are in the store. Must be called before spawning the manager.
+    pub async fn recover(mut self) -> Result<Self, StoreError> {
+        let journal = match self.config.journal.clone() {
+            Some(journal) => journal,
+            None => return Ok(self),
+        };
+
+        for (account_id, journaled) in journal.accounts()? {
+            // Slow subscribers hold back the next account
+            self.broadcast.ready(account_id).await;
+            let actor = self.rebuild(account_id, journaled)?;
+            self.spawn_actor(account_id, actor);
+        }
+        Ok(self)
+    }
+
+    // Executes what the journal has for the account without broadcasting,
+    // then tells subscribers the result. Requests that crash the account are
+    // skipped, so one bad request does not take the whole account with it.
+    fn rebuild(
+        &mut self,
+        account_id: u32,
+        journaled: JournaledAccount,
+    ) -> Result<AccountActor, StoreError> {
+        let mut quarantined = HashSet::new();
+        loop {
+            let mut actor = self
+                .journaled(account_id, journaled.saved.clone())?
+                .with_config(self.config.clone())
+                .with_broadcast(Broadcast::new());
+            let mut current = 0;
+            let replayed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                for (i, request) in journaled.requests.iter().enumerate() {
+                    if !quarantined.contains(&i) {
+                        current = i;
+                        let _ = actor.execute_journaled(request.clone());
+                    }
+                }
+            }));
+
+            if replayed.is_ok() {
+                let mut actor = actor.with_broadcast(self.broadcast.clone());
+                actor.announce();
+                return Ok(actor);
+            }
+            let request = &journaled.requests[current];
+            tracing::error!("Account {} crashes on {:?}. Skipped.", account_id, request);
+            quarantined.insert(current);
         }
     }
 
-    #[tracing::instrument(skip(broadcast))]
-    fn new_actor(id: u32, broadcast: Broadcast<AllEvents>) -> AccountClient {
-        let account = Account::new(id);
-        AccountActor::new(account, broadcast).spawn()
+    // Starting point to replay the journal of an account.
+    fn journaled(
+        &mut self,
+        account_id: u32,
+        saved: Option<AccountParts>,
+    ) -> Result<AccountActor, StoreError> {
+        match saved {
+            Some(parts) => {
+                let account = Account::from_parts(parts, self.dispute_policy.clone())?;
+                Ok(AccountActor::restore(account, self.broadcast.clone()))
+            }
+            None => self.load(account_id),
+        }
     }
 
     #[tracing::instrument(skip(self, callback))]
-    pub fn handle_account_request(
+    pub async fn handle_account_request(
         &mut self,
         request: AccountRequests,
         callback: Sender<AccountManagerResponses>,
+        deadline: Deadline,
     ) {
-        let account_id = request.get_account_id();
-        let account = self
-            .accounts
-            .entry(account_id)
-            .or_insert_with(|| Self::new_actor(account_id, self.broadcast.clone()))
-            .clone();
+        if let Some(reason) = &self.escalated {
+            let err = ActorError::Escalated(reason.clone());
+            let _ = callback
+                .send_async(AccountManagerResponses::Error(err))
+                .await;
+            return;
+        }
 
+        let account_id = match request.account_id() {
+            Some(account_id) => account_id,
+            None => {
+                let reason = format!("{:?} is not for an account", request);
+                let err = ActorError::InvalidRequest(reason);
+                let _ = callback
+                    .send_async(AccountManagerResponses::Error(err))
+                    .await;
+                return;
+            }
+        };
+        if !self.accounts.contains_key(&account_id) {
+            self.make_room().await;
+            if let Err(err) = self.activate(account_id) {
+                // Never replace an account we could not load with an empty one
+                tracing::error!("Account {} not loaded: {:?}", account_id, err);
+                let _ = callback
+                    .send_async(AccountManagerResponses::Error(err.into()))
+                    .await;
+                return;
+            }
+        }
+
+        let (account, in_flight) = self.touch(account_id);
+        in_flight.fetch_add(1, Ordering::SeqCst);
+
+        // Enqueued before handling anything else, so releasing
+        // the account later applies this request first.
+        let response = account
+            .enqueue_before_async(request.clone(), deadline)
+            .await;
         tokio::task::spawn(async move {
-            match account.send_async(request.clone()).await {
+            let response = match response {
+                Ok(response) => response
+                    .recv_async()
+                    .await
+                    .map_err(|_| ActorError::ActorStopped),
+                Err(err) => Err(err),
+            };
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            match response {
+                Ok(AccountResponses::Error(err)) | Err(err) => {
+                    tracing::warn!("{:?} {:?}", request, err);
+                    let _ = callback
+                        .send_async(AccountManagerResponses::Error(err))
+                        .await;
+                }
                 Ok(response) => {
                     let _ = callback.send_async(response.into()).await;
                 }
+            }
+        });
+    }
+
+    // Active accounts first accept everything pending;
+    // passivated ones come from the store.
+    pub async fn snapshot_accounts(&mut self) -> Result<Vec<Account>, StoreError> {
+        let mut accounts = vec![];
+        for (account_id, account) in self.accounts.iter() {
+            let request = SnapshotRequest {
+                account_id: *account_id,
+            };
+            match account.client.send_snapshot_async(request).await {
+                Ok(SnapshotResponse(account)) => accounts.push(account),
+                Err(err) => tracing::error!("Account {} lost: {:?}", account_id, err),
+            }
+        }
+
+        let active: HashSet<_> = self.accounts.keys().copied().collect();
+        self.store.scan(&self.dispute_policy, &mut |account| {
+            if !active.contains(&account.id()) {
+                accounts.push(account);
+            }
+        })?;
+
+        Ok(accounts)
+    }
+
+    // Restored accounts replace the ones we have. They go to the store
+    // and are activated on their next request.
+    pub async fn restore_accounts(&mut self, accounts: Vec<Account>) -> Result<(), StoreError> {
+        for account in accounts {
+            self.passivate(account.id()).await;
+            self.save(&account)?;
+        }
+        Ok(())
+    }
+
+    // Active and passivated accounts.
+    pub fn account_ids(&self) -> Result<Vec<u32>, StoreError> {
+        let mut account_ids: HashSet<_> = self.accounts.keys().copied().collect();
+        self.store.scan(&self.dispute_policy, &mut |account| {
+            account_ids.insert(account.id());
+        })?;
+
+        let mut account_ids: Vec<_> = account_ids.into_iter().collect();
+        account_ids.sort_unstable();
+        Ok(account_ids)
+    }
+
+    // Hands accounts over to another manager. Requests already sent to them
+    // are applied first. Afterwards they are gone from here, store included.
+    pub async fn release_accounts(
+        &mut self,
+        account_ids: Vec<u32>,
+    ) -> Result<Vec<Account>, StoreError> {
+        let mut accounts = vec![];
+        for account_id in account_ids {
+            self.supervisor.forget(&account_id);
+            let account = match self.accounts.remove(&account_id) {
+                Some(active) => {
+                    self.lru.remove(&active.last_used);
+                    match active
+                        .client
+                        .send_passivate_async(PassivateRequest { account_id })
+                        .await
+                    {
+                        Ok(account) => Some(account),
+                        Err(err) => {
+                            tracing::error!("Account {} lost: {:?}", account_id, err);
+                            None
+                        }
+                    }
+                }
+                None => self.store.load(account_id, &self.dispute_policy)?,
+            };
+
+            if let Some(account) = account {
+                if let Err(err) = self.journal_released(account_id) {
+                    // Not handed over, so they stay here
+                    accounts.push(account);
+                    for account in accounts {
+                        self.store.save(&account)?;
+                    }
+                    return Err(err);
+                }
+                if let Err(err) = self.store.delete(account_id) {
+                    // Handed over anyway. Restoring it here again overwrites this copy
+                    tracing::warn!("Stale account {} left in the store: {:?}", account_id, err);
+                }
+                accounts.push(account);
+            }
+        }
+        Ok(accounts)
+    }
+
+    pub fn metrics(&self) -> PassivationMetrics {
+        PassivationMetrics {
+            active: self.accounts.len(),
+            ..self.metrics
+        }
+    }
+
+    // Spawns the account actor, from the store if it is there.
+    #[tracing::instrument(skip(self))]
+    fn activate(&mut self, account_id: u32) -> Result<(), StoreError> {
+        let actor = self.load(account_id)?;
+        self.spawn_actor(account_id, actor);
+        Ok(())
+    }
+
+    fn load(&mut self, account_id: u32) -> Result<AccountActor, StoreError> {
+        Ok(match self.store.load(account_id, &self.dispute_policy)? {
+            Some(account) => {
+                self.metrics.restored += 1;
+                AccountActor::restore(account, self.broadcast.clone())
+            }
+            None => {
+                let account = Account::with_dispute_policy(account_id, self.dispute_policy.clone());
+                AccountActor::new(account, self.broadcast.clone())
+            }
+        })
+    }
+
+    fn spawn_actor(&mut self, account_id: u32, actor: AccountActor) {
+        let (client, task) = actor.with_config(self.config.clone()).spawn_linked();
+
+        self.tick += 1;
+        let generation = self.tick;
+        let exits = self.exits.clone();
+        tokio::task::spawn(async move {
+            let exit = supervisor::exit(task).await;
+            let exited = AccountExited {
+                account_id,
+                generation,
+                exit,
+            };
+            let _ = exits.send_async(exited).await;
+        });
+
+        self.lru.insert(self.tick, account_id);
+        self.accounts.insert(
+            account_id,
+            ActiveAccount {
+                client,
+                generation,
+                in_flight: Arc::new(AtomicUsize::new(0)),
+                last_used: self.tick,
+                used: false,
+            },
+        );
+        self.metrics.activations += 1;
+    }
+
+    // Passivated and released actors stop on purpose and are no longer here.
+    // Any other actor that stops crashed, and is restarted.
+    fn account_exited(&mut self, exited: AccountExited) {
+        let AccountExited {
+            account_id,
+            generation,
+            exit,
+        } = exited;
+        let is_current = self
+            .accounts
+            .get(&account_id)
+            .is_some_and(|account| account.generation == generation);
+        if !is_current {
+            return;
+        }
+
+        if let Some(account) = self.accounts.remove(&account_id) {
+            self.lru.remove(&account.last_used);
+        }
+        let reason = match exit {
+            Exit::Crashed(reason) => reason,
+            Exit::Stopped => "stopped".into(),
+        };
+        tracing::error!("Account {} crashed: {}", account_id, reason);
+
+        match self.supervisor.crashed(account_id) {
+            Decision::Restart => match self.restart(account_id) {
+                Ok(()) => self.metrics.restarts += 1,
+                // Anything else would lose requests already answered
                 Err(err) => {
-                    tracing::warn!("{:?} {:?}", request, err);
-                    let _ = callback.send_async(AccountManagerResponses::Error(err));
+                    tracing::error!("Account {} not rebuilt. Escalating.", account_id);
+                    self.escalated = Some(format!("Account {} not rebuilt: {:?}", account_id, err));
                 }
+            },
+            Decision::Escalate => {
+                tracing::error!("Account {} keeps crashing. Escalating.", account_id);
+                self.escalated = Some(format!("Account {} keeps crashing: {}", account_id, reason));
             }
+        }
+    }
+
+    // As it was last saved, plus what the journal has for the account
+    // since then, which is everything it had applied before crashing.
+    // Without a journal, what it applied since it was saved is unknown.
+    fn restart(&mut self, account_id: u32) -> Result<(), ActorError> {
+        let journal = match &self.config.journal {
+            Some(journal) => journal.clone(),
+            None => return Err(ActorError::Store("nothing to rebuild it from".into())),
+        };
+        let journaled = journal.account(account_id)?.unwrap_or_default();
+        let actor = self.rebuild(account_id, journaled)?;
+        self.spawn_actor(account_id, actor);
+        Ok(())
+    }
+
+    fn touch(&mut self, account_id: u32) -> (AccountClient, Arc<AtomicUsize>) {
+        self.tick += 1;
+        let account = self
+            .accounts
+            .get_mut(&account_id)
+            .expect("Account is active");
+        self.lru.remove(&account.last_used);
+        self.lru.insert(self.tick, account_id);
+        account.last_used = self.tick;
+        account.used = true;
+
+        (account.client.clone(), account.in_flight.clone())
+    }
+
+    // Passivates least recently used idle accounts until there is room for one more.
+    // Busy accounts are never passivated, so we may go over the limit for a while.
+    async fn make_room(&mut self) {
+        let max_active_accounts = match self.passivation.max_active_accounts {
+            Some(max) => max.max(1),
+            None => return,
+        };
+
+        let excess = (self.accounts.len() + 1).saturating_sub(max_active_accounts);
+        let evict: Vec<_> = self
+            .lru
+            .values()
+            .filter(|id| self.accounts[id].is_idle())
+            .take(excess)
+            .copied()
+            .collect();
+        if evict.len() < excess {
+            tracing::warn!("All accounts are busy. Going over max_active_accounts.");
+        }
+
+        for account_id in evict {
+            self.passivate(account_id).await;
+        }
+    }
+
+    async fn passivate_idle(&mut self) {
+        let idle: Vec<_> = self
+            .accounts
+            .iter()
+            .filter(|(_, account)| !account.used && account.is_idle())
+            .map(|(id, _)| *id)
+            .collect();
+        for account_id in idle {
+            self.passivate(account_id).await;
+        }
+
+        for account in self.accounts.values_mut() {
+            account.used = false;
+        }
+        self.schedule_idle_sweep();
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn passivate(&mut self, account_id: u32) {
+        let account = match self.accounts.remove(&account_id) {
+            Some(account) => account,
+            None => return,
+        };
+        self.lru.remove(&account.last_used);
+
+        match account
+            .client
+            .send_passivate_async(PassivateRequest { account_id })
+            .await
+        {
+            Ok(account) => match self.save(&account) {
+                Ok(()) => self.metrics.passivations += 1,
+                Err(err) => {
+                    // Keep it in memory instead
+                    tracing::error!("Account {} not saved: {:?}", account_id, err);
+                    let actor = AccountActor::restore(account, self.broadcast.clone());
+                    self.spawn_actor(account_id, actor);
+                }
+            },
+            Err(err) => tracing::error!("Account {} lost: {:?}", account_id, err),
+        }
+    }
+
+    // The journal no longer needs what it had for the account.
+    fn save(&self, account: &Account) -> Result<(), StoreError> {
+        if let Some(journal) = &self.config.journal {
+            journal.saved(&account.to_parts())?;
+        }
+        self.store.save(account)
+    }
+
+    fn journal_released(&self, account_id: u32) -> Result<(), StoreError> {
+        match &self.config.journal {
+            Some(journal) => journal.released(account_id),
+            None => Ok(()),
+        }
+    }
+
+    fn schedule_idle_sweep(&self) {
+        let (idle_timeout, sender) = match (self.passivation.idle_timeout, self.sender.clone()) {
+            (Some(idle_timeout), Some(sender)) => (idle_timeout, sender),
+            _ => return,
+        };
+
+        let sleep = self.config.clock.sleep(idle_timeout);
+        tokio::task::spawn(async move {
+            sleep.await;
+            let (callback_sender, callback_recv) = flume::bounded(1);
+            let _ = sender
+                .send_async(CommandEnvelope::new(
+                    AccountManagerRequests::PassivateIdleRequest(PassivateIdle),
+                    callback_sender,
+                ))
+                .await;
+            let _ = callback_recv.recv_async().await;
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        actors::{
+            account::{
+                AccountActorConfig, AccountRequests, AccountResponses, ChargebackRequest,
+                DepositRequest, DepositResponse, DisputeRequest, ResolveRequest, Scheduling,
+                WithdrawRequest, WithdrawResponse,
+            },
+            aggregators::{accounts_state_aggregator::AccountsStateAggregator, Aggregator},
+            clock::VirtualClock,
+            init_log,
+            supervisor::RestartPolicy,
+            test_utils::{journaled_config, temp_path, CrashOnWithdrawals},
+            Actor, ActorError,
+        },
+        broadcast::Broadcast,
+        domain::{account::AccountParts, events::AllEvents, money::Currency::*},
+        store::{
+            file::FileAccountStore,
+            journal::{FsyncPolicy, Journal},
+        },
+    };
+
+    use super::{
+        AccountManagerActor, AccountManagerClient, Metrics, PassivationConfig, PassivationMetrics,
+        ReleaseAccountsRequest, ReleaseAccountsResponse, RestoreAccountsRequest,
+        RestoreAccountsResponse, SnapshotAccountsRequest, SnapshotAccountsResponse,
+    };
+
+    fn manager(clock: VirtualClock, passivation: PassivationConfig) -> AccountManagerActor {
+        let config = AccountActorConfig {
+            scheduling: Scheduling::Deterministic,
+            clock: Arc::new(clock),
+            ..Default::default()
+        };
+        AccountManagerActor::new(0, Broadcast::new(), config).with_passivation(passivation)
+    }
+
+    fn spawn_manager(clock: VirtualClock, passivation: PassivationConfig) -> AccountManagerClient {
+        manager(clock, passivation).spawn()
+    }
+
+    async fn deposit(manager: &AccountManagerClient, account_id: u32, transaction_id: u32) {
+        let response = manager
+            .send_account_async(DepositRequest {
+                account_id,
+                transaction_id,
+                amount: 1 * Bitcoin,
+            })
+            .await;
+        assert!(matches!(
+            response,
+            Ok(AccountResponses::DepositResponse(DepositResponse::Ok))
+        ));
+    }
+
+    async fn withdraw(manager: &AccountManagerClient, account_id: u32, transaction_id: u32) {
+        let response = manager
+            .send_account_async(WithdrawRequest {
+                account_id,
+                transaction_id,
+                amount: 1 * Bitcoin,
+            })
+            .await;
+        assert!(matches!(
+            response,
+            Ok(AccountResponses::WithdrawResponse(WithdrawResponse::Ok))
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn ok_least_recently_used_account_is_passivated() {
+        init_log();
+
+        let manager = spawn_manager(
+            VirtualClock::new(),
+            PassivationConfig {
+                max_active_accounts: Some(2),
+                ..Default::default()
+            },
+        );
+
+        deposit(&manager, 1, 1).await;
+        deposit(&manager, 2, 2).await;
+        deposit(&manager, 1, 3).await;
+        deposit(&manager, 3, 4).await;
+
+        // Account 2 was passivated and comes back with its balance
+        withdraw(&manager, 2, 5).await;
+
+        let metrics = manager.send_metrics_async(Metrics).await.unwrap();
+        assert_eq!(
+            metrics,
+            PassivationMetrics {
+                activations: 4,
+                passivations: 2,
+                restored: 1,
+                restarts: 0,
+                active: 2,
+            }
+        );
+
+        // Account 1 was the one passivated now
+        withdraw(&manager, 1, 6).await;
+        withdraw(&manager, 1, 7).await;
+    }
+
+    #[tokio::test]
+    pub async fn ok_idle_account_is_passivated() {
+        init_log();
+
+        let clock = VirtualClock::new();
+        let idle_timeout = Duration::from_secs(60);
+        let manager = spawn_manager(
+            clock.clone(),
+            PassivationConfig {
+                idle_timeout: Some(idle_timeout),
+                ..Default::default()
+            },
+        );
+
+        let sweep = || async {
+            while clock.pending_sleeps() == 0 {
+                tokio::task::yield_now().await;
+            }
+            clock.advance(idle_timeout);
+            while clock.pending_sleeps() == 0 {
+                tokio::task::yield_now().await;
+            }
+        };
+
+        deposit(&manager, 1, 1).await;
+        // Used during this timeout
+        sweep().await;
+        let metrics = manager.send_metrics_async(Metrics).await.unwrap();
+        assert_eq!(metrics.passivations, 0);
+
+        // Idle for a whole timeout
+        sweep().await;
+        let metrics = manager.send_metrics_async(Metrics).await.unwrap();
+        assert_eq!(metrics.passivations, 1);
+        assert_eq!(metrics.active, 0);
+
+        withdraw(&manager, 1, 2).await;
+        let metrics = manager.send_metrics_async(Metrics).await.unwrap();
+        assert_eq!(metrics.activations, 2);
+        assert_eq!(metrics.restored, 1);
+    }
+
+    #[tokio::test]
+    pub async fn ok_passivated_accounts_survive_restarts() {
+        init_log();
+
+        let path = temp_path("manager-restart");
+        let passivation = PassivationConfig {
+            max_active_accounts: Some(1),
+            ..Default::default()
+        };
+
+        let store = Arc::new(FileAccountStore::open(&path).unwrap());
+        let manager1 = manager(VirtualClock::new(), passivation)
+            .with_store(store)
+            .spawn();
+        deposit(&manager1, 1, 1).await;
+        // Passivates account 1
+        deposit(&manager1, 2, 2).await;
+
+        let store = Arc::new(FileAccountStore::open(&path).unwrap());
+        let manager2 = manager(VirtualClock::new(), passivation)
+            .with_store(store)
+            .spawn();
+        withdraw(&manager2, 1, 3).await;
+
+        let metrics = manager2.send_metrics_async(Metrics).await.unwrap();
+        assert_eq!(metrics.restored, 1);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    pub async fn err_crashed_accounts_restart_until_escalated() {
+        init_log();
+
+        let path = temp_path("manager-crashes");
+        let journal = Journal::open(&path, FsyncPolicy::Never).unwrap();
+        let manager = AccountManagerActor::new(0, Broadcast::new(), journaled_config(journal))
+            .with_passivation(PassivationConfig {
+                max_active_accounts: Some(1),
+                ..Default::default()
+            })
+            .with_dispute_policy(Arc::new(CrashOnWithdrawals))
+            .with_restart_policy(RestartPolicy {
+                max_restarts: 1,
+                window: Duration::from_secs(60),
+            })
+            .spawn();
+        let dispute = |transaction_id| DisputeRequest {
+            account_id: 1,
+            transaction_id,
+        };
+
+        deposit(&manager, 1, 1).await;
+        deposit(&manager, 1, 2).await;
+        // Passivates account 1, so the store has both deposits
+        deposit(&manager, 2, 3).await;
+        withdraw(&manager, 1, 4).await;
+
+        let response = manager.send_account_async(dispute(4)).await;
+        assert!(matches!(response, Err(ActorError::ActorStopped)));
+        while manager.send_metrics_async(Metrics).await.unwrap().restarts == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // The journaled withdrawal is still there, the dispute is skipped
+        withdraw(&manager, 1, 5).await;
+        let response = manager
+            .send_account_async(WithdrawRequest {
+                account_id: 1,
+                transaction_id: 6,
+                amount: 1 * Bitcoin,
+            })
+            .await;
+        assert!(matches!(
+            response,
+            Ok(AccountResponses::WithdrawResponse(WithdrawResponse::Error(
+                _
+            )))
+        ));
+
+        // Crashing again within the window is escalated.
+        // The manager still answers, but takes no more account requests.
+        let response = manager.send_account_async(dispute(5)).await;
+        assert!(matches!(response, Err(ActorError::ActorStopped)));
+        wait_escalated(&manager).await;
+        let metrics = manager.send_metrics_async(Metrics).await.unwrap();
+        assert_eq!(metrics.restarts, 1);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    pub async fn err_crashed_accounts_without_journal_are_escalated() {
+        init_log();
+
+        let manager = manager(VirtualClock::new(), PassivationConfig::default())
+            .with_dispute_policy(Arc::new(CrashOnWithdrawals))
+            .spawn();
+
+        deposit(&manager, 1, 1).await;
+        withdraw(&manager, 1, 2).await;
+        let response = manager
+            .send_account_async(DisputeRequest {
+                account_id: 1,
+                transaction_id: 2,
+            })
+            .await;
+        assert!(matches!(response, Err(ActorError::ActorStopped)));
+
+        // The withdrawal cannot be rebuilt
+        wait_escalated(&manager).await;
+        let metrics = manager.send_metrics_async(Metrics).await.unwrap();
+        assert_eq!(metrics.restarts, 0);
+    }
+
+    async fn wait_escalated(manager: &AccountManagerClient) {
+        loop {
+            let response = manager
+                .send_account_async(DepositRequest {
+                    account_id: 2,
+                    transaction_id: 8,
+                    amount: 1 * Bitcoin,
+                })
+                .await;
+            match response {
+                Err(ActorError::Escalated(_)) => break,
+                Ok(AccountResponses::DepositResponse(_)) => tokio::task::yield_now().await,
+                response => panic!("Unexpected {:?}", response),
+            }
+        }
+    }
+
+    fn operations() -> Vec<AccountRequests> {
+        let mut operations: Vec<AccountRequests> = vec![];
+        for transaction_id in 0..30 {
+            let account_id = transaction_id % 3;
+            operations.push(
+                DepositRequest {
+                    account_id,
+                    transaction_id,
+                    amount: 2 * Bitcoin,
+                }
+                .into(),
+            );
+            if transaction_id % 4 == 0 {
+                operations.push(
+                    DisputeRequest {
+                        account_id,
+                        transaction_id,
+                    }
+                    .into(),
+                );
+            }
+            if transaction_id % 8 == 0 {
+                operations.push(
+                    ResolveRequest {
+                        account_id,
+                        transaction_id,
+                    }
+                    .into(),
+                );
+            }
+            if transaction_id == 20 {
+                operations.push(
+                    ChargebackRequest {
+                        account_id,
+                        transaction_id,
+                    }
+                    .into(),
+                );
+            }
+        }
+        operations
+    }
+
+    async fn run(
+        path: &std::path::Path,
+        operations: &[AccountRequests],
+    ) -> AccountsStateAggregator {
+        let broadcast = Broadcast::new();
+        let recorder = broadcast.clone().spawn_recorder();
+        let manager = journaled(path, broadcast).recover().await.unwrap().spawn();
+
+        for operation in operations {
+            let _ = manager.send_account_async(operation.clone()).await;
+        }
+
+        let mut state = AccountsStateAggregator::default();
+        for event in recorder.stop().await {
+            state.handle(event);
+        }
+        state
+    }
+
+    fn journaled(path: &std::path::Path, broadcast: Broadcast<AllEvents>) -> AccountManagerActor {
+        let journal = Journal::open(path, FsyncPolicy::Never).unwrap();
+        AccountManagerActor::new(0, broadcast, journaled_config(journal))
+    }
+
+    // Kills the runtime the manager runs on, so it is never stopped
+    // and its accounts are never passivated.
+    fn crash(path: &std::path::Path, operations: &[AccountRequests]) {
+        let path = path.to_owned();
+        let operations = operations.to_vec();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let manager = journaled(&path, Broadcast::new()).spawn();
+                for operation in operations {
+                    let _ = manager.send_account_async(operation).await;
+                }
+                std::mem::forget(manager);
+            });
+            runtime.shutdown_background();
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[tokio::test]
+    pub async fn ok_journal_recovers_from_crash() {
+        init_log();
+
+        let path = |name: &str| temp_path(&format!("manager-{}", name));
+
+        let operations = operations();
+        let uninterrupted = run(&path("uninterrupted"), &operations).await;
+
+        // Killed in the middle of the file
+        let crashed = path("crashed");
+        let (before, after) = operations.split_at(operations.len() / 2);
+        crash(&crashed, before);
+        let recovered = run(&crashed, after).await;
+
+        assert_eq!(recovered, uninterrupted);
+        assert!(recovered.accounts[&2].locked);
+        let _ = std::fs::remove_file(path("uninterrupted"));
+        let _ = std::fs::remove_file(crashed);
+    }
+
+    #[tokio::test]
+    pub async fn ok_journal_keeps_passivated_accounts() {
+        init_log();
+
+        let path = temp_path("manager-compaction");
+        let passivation = PassivationConfig {
+            max_active_accounts: Some(1),
+            ..Default::default()
+        };
+        let spawn = || {
+            let journal = Journal::open(&path, FsyncPolicy::Never)
+                .unwrap()
+                .with_compaction(1);
+            AccountManagerActor::new(0, Broadcast::new(), journaled_config(journal))
+                .with_passivation(passivation)
+        };
+
+        let manager = spawn().spawn();
+        deposit(&manager, 1, 1).await;
+        deposit(&manager, 1, 2).await;
+        // Passivate account 1 and then account 2
+        deposit(&manager, 2, 3).await;
+        withdraw(&manager, 1, 4).await;
+
+        // Only the last withdraw is still journaled, but nothing
+        // is lost even if the store is gone with the process.
+        let journal = Journal::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(journal.requests().unwrap().len(), 1);
+        let recovered = spawn().recover().await.unwrap().spawn();
+        assert_recovered(snapshot(&recovered).await, snapshot(&manager).await);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    pub async fn ok_journal_follows_migrated_accounts() {
+        init_log();
+
+        let path = |name: &str| temp_path(&format!("manager-migrated-{}", name));
+        let (from, to) = (path("from"), path("to"));
+        let source = journaled(&from, Broadcast::new()).spawn();
+        deposit(&source, 1, 1).await;
+        deposit(&source, 2, 2).await;
+
+        let accounts = match source
+            .send_release_accounts_async(ReleaseAccountsRequest {
+                account_ids: vec![1],
+            })
+            .await
+        {
+            Ok(ReleaseAccountsResponse::Ok(accounts)) => accounts,
+            response => panic!("{:?}", response),
+        };
+        let target = journaled(&to, Broadcast::new()).spawn();
+        let restored = target
+            .send_restore_accounts_async(RestoreAccountsRequest { accounts })
+            .await;
+        assert!(matches!(restored, Ok(RestoreAccountsResponse::Ok)));
+
+        // Each journal only replays the accounts its manager owns
+        let replayed = |path| {
+            let journal = Journal::open(path, FsyncPolicy::Never).unwrap();
+            journal.accounts().unwrap().into_keys().collect::<Vec<_>>()
+        };
+        assert_eq!(replayed(&from), vec![2]);
+        assert_eq!(replayed(&to), vec![1]);
+        let recovered = journaled(&to, Broadcast::new())
+            .recover()
+            .await
+            .unwrap()
+            .spawn();
+        assert_recovered(snapshot(&recovered).await, snapshot(&target).await);
+        let _ = std::fs::remove_file(from);
+        let _ = std::fs::remove_file(to);
+    }
+
+    async fn snapshot(manager: &AccountManagerClient) -> Vec<AccountParts> {
+        let mut accounts = match manager
+            .send_snapshot_accounts_async(SnapshotAccountsRequest)
+            .await
+        {
+            Ok(SnapshotAccountsResponse::Ok(accounts)) => accounts,
+            response => panic!("{:?}", response),
+        };
+        accounts.sort_by_key(|account| account.id());
+        accounts.iter().map(|account| account.to_parts()).collect()
+    }
+
+    // Recovered accounts announce themselves on a new bus,
+    // so their events go on right after the ones before.
+    fn assert_recovered(mut recovered: Vec<AccountParts>, mut expected: Vec<AccountParts>) {
+        for (recovered, expected) in recovered.iter_mut().zip(expected.iter_mut()) {
+            assert_eq!(recovered.sequence, expected.sequence + 1);
+            recovered.sequence = 0;
+            expected.sequence = 0;
+        }
+        assert_eq!(recovered, expected);
+    }
+
+    #[tokio::test]
+    pub async fn ok_snapshot_restores_ledger() {
+        init_log();
+
+        let passivation = PassivationConfig {
+            max_active_accounts: Some(2),
+            ..Default::default()
+        };
+        let operations = operations();
+        let (before, after) = operations.split_at(operations.len() / 2);
+
+        let uninterrupted = spawn_manager(VirtualClock::new(), passivation);
+        for operation in operations.iter() {
+            let _ = uninterrupted.send_account_async(operation.clone()).await;
+        }
+
+        let manager1 = spawn_manager(VirtualClock::new(), passivation);
+        for operation in before {
+            let _ = manager1.send_account_async(operation.clone()).await;
+        }
+        let accounts = match manager1
+            .send_snapshot_accounts_async(SnapshotAccountsRequest)
+            .await
+        {
+            Ok(SnapshotAccountsResponse::Ok(accounts)) => accounts,
+            response => panic!("{:?}", response),
+        };
+        assert_eq!(accounts.len(), 3);
+
+        let manager2 = spawn_manager(VirtualClock::new(), passivation);
+        let response = manager2
+            .send_restore_accounts_async(RestoreAccountsRequest { accounts })
+            .await;
+        assert!(matches!(response, Ok(RestoreAccountsResponse::Ok)));
+        for operation in after {
+            let _ = manager2.send_account_async(operation.clone()).await;
+        }
+
+        assert_eq!(snapshot(&manager2).await, snapshot(&uninterrupted).await);
+    }
+}
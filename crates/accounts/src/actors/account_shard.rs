@@ -1,12 +1,19 @@
+use std::collections::HashMap;
+
 use flume::Sender;
 use hashring::HashRing;
 
-use crate::gen_client_extension_methods;
+use crate::{domain::account::Account, gen_client_extension_methods};
 
 use super::{
     account::{AccountRequests, AccountResponses},
-    account_manager::AccountManagerClient,
-    Actor, CommandEnvelope,
+    account_manager::{
+        AccountIdsRequest, AccountIdsResponse, AccountManagerClient, AccountManagerResponses,
+        ReleaseAccountsRequest, ReleaseAccountsResponse, RestoreAccountsRequest,
+        RestoreAccountsResponse, SnapshotAccountsRequest, SnapshotAccountsResponse,
+    },
+    deadline::Deadline,
+    Actor, ActorError, CommandEnvelope, Lifecycle,
 };
 
 #[derive(Clone)]
@@ -15,11 +22,38 @@ pub struct AccountShardClient(Sender<Envelope>);
 gen_client_extension_methods! {
     impl AccountShard for AccountShardClient {
         fn account(_: AccountRequests) -> AccountResponses;
+        fn snapshot_accounts(_: SnapshotAccountsRequest) -> SnapshotAccountsResponse;
+        fn restore_accounts(_: RestoreAccountsRequest) -> RestoreAccountsResponse;
+        fn add_manager(_: AddManagerRequest) -> AddManagerResponse;
+        fn remove_manager(_: RemoveManagerRequest) -> RemoveManagerResponse;
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct AddManagerRequest(pub AccountManagerClient);
+
+#[derive(Clone, Debug)]
+pub enum AddManagerResponse {
+    Ok { migrated: usize },
+    Error(ActorError),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RemoveManagerRequest {
+    pub id: u64,
+}
+
+#[derive(Clone, Debug)]
+pub enum RemoveManagerResponse {
+    Ok { migrated: usize },
+    Error(ActorError),
+}
+
 pub struct AccountShardActor {
     ring: HashRing<AccountManagerClient>,
+    managers: Vec<AccountManagerClient>,
+    mailbox_capacity: Option<usize>,
+    sender: Option<Sender<Envelope>>,
 }
 
 #[async_trait::async_trait]
@@ -33,47 +67,855 @@ impl Actor<AccountShardRequests, AccountShardResponses> for AccountShardActor {
         AccountShardClient(sender)
     }
 
+    fn set_sender(
+        &mut self,
+        sender: flume::Sender<CommandEnvelope<AccountShardRequests, AccountShardResponses>>,
+    ) {
+        self.sender = Some(sender);
+    }
+
+    fn mailbox_capacity(&self) -> Option<usize> {
+        self.mailbox_capacity
+    }
+
     async fn handle_request(
         &mut self,
         request: AccountShardRequests,
         callback: Sender<AccountShardResponses>,
+        deadline: Deadline,
     ) {
+        if let Err(err) = deadline.check() {
+            let _ = callback.send_async(AccountShardResponses::Error(err)).await;
+            return;
+        }
+
         use AccountShardRequests::*;
         match request {
-            AccountRequest(r) => self.redirect_request(r, callback),
+            AccountRequest(r) => self.redirect_request(r, callback, deadline).await,
+            SnapshotAccountsRequest(_) => self.snapshot_accounts(callback),
+            RestoreAccountsRequest(r) => self.restore_accounts(r, callback),
+            AddManagerRequest(r) => {
+                let response = match self.add_manager(r.0).await {
+                    Ok(migrated) => AddManagerResponse::Ok { migrated },
+                    Err(err) => AddManagerResponse::Error(err),
+                };
+                let _ = callback.send_async(response.into()).await;
+            }
+            RemoveManagerRequest(r) => {
+                let response = match self.remove_manager(r.id).await {
+                    Ok(migrated) => RemoveManagerResponse::Ok { migrated },
+                    Err(err) => RemoveManagerResponse::Error(err),
+                };
+                let _ = callback.send_async(response.into()).await;
+            }
+            Lifecycle(_) => unreachable!("Lifecycle messages are handled by Actor::handle"),
         };
     }
+
+    // Managers answer once their accounts did, so every request
+    // redirected before has been accepted when this returns.
+    async fn handle_lifecycle(&mut self, lifecycle: Lifecycle) {
+        for manager in self.managers.iter() {
+            let result = match lifecycle {
+                Lifecycle::Drain => manager.send_drain_async().await,
+                Lifecycle::Stop => manager.send_stop_async().await,
+            };
+            if let Err(err) = result {
+                tracing::error!("Manager {} not drained: {:?}", manager.id(), err);
+            }
+        }
+    }
 }
 
 impl AccountShardActor {
     pub fn new(clients: Vec<AccountManagerClient>) -> Self {
         let mut ring = HashRing::new();
-        for client in clients {
-            ring.add(client);
+        for client in clients.iter() {
+            ring.add(client.clone());
+        }
+
+        Self {
+            ring,
+            managers: clients,
+            mailbox_capacity: None,
+            sender: None,
+        }
+    }
+
+    pub fn with_mailbox_capacity(mut self, capacity: usize) -> Self {
+        self.mailbox_capacity = Some(capacity);
+        self
+    }
+
+    // Accounts of all managers.
+    pub fn snapshot_accounts(&mut self, callback: Sender<AccountShardResponses>) {
+        let managers = self.managers.clone();
+        tokio::task::spawn(async move {
+            let mut accounts = vec![];
+            for manager in managers {
+                match manager
+                    .send_snapshot_accounts_async(SnapshotAccountsRequest)
+                    .await
+                {
+                    Ok(SnapshotAccountsResponse::Ok(x)) => accounts.extend(x),
+                    Ok(SnapshotAccountsResponse::Error(err)) | Err(err) => {
+                        let response = SnapshotAccountsResponse::Error(err);
+                        let _ = callback.send_async(response.into()).await;
+                        return;
+                    }
+                }
+            }
+            let response = SnapshotAccountsResponse::Ok(accounts);
+            let _ = callback.send_async(response.into()).await;
+        });
+    }
+
+    // Each account goes to the manager that owns it.
+    pub fn restore_accounts(
+        &mut self,
+        request: RestoreAccountsRequest,
+        callback: Sender<AccountShardResponses>,
+    ) {
+        let ring = self.ring.clone();
+        tokio::task::spawn(async move {
+            let response = match Self::restore(&ring, request.accounts.into_iter()).await {
+                Ok(()) => RestoreAccountsResponse::Ok,
+                Err(err) => RestoreAccountsResponse::Error(err),
+            };
+            let _ = callback.send_async(response.into()).await;
+        });
+    }
+
+    // While accounts migrate, new requests wait in the mailbox.
+    // Returns how many accounts moved.
+    pub async fn add_manager(
+        &mut self,
+        manager: AccountManagerClient,
+    ) -> Result<usize, ActorError> {
+        if self.managers.iter().any(|m| m.id() == manager.id()) {
+            let reason = format!("Manager {} already added", manager.id());
+            return Err(ActorError::InvalidRequest(reason));
+        }
+
+        let mut ring = self.ring.clone();
+        ring.add(manager.clone());
+        let mut managers = self.managers.clone();
+        managers.push(manager);
+        self.rebalance(ring, managers).await
+    }
+
+    // All accounts of the removed manager move to the others.
+    pub async fn remove_manager(&mut self, id: u64) -> Result<usize, ActorError> {
+        let manager = match self.managers.iter().find(|m| m.id() == id) {
+            Some(manager) => manager.clone(),
+            None => {
+                return Err(ActorError::InvalidRequest(format!(
+                    "Unknown manager {}",
+                    id
+                )))
+            }
+        };
+        if self.managers.len() == 1 {
+            let reason = "Cannot remove the last manager".into();
+            return Err(ActorError::InvalidRequest(reason));
+        }
+
+        let mut ring = self.ring.clone();
+        ring.remove(&manager);
+        let mut managers = self.managers.clone();
+        managers.retain(|m| m.id() != id);
+        self.rebalance(ring, managers).await
+    }
+
+    // Moves accounts whose owner is different in the new ring.
+    // Requests already redirected to the old owner are applied before it releases them.
+    // If anything fails, released accounts go back and the old ring is kept.
+    async fn rebalance(
+        &mut self,
+        ring: HashRing<AccountManagerClient>,
+        managers: Vec<AccountManagerClient>,
+    ) -> Result<usize, ActorError> {
+        let mut released = vec![];
+        let mut result = Ok(());
+        for manager in self.managers.iter() {
+            match Self::release_moving(manager, &ring).await {
+                Ok(accounts) => released.push((manager.clone(), accounts)),
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+
+        if result.is_ok() {
+            let accounts = released.iter().flat_map(|(_, accounts)| accounts.clone());
+            result = Self::restore(&ring, accounts).await;
+        }
+
+        match result {
+            Ok(()) => {
+                self.ring = ring;
+                self.managers = managers;
+                Ok(released.iter().map(|(_, accounts)| accounts.len()).sum())
+            }
+            Err(err) => {
+                // Copies already restored on their new owners are dropped first
+                let moved = released.iter().flat_map(|(_, accounts)| accounts);
+                Self::release_copies(&ring, moved).await;
+                for (manager, accounts) in released {
+                    let request = RestoreAccountsRequest { accounts };
+                    if let Err(err) = manager.send_restore_accounts_async(request).await {
+                        tracing::error!("Accounts of manager {} lost: {:?}", manager.id(), err);
+                    }
+                }
+                Err(err)
+            }
+        }
+    }
+
+    async fn release_moving(
+        manager: &AccountManagerClient,
+        ring: &HashRing<AccountManagerClient>,
+    ) -> Result<Vec<Account>, ActorError> {
+        let account_ids = match manager.send_account_ids_async(AccountIdsRequest).await {
+            Ok(AccountIdsResponse::Ok(account_ids)) => account_ids,
+            Ok(AccountIdsResponse::Error(err)) | Err(err) => return Err(err),
+        };
+        let account_ids: Vec<_> = account_ids
+            .into_iter()
+            .filter(|account_id| ring.get(account_id).map(|m| m.id()) != Some(manager.id()))
+            .collect();
+        if account_ids.is_empty() {
+            return Ok(vec![]);
         }
 
-        Self { ring }
+        match manager
+            .send_release_accounts_async(ReleaseAccountsRequest { account_ids })
+            .await
+        {
+            Ok(ReleaseAccountsResponse::Ok(accounts)) => Ok(accounts),
+            Ok(ReleaseAccountsResponse::Error(err)) | Err(err) => Err(err),
+        }
+    }
+
+    async fn release_copies<'a>(
+        ring: &HashRing<AccountManagerClient>,
+        accounts: impl Iterator<Item = &'a Account>,
+    ) {
+        let mut by_manager: HashMap<u64, (AccountManagerClient, Vec<_>)> = HashMap::new();
+        for account in accounts {
+            if let Some(manager) = ring.get(&account.id()) {
+                by_manager
+                    .entry(manager.id())
+                    .or_insert_with(|| (manager.clone(), vec![]))
+                    .1
+                    .push(account.id());
+            }
+        }
+
+        for (manager, account_ids) in by_manager.into_values() {
+            let request = ReleaseAccountsRequest { account_ids };
+            match manager.send_release_accounts_async(request).await {
+                Ok(ReleaseAccountsResponse::Ok(_)) => {}
+                Ok(ReleaseAccountsResponse::Error(err)) | Err(err) => {
+                    tracing::error!("Copies left on manager {}: {:?}", manager.id(), err)
+                }
+            }
+        }
+    }
+
+    // Each account goes to the manager that owns it in the ring.
+    async fn restore(
+        ring: &HashRing<AccountManagerClient>,
+        accounts: impl Iterator<Item = Account>,
+    ) -> Result<(), ActorError> {
+        let mut by_manager: HashMap<u64, (AccountManagerClient, Vec<_>)> = HashMap::new();
+        for account in accounts {
+            let manager = ring
+                .get(&account.id())
+                .cloned()
+                .ok_or(ActorError::ShardUnavailable)?;
+            by_manager
+                .entry(manager.id())
+                .or_insert_with(|| (manager, vec![]))
+                .1
+                .push(account);
+        }
+
+        for (manager, accounts) in by_manager.into_values() {
+            let request = RestoreAccountsRequest { accounts };
+            match manager.send_restore_accounts_async(request).await {
+                Ok(RestoreAccountsResponse::Ok) => {}
+                Ok(RestoreAccountsResponse::Error(err)) | Err(err) => return Err(err),
+            }
+        }
+        Ok(())
     }
 
     #[tracing::instrument(skip(self, callback))]
-    pub fn redirect_request(
+    pub async fn redirect_request(
         &mut self,
         request: AccountRequests,
         callback: Sender<AccountShardResponses>,
+        deadline: Deadline,
     ) {
-        let account_id = request.get_account_id();
-        let account = self.ring.get(&account_id).cloned().unwrap(); //TODO remove unwrap
+        let account_id = match request.account_id() {
+            Some(account_id) => account_id,
+            None => {
+                // Lifecycle messages go to the shard, which forwards them to every manager
+                let reason = format!("{:?} is not for an account", request);
+                let err = ActorError::InvalidRequest(reason);
+                let _ = callback.send_async(AccountShardResponses::Error(err)).await;
+                return;
+            }
+        };
 
+        // Enqueued before handling anything else, so a rebalance
+        // only releases the account after applying this request.
+        let (manager_id, response) = match self.ring.get(&account_id) {
+            Some(manager) => (
+                Some(manager.id()),
+                manager
+                    .enqueue_before_async(request.into(), deadline)
+                    .await
+                    .map_err(|_| ActorError::ShardUnavailable),
+            ),
+            None => (None, Err(ActorError::ShardUnavailable)),
+        };
+        let sender = self.sender.clone();
         tokio::task::spawn(async move {
-            match account.send_account_async(request).await {
+            let response = match response {
+                Ok(response) => match response.recv_async().await {
+                    Ok(AccountManagerResponses::AccountResponse(response)) => Ok(response),
+                    Ok(AccountManagerResponses::Error(err)) => Err(err),
+                    Ok(response) => Err(ActorError::UnexpectedResponse(format!("{:?}", response))),
+                    Err(_) => Err(ActorError::ActorStopped),
+                },
+                Err(err) => Err(err),
+            };
+            match response {
                 Ok(response) => {
                     let _ = callback.send_async(response.into()).await;
                 }
                 Err(err) => {
                     tracing::warn!("{:?}", err);
+                    let escalated = matches!(err, ActorError::Escalated(_));
                     let _ = callback.send_async(AccountShardResponses::Error(err)).await;
+                    if let (true, Some(id), Some(sender)) = (escalated, manager_id, sender) {
+                        Self::remove_escalated(sender, id).await;
+                    }
                 }
             }
         });
     }
+
+    // Managers that gave up on their accounts are removed,
+    // so the accounts move to the other managers.
+    async fn remove_escalated(sender: Sender<Envelope>, id: u64) {
+        let (callback, response) = flume::bounded(1);
+        let request = AccountShardRequests::RemoveManagerRequest(RemoveManagerRequest { id });
+        if sender
+            .send_async(CommandEnvelope::new(request, callback))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        match response.recv_async().await {
+            Ok(AccountShardResponses::RemoveManagerResponse(RemoveManagerResponse::Ok {
+                migrated,
+            })) => {
+                tracing::warn!(
+                    "Escalated manager {} removed. {} accounts moved.",
+                    id,
+                    migrated
+                );
+            }
+            response => tracing::error!("Escalated manager {} not removed: {:?}", id, response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use rust_decimal::Decimal;
+
+    use crate::{
+        actors::{
+            account::{
+                AccountActorConfig, AccountRequests, AccountResponses, DepositRequest,
+                DepositResponse, DisputeRequest, Scheduling, WithdrawRequest,
+            },
+            account_manager::{
+                AccountIdsRequest, AccountIdsResponse, AccountManagerActor, AccountManagerClient,
+                PassivationConfig, SnapshotAccountsRequest, SnapshotAccountsResponse,
+            },
+            aggregators::accounts_state_aggregator::AccountsStateActor,
+            clock::VirtualClock,
+            deadline::{CancellationToken, Deadline},
+            init_log,
+            supervisor::RestartPolicy,
+            test_utils::CrashOnWithdrawals,
+            Actor, ActorError, Lifecycle, Spawn,
+        },
+        broadcast::Broadcast,
+        domain::{
+            account::{Account, AccountErrors, AccountParts},
+            dispute_policy::DisputePolicy,
+            money::Currency::*,
+        },
+        store::{memory::InMemoryAccountStore, AccountStore, StoreError},
+    };
+
+    use super::{
+        AccountShardActor, AccountShardClient, AccountShardResponses, AddManagerRequest,
+        AddManagerResponse, RemoveManagerRequest, RemoveManagerResponse,
+    };
+
+    fn manager(id: u64) -> AccountManagerClient {
+        let config = AccountActorConfig {
+            scheduling: Scheduling::Deterministic,
+            ..Default::default()
+        };
+        AccountManagerActor::new(id, Broadcast::new(), config)
+            .with_passivation(PassivationConfig {
+                max_active_accounts: Some(8),
+                ..Default::default()
+            })
+            .spawn()
+    }
+
+    fn operations(transactions: std::ops::Range<u32>) -> Vec<AccountRequests> {
+        let mut operations: Vec<AccountRequests> = vec![];
+        for transaction_id in transactions {
+            let account_id = transaction_id % 20;
+            operations.push(
+                DepositRequest {
+                    account_id,
+                    transaction_id,
+                    amount: 3 * Bitcoin,
+                }
+                .into(),
+            );
+            if transaction_id % 3 == 0 {
+                operations.push(
+                    WithdrawRequest {
+                        account_id,
+                        transaction_id: transaction_id + 1_000,
+                        amount: 1 * Bitcoin,
+                    }
+                    .into(),
+                );
+            }
+            if transaction_id % 7 == 0 {
+                operations.push(
+                    DisputeRequest {
+                        account_id,
+                        transaction_id,
+                    }
+                    .into(),
+                );
+            }
+        }
+        operations
+    }
+
+    // Every operation is enqueued before waiting for any response,
+    // so they are in flight while the shard rebalances.
+    async fn send_all(shard: &AccountShardClient, operations: Vec<AccountRequests>) {
+        let responses = enqueue_all(shard, operations).await;
+        receive_all(responses).await;
+    }
+
+    // Returns once every operation is in the shard mailbox.
+    async fn enqueue_all(
+        shard: &AccountShardClient,
+        operations: Vec<AccountRequests>,
+    ) -> Vec<flume::Receiver<AccountShardResponses>> {
+        let mut responses = vec![];
+        for operation in operations {
+            responses.push(shard.enqueue_async(operation.into()).await.unwrap());
+        }
+        responses
+    }
+
+    async fn receive_all(responses: Vec<flume::Receiver<AccountShardResponses>>) {
+        for response in responses {
+            let response = response.recv_async().await;
+            assert!(
+                matches!(response, Ok(AccountShardResponses::AccountResponse(_))),
+                "{:?}",
+                response
+            );
+        }
+    }
+
+    async fn snapshot(shard: &AccountShardClient) -> Vec<AccountParts> {
+        let mut accounts = match shard
+            .send_snapshot_accounts_async(SnapshotAccountsRequest)
+            .await
+        {
+            Ok(SnapshotAccountsResponse::Ok(accounts)) => accounts,
+            response => panic!("{:?}", response),
+        };
+        accounts.sort_by_key(|account| account.id());
+        accounts.iter().map(|account| account.to_parts()).collect()
+    }
+
+    #[tokio::test]
+    pub async fn ok_rebalancing_keeps_balances() {
+        init_log();
+
+        let uninterrupted = AccountShardActor::new(vec![manager(0)]).spawn();
+        send_all(&uninterrupted, operations(0..300)).await;
+
+        let shard = AccountShardActor::new(vec![manager(0)]).spawn();
+        // Operations are still being applied while managers come and go
+        let sending = enqueue_all(&shard, operations(0..100)).await;
+        let added = shard
+            .send_add_manager_async(AddManagerRequest(manager(1)))
+            .await;
+        assert!(matches!(added, Ok(AddManagerResponse::Ok { migrated }) if migrated > 0));
+        receive_all(sending).await;
+
+        let sending = enqueue_all(&shard, operations(100..200)).await;
+        let added = shard
+            .send_add_manager_async(AddManagerRequest(manager(2)))
+            .await;
+        assert!(matches!(added, Ok(AddManagerResponse::Ok { .. })));
+        let removed = shard
+            .send_remove_manager_async(RemoveManagerRequest { id: 0 })
+            .await;
+        assert!(matches!(removed, Ok(RemoveManagerResponse::Ok { migrated }) if migrated > 0));
+        receive_all(sending).await;
+
+        send_all(&shard, operations(200..300)).await;
+        assert_eq!(snapshot(&shard).await, snapshot(&uninterrupted).await);
+
+        let removed = shard
+            .send_remove_manager_async(RemoveManagerRequest { id: 0 })
+            .await;
+        assert!(matches!(removed, Ok(RemoveManagerResponse::Error(_))));
+    }
+
+    // Fails to save the second account, after saving the first
+    #[derive(Debug, Default)]
+    struct FailingStore {
+        saved: std::sync::atomic::AtomicUsize,
+        store: InMemoryAccountStore,
+    }
+
+    impl AccountStore for FailingStore {
+        fn load(
+            &self,
+            account_id: u32,
+            dispute_policy: &Arc<dyn DisputePolicy>,
+        ) -> Result<Option<Account>, StoreError> {
+            self.store.load(account_id, dispute_policy)
+        }
+
+        fn save(&self, account: &Account) -> Result<(), StoreError> {
+            match self.saved.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 => self.store.save(account),
+                _ => Err(std::io::Error::other("disk full").into()),
+            }
+        }
+
+        fn delete(&self, account_id: u32) -> Result<(), StoreError> {
+            self.store.delete(account_id)
+        }
+
+        fn scan(
+            &self,
+            dispute_policy: &Arc<dyn DisputePolicy>,
+            visit: &mut dyn FnMut(Account),
+        ) -> Result<(), StoreError> {
+            self.store.scan(dispute_policy, visit)
+        }
+    }
+
+    #[tokio::test]
+    pub async fn err_failed_rebalancing_leaves_no_copies() {
+        init_log();
+
+        let shard = AccountShardActor::new(vec![manager(0)]).spawn();
+        send_all(&shard, operations(0..100)).await;
+        let before = snapshot(&shard).await;
+
+        let config = AccountActorConfig {
+            scheduling: Scheduling::Deterministic,
+            ..Default::default()
+        };
+        let failing = AccountManagerActor::new(1, Broadcast::new(), config)
+            .with_store(Arc::new(FailingStore::default()))
+            .spawn();
+        let added = shard
+            .send_add_manager_async(AddManagerRequest(failing.clone()))
+            .await;
+        assert!(matches!(added, Ok(AddManagerResponse::Error(_))));
+
+        // The account restored before failing went back too
+        let account_ids = failing.send_account_ids_async(AccountIdsRequest).await;
+        assert!(matches!(account_ids, Ok(AccountIdsResponse::Ok(ids)) if ids.is_empty()));
+        assert_eq!(snapshot(&shard).await, before);
+    }
+
+    #[tokio::test]
+    pub async fn ok_escalated_manager_is_removed() {
+        init_log();
+
+        let config = AccountActorConfig {
+            scheduling: Scheduling::Deterministic,
+            ..Default::default()
+        };
+        let managers = (0..2)
+            .map(|id| {
+                AccountManagerActor::new(id, Broadcast::new(), config.clone())
+                    .with_dispute_policy(Arc::new(CrashOnWithdrawals))
+                    // The first crash is escalated
+                    .with_restart_policy(RestartPolicy {
+                        max_restarts: 0,
+                        window: Duration::from_secs(60),
+                    })
+                    .spawn()
+            })
+            .collect();
+        let shard = AccountShardActor::new(managers).spawn();
+
+        let mut operations: Vec<AccountRequests> = vec![];
+        for account_id in 0..8 {
+            operations.push(
+                DepositRequest {
+                    account_id,
+                    transaction_id: account_id,
+                    amount: 2 * Bitcoin,
+                }
+                .into(),
+            );
+            operations.push(
+                WithdrawRequest {
+                    account_id,
+                    transaction_id: 100 + account_id,
+                    amount: 1 * Bitcoin,
+                }
+                .into(),
+            );
+        }
+        send_all(&shard, operations).await;
+
+        let response = shard
+            .send_account_async(DisputeRequest {
+                account_id: 0,
+                transaction_id: 100,
+            })
+            .await;
+        assert!(matches!(response, Err(ActorError::ActorStopped)));
+
+        // Fails until the shard removed the manager of the crashed account
+        for transaction_id in 200.. {
+            let response = shard
+                .send_account_async(DepositRequest {
+                    account_id: 0,
+                    transaction_id,
+                    amount: 1 * Bitcoin,
+                })
+                .await;
+            match response {
+                Ok(AccountResponses::DepositResponse(DepositResponse::Ok)) => break,
+                Err(_) => tokio::task::yield_now().await,
+                response => panic!("Unexpected {:?}", response),
+            }
+        }
+
+        // Accounts of the removed manager moved with their balances
+        let operations = (1..8)
+            .map(|account_id| {
+                WithdrawRequest {
+                    account_id,
+                    transaction_id: 300 + account_id,
+                    amount: 1 * Bitcoin,
+                }
+                .into()
+            })
+            .collect();
+        send_all(&shard, operations).await;
+        let accounts = snapshot(&shard).await;
+        assert_eq!(accounts.len(), 8);
+        for account in accounts.into_iter().skip(1) {
+            assert!(account.total.is_zero(), "{:?}", account);
+        }
+    }
+
+    // Every mailbox holds one request, so senders keep waiting
+    // for the actors after them; nothing deadlocks or gets lost.
+    #[tokio::test]
+    pub async fn ok_bounded_mailboxes_keep_balances() {
+        init_log();
+
+        let unbounded = AccountShardActor::new(vec![manager(0)]).spawn();
+        send_all(&unbounded, operations(0..300)).await;
+
+        let config = AccountActorConfig {
+            scheduling: Scheduling::Deterministic,
+            mailbox_capacity: Some(1),
+            ..Default::default()
+        };
+        let managers = (0..2)
+            .map(|id| {
+                AccountManagerActor::new(id, Broadcast::new(), config.clone())
+                    .with_mailbox_capacity(1)
+                    .spawn()
+            })
+            .collect();
+        let shard = AccountShardActor::new(managers)
+            .with_mailbox_capacity(1)
+            .spawn();
+        assert_eq!(shard.0.capacity(), Some(1));
+
+        send_all(&shard, operations(0..300)).await;
+        assert_eq!(snapshot(&shard).await, snapshot(&unbounded).await);
+    }
+
+    #[tokio::test]
+    pub async fn err_typed_errors_reach_caller() {
+        init_log();
+
+        let shard = AccountShardActor::new(vec![manager(0)]).spawn();
+        let withdrawn = shard
+            .send_account_async(WithdrawRequest {
+                account_id: 1,
+                transaction_id: 1,
+                amount: 1 * Bitcoin,
+            })
+            .await
+            .and_then(|response| response.into_result());
+        assert!(matches!(
+            withdrawn,
+            Err(ActorError::Domain(AccountErrors::NegativeAmount))
+        ));
+
+        let removed = shard
+            .send_remove_manager_async(RemoveManagerRequest { id: 7 })
+            .await;
+        assert!(matches!(
+            removed,
+            Ok(RemoveManagerResponse::Error(ActorError::InvalidRequest(_)))
+        ));
+
+        // Not for any account, so no manager gets it
+        let drained = shard
+            .send_account_async(AccountRequests::Lifecycle(Lifecycle::Drain))
+            .await;
+        assert!(matches!(drained, Err(ActorError::InvalidRequest(_))));
+    }
+
+    #[tokio::test]
+    pub async fn ok_expired_requests_are_skipped() {
+        init_log();
+
+        let clock = VirtualClock::new();
+        let config = AccountActorConfig {
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        };
+        let manager = AccountManagerActor::new(0, Broadcast::new(), config).spawn();
+        let shard = AccountShardActor::new(vec![manager]).spawn();
+
+        let deposit = |transaction_id| DepositRequest {
+            account_id: 1,
+            transaction_id,
+            amount: 1 * Bitcoin,
+        };
+        let deadline = Deadline::after(Arc::new(clock.clone()), Duration::ZERO);
+        let response = shard.send_account_before_async(deposit(0), deadline).await;
+        assert!(matches!(response, Err(ActorError::Timeout)));
+
+        // Both wait the reorder window of the account,
+        // and the first is cancelled meanwhile
+        let cancellation = CancellationToken::new();
+        let deadline = Deadline::never().with_cancellation(cancellation.clone());
+        let cancelled = shard
+            .send_account_before_async(deposit(1), deadline)
+            .spawn();
+        let deposited = shard.send_account_async(deposit(2)).spawn();
+        while clock.pending_sleeps() < 2 {
+            tokio::task::yield_now().await;
+        }
+        cancellation.cancel();
+        assert!(matches!(
+            cancelled.await.unwrap(),
+            Err(ActorError::Cancelled)
+        ));
+        clock.advance(AccountActorConfig::default().reorder_window);
+        assert!(matches!(
+            deposited.await.unwrap(),
+            Ok(AccountResponses::DepositResponse(DepositResponse::Ok))
+        ));
+
+        let mut expected = Account::new(1);
+        let _ = expected.deposit(2, 1 * Bitcoin);
+        expected.set_sequence(1);
+        assert_eq!(snapshot(&shard).await, vec![expected.to_parts()]);
+    }
+
+    #[tokio::test]
+    pub async fn ok_drain_accepts_pending_requests() {
+        init_log();
+
+        let clock = VirtualClock::new();
+        let config = AccountActorConfig {
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        };
+        let broadcast = Broadcast::new();
+        let aggregator = AccountsStateActor::new(broadcast.clone()).spawn();
+        let manager = AccountManagerActor::new(0, broadcast, config).spawn();
+        let shard = AccountShardActor::new(vec![manager]).spawn();
+
+        let deposits: Vec<_> = (0..3)
+            .map(|transaction_id| {
+                let deposit = DepositRequest {
+                    account_id: transaction_id % 2,
+                    transaction_id,
+                    amount: 1 * Bitcoin,
+                };
+                shard.send_account_async(deposit).spawn()
+            })
+            .collect();
+        // Both accounts wait their reorder window, which never passes
+        while clock.pending_sleeps() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        shard.send_drain_async().await.unwrap();
+        aggregator.send_drain_async().await.unwrap();
+        for deposit in deposits {
+            assert!(matches!(
+                deposit.await.unwrap(),
+                Ok(AccountResponses::DepositResponse(DepositResponse::Ok))
+            ));
+        }
+        let state = aggregator.get_state().await.unwrap();
+        assert_eq!(state.accounts[&0].total, Decimal::from(2));
+        assert_eq!(state.accounts[&1].total, Decimal::from(1));
+        let expired = Deadline::after(Arc::new(clock.clone()), Duration::ZERO);
+        let state = aggregator.get_state_before(expired).await;
+        assert!(matches!(state, Err(ActorError::Timeout)));
+
+        shard.send_stop_async().await.unwrap();
+        let response = shard
+            .send_account_async(DepositRequest {
+                account_id: 0,
+                transaction_id: 3,
+                amount: 1 * Bitcoin,
+            })
+            .await;
+        assert!(matches!(response, Err(ActorError::MailboxClosed)));
+
+        aggregator.send_stop_async().await.unwrap();
+        let state = aggregator.get_state().await;
+        assert!(matches!(state, Err(ActorError::ActorStopped)));
+    }
 }
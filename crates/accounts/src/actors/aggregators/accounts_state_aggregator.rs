@@ -6,7 +6,7 @@ use crate::domain::events::AllEvents;
 
 use super::{Aggregator, AggregatorActor};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct AccountState {
     pub client: u32,
     pub available: Decimal,
@@ -15,39 +15,78 @@ pub struct AccountState {
     pub locked: bool,
 }
 
-#[derive(Default, Clone, Debug)]
+#[derive(Default, Clone, Debug, PartialEq)]
 pub struct AccountsStateAggregator {
     pub accounts: HashMap<u32, AccountState>,
 }
 
+impl AccountsStateAggregator {
+    fn state(&mut self, account_id: u32) -> &mut AccountState {
+        self.accounts
+            .entry(account_id)
+            .or_insert_with(|| AccountState {
+                client: account_id,
+                available: Decimal::ZERO,
+                held: Decimal::ZERO,
+                total: Decimal::ZERO,
+                locked: false,
+            })
+    }
+}
+
 impl Aggregator for AccountsStateAggregator {
     type Event = AllEvents;
 
     fn handle(&mut self, event: AllEvents) {
         match event {
-            AllEvents::AccountUpdated {
+            AllEvents::Deposited {
+                account_id,
+                balances,
+                ..
+            }
+            | AllEvents::Withdrawn {
+                account_id,
+                balances,
+                ..
+            }
+            | AllEvents::DisputeOpened {
+                account_id,
+                balances,
+                ..
+            }
+            | AllEvents::DisputeResolved {
+                account_id,
+                balances,
+                ..
+            }
+            | AllEvents::ChargedBack {
                 account_id,
-                amount,
-                held,
+                balances,
+                ..
+            } => {
+                let state = self.state(account_id);
+                state.available = balances.available;
+                state.held = balances.held;
+                state.total = balances.total;
+            }
+            AllEvents::AccountLocked { account_id, .. } => self.state(account_id).locked = true,
+            AllEvents::AccountFrozen { account_id, .. } => self.state(account_id).locked = true,
+            AllEvents::AccountUnfrozen { account_id, .. } => self.state(account_id).locked = false,
+            AllEvents::HistoryRewound {
+                account_id,
+                balances,
                 locked,
                 ..
             } => {
-                let state = self
-                    .accounts
-                    .entry(account_id)
-                    .or_insert_with(|| AccountState {
-                        client: account_id,
-                        available: Decimal::ZERO,
-                        held: Decimal::ZERO,
-                        total: Decimal::ZERO,
-                        locked: false,
-                    });
-
+                let state = self.state(account_id);
+                state.available = balances.available;
+                state.held = balances.held;
+                state.total = balances.total;
                 state.locked = locked;
-                state.available = amount;
-                state.held = held;
-                state.total = state.available + state.held;
             }
+            AllEvents::OperationRejected { .. }
+            | AllEvents::OutcomeChanged { .. }
+            | AllEvents::AccountClosed { .. } => {}
         }
     }
 }
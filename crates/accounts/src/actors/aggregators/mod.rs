@@ -1,7 +1,7 @@
 pub mod accounts_state_aggregator;
 
-use super::{Actor, CommandEnvelope};
-use crate::broadcast::Broadcast;
+use super::{deadline::Deadline, Actor, ActorError, CommandEnvelope, Lifecycle, LifecycleRequest};
+use crate::broadcast::{Broadcast, Filter, Subscription, Topic};
 use flume::Sender;
 
 pub trait Aggregator {
@@ -21,24 +21,89 @@ impl<TState: Clone + std::fmt::Debug> AggregatorClient<TState> {
     pub async fn send_async(
         &self,
         payload: AggregatorRequests<TState>,
-    ) -> Result<AggregatorResponses, ()> {
-        let (callback, receiver) = flume::bounded(1);
-        let envelope = Envelope { payload, callback };
-        let _ = self.0.send_async(envelope).await;
-        let response = receiver.recv_async().await.map_err(|_| ());
+    ) -> Result<AggregatorResponses, ActorError> {
+        self.send_before_async(payload, Deadline::never()).await
+    }
 
-        response
+    pub async fn send_before_async(
+        &self,
+        payload: AggregatorRequests<TState>,
+        deadline: Deadline,
+    ) -> Result<AggregatorResponses, ActorError> {
+        deadline
+            .enforce(async {
+                let (callback, receiver) = flume::bounded(1);
+                let envelope = Envelope::new(payload, callback).with_deadline(deadline.clone());
+                self.0
+                    .send_async(envelope)
+                    .await
+                    .map_err(|_| ActorError::ActorStopped)?;
+                match receiver.recv_async().await {
+                    Ok(AggregatorResponses::Error(err)) => Err(err),
+                    Ok(response) => Ok(response),
+                    Err(_) => Err(ActorError::ActorStopped),
+                }
+            })
+            .await
+    }
+
+    // A copy of the current state.
+    pub async fn get_state(&self) -> Result<TState, ActorError>
+    where
+        TState: 'static + Send,
+    {
+        self.get_state_before(Deadline::never()).await
+    }
+
+    pub async fn get_state_before(&self, deadline: Deadline) -> Result<TState, ActorError>
+    where
+        TState: 'static + Send,
+    {
+        let (sender, receiver) = flume::bounded(1);
+        let call = move |state: &TState| {
+            let _ = sender.send(state.clone());
+        };
+        self.send_before_async(AggregatorRequests::Call(Box::new(call)), deadline)
+            .await?;
+        receiver
+            .recv_async()
+            .await
+            .map_err(|_| ActorError::ActorStopped)
+    }
+
+    // Waits until every event broadcast before was aggregated.
+    pub async fn send_drain_async(&self) -> Result<(), ActorError> {
+        self.send_async(AggregatorRequests::Lifecycle(Lifecycle::Drain))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn send_stop_async(&self) -> Result<(), ActorError> {
+        self.send_async(AggregatorRequests::Lifecycle(Lifecycle::Stop))
+            .await
+            .map(|_| ())
     }
 }
 
 pub enum AggregatorRequests<TState> {
     Call(Box<dyn Fn(&TState) + Send>),
+    Lifecycle(Lifecycle),
+}
+
+impl<TState> LifecycleRequest for AggregatorRequests<TState> {
+    fn lifecycle(&self) -> Option<Lifecycle> {
+        match self {
+            Self::Lifecycle(lifecycle) => Some(*lifecycle),
+            _ => None,
+        }
+    }
 }
 
 impl<TState> std::fmt::Debug for AggregatorRequests<TState> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Call(_) => f.debug_tuple("Call").finish(),
+            Self::Lifecycle(lifecycle) => f.debug_tuple("Lifecycle").field(lifecycle).finish(),
         }
     }
 }
@@ -46,21 +111,29 @@ impl<TState> std::fmt::Debug for AggregatorRequests<TState> {
 #[derive(Clone, Debug)]
 pub enum AggregatorResponses {
     Finished,
+    Lifecycle(Lifecycle),
+    Error(ActorError),
+}
+
+impl From<Lifecycle> for AggregatorResponses {
+    fn from(item: Lifecycle) -> Self {
+        AggregatorResponses::Lifecycle(item)
+    }
 }
 
-pub struct AggregatorActor<TState, TEvent> {
+pub struct AggregatorActor<TState, TEvent: Topic> {
     state: TState,
-    broadcast: Broadcast<TEvent>,
+    events: Subscription<TEvent>,
 }
 
-impl<TState, TEvent> std::fmt::Debug for AggregatorActor<TState, TEvent>
+impl<TState, TEvent: Topic> std::fmt::Debug for AggregatorActor<TState, TEvent>
 where
     TState: std::fmt::Debug,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AggregatorActor")
             .field("state", &self.state)
-            .field("broadcast", &"...")
+            .field("events", &"...")
             .finish()
     }
 }
@@ -70,7 +143,7 @@ impl<TState, TEvent> Actor<AggregatorRequests<TState>, AggregatorResponses>
     for AggregatorActor<TState, TEvent>
 where
     TState: 'static + Send + Clone + Aggregator<Event = TEvent> + std::fmt::Debug,
-    TEvent: 'static + Send + Clone + std::fmt::Debug,
+    TEvent: 'static + Send + Clone + Topic + std::fmt::Debug,
 {
     type Client = AggregatorClient<TState>;
 
@@ -85,12 +158,23 @@ where
         &mut self,
         request: AggregatorRequests<TState>,
         callback: Sender<AggregatorResponses>,
+        deadline: Deadline,
     ) {
         let response = match request {
-            AggregatorRequests::Call(f) => {
-                let state = &self.state;
-                f(state);
-                AggregatorResponses::Finished
+            // Expired calls are skipped, lifecycle requests never are
+            AggregatorRequests::Call(f) => match deadline.check() {
+                Ok(()) => {
+                    let state = &self.state;
+                    f(state);
+                    AggregatorResponses::Finished
+                }
+                Err(err) => AggregatorResponses::Error(err),
+            },
+            AggregatorRequests::Lifecycle(lifecycle) => {
+                while let Some(published) = self.events.try_recv() {
+                    self.state.handle(published.event);
+                }
+                lifecycle.into()
             }
         };
         let _ = callback.send_async(response).await;
@@ -103,11 +187,17 @@ where
     ) {
         loop {
             tokio::select! {
+                // aggregate events
+                published = self.events.recv_async() => self.state.handle(published.event),
                 // handle requests
                 command = receiver.recv_async() => {
                     match command {
-                        Ok(CommandEnvelope { payload, callback }) => {
-                            self.handle_request(payload, callback).await;
+                        Ok(CommandEnvelope { payload, callback, deadline }) => {
+                            let stop = payload.lifecycle() == Some(Lifecycle::Stop);
+                            self.handle_request(payload, callback, deadline).await;
+                            if stop {
+                                break;
+                            }
                         }
                         Err(err) => {
                             tracing::error!("{}", err);
@@ -115,10 +205,6 @@ where
                         }
                     }
                 },
-                // aggregate events
-                event = self.broadcast.recv_async() => {
-                    self.state.handle(event.unwrap());
-                },
                 else => break,
             };
         }
@@ -126,11 +212,19 @@ where
     }
 }
 
-impl<TState: Aggregator + Default, TEvent> AggregatorActor<TState, TEvent> {
+impl<TState: Aggregator + Default, TEvent: Clone + Topic> AggregatorActor<TState, TEvent> {
+    // Aggregates the events broadcast from now on.
     pub fn new(broadcast: Broadcast<TEvent>) -> Self {
+        Self::with_state(TState::default(), broadcast)
+    }
+}
+
+impl<TState, TEvent: Clone + Topic> AggregatorActor<TState, TEvent> {
+    // Continues aggregating from a previous state.
+    pub fn with_state(state: TState, broadcast: Broadcast<TEvent>) -> Self {
         Self {
-            state: TState::default(),
-            broadcast,
+            state,
+            events: broadcast.subscribe(Filter::all()),
         }
     }
 }
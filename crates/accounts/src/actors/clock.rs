@@ -0,0 +1,131 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// Where actors get their time from.
+// The sleep is registered when `sleep` is called, not when it is first polled.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+// Tokio timers. Also follows tokio paused time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+// Time only moves when `advance` is called.
+#[derive(Clone, Debug)]
+pub struct VirtualClock {
+    start: Instant,
+    state: Arc<Mutex<VirtualClockState>>,
+}
+
+#[derive(Debug, Default)]
+struct VirtualClockState {
+    now: Duration,
+    sequence: u64,
+    sleepers: BTreeMap<(Duration, u64), tokio::sync::oneshot::Sender<()>>,
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            state: Default::default(),
+        }
+    }
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Time elapsed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().unwrap().now
+    }
+
+    pub fn pending_sleeps(&self) -> usize {
+        self.state.lock().unwrap().sleepers.len()
+    }
+
+    // Wakes, in deadline order, everything sleeping until `now + duration`.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now += duration;
+
+        let now = state.now;
+        let not_due = state.sleepers.split_off(&(now, u64::MAX));
+        let due = std::mem::replace(&mut state.sleepers, not_due);
+        for (_, sleeper) in due {
+            let _ = sleeper.send(());
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        if duration.is_zero() {
+            return Box::pin(std::future::ready(()));
+        }
+
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let mut state = self.state.lock().unwrap();
+        state.sequence += 1;
+        let key = (state.now + duration, state.sequence);
+        state.sleepers.insert(key, sender);
+
+        Box::pin(async move {
+            let _ = receiver.await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Clock, VirtualClock};
+
+    #[tokio::test]
+    pub async fn ok_virtual_clock_only_wakes_due_sleeps() {
+        let clock = VirtualClock::new();
+        let short = tokio::task::spawn(clock.sleep(Duration::from_millis(10)));
+        let long = tokio::task::spawn(clock.sleep(Duration::from_millis(100)));
+        assert_eq!(clock.pending_sleeps(), 2);
+
+        clock.advance(Duration::from_millis(50));
+        short.await.unwrap();
+        assert_eq!(clock.pending_sleeps(), 1);
+        assert!(!long.is_finished());
+
+        clock.advance(Duration::from_millis(50));
+        long.await.unwrap();
+        assert_eq!(clock.elapsed(), Duration::from_millis(100));
+    }
+}
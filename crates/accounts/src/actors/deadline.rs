@@ -0,0 +1,184 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{sync::Notify, time::Instant};
+
+use super::{clock::Clock, ActorError};
+
+// Cancels every call it was given to. Clones cancel the same calls.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<CancellationState>);
+
+#[derive(Debug, Default)]
+struct CancellationState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub async fn cancelled(&self) {
+        let notified = self.0.notify.notified();
+        tokio::pin!(notified);
+        // Registered before checking, so a cancel in between is not missed
+        notified.as_mut().enable();
+        if !self.is_cancelled() {
+            notified.await;
+        }
+    }
+}
+
+// Until when a call is worth handling. Actors answer expired calls with
+// `ActorError::Timeout` or `ActorError::Cancelled` instead of handling them,
+// and pass the deadline on to the actors they call.
+// The deadline keeps the clock it was set with, so it expires the same
+// way everywhere it is passed on to.
+#[derive(Clone, Debug, Default)]
+pub struct Deadline {
+    at: Option<(Instant, Arc<dyn Clock>)>,
+    cancellation: Option<CancellationToken>,
+}
+
+impl Deadline {
+    // Calls wait as long as it takes
+    pub fn never() -> Self {
+        Self::default()
+    }
+
+    pub fn at(clock: Arc<dyn Clock>, at: Instant) -> Self {
+        Self {
+            at: Some((at, clock)),
+            cancellation: None,
+        }
+    }
+
+    pub fn after(clock: Arc<dyn Clock>, duration: Duration) -> Self {
+        let at = clock.now() + duration;
+        Self::at(clock, at)
+    }
+
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    // None when there is no deadline. Zero when it already expired.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.at
+            .as_ref()
+            .map(|(at, clock)| at.saturating_duration_since(clock.now()))
+    }
+
+    pub fn check(&self) -> Result<(), ActorError> {
+        if self.cancellation.as_ref().is_some_and(|x| x.is_cancelled()) {
+            return Err(ActorError::Cancelled);
+        }
+        if self.remaining().is_some_and(|x| x.is_zero()) {
+            return Err(ActorError::Timeout);
+        }
+        Ok(())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.check().is_err()
+    }
+
+    // Resolves when the deadline expires, with the reason.
+    pub async fn expired(&self) -> ActorError {
+        let timeout = async {
+            match (&self.at, self.remaining()) {
+                (Some((_, clock)), Some(remaining)) => clock.sleep(remaining).await,
+                _ => std::future::pending().await,
+            }
+        };
+        let cancelled = async {
+            match &self.cancellation {
+                Some(cancellation) => cancellation.cancelled().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = cancelled => ActorError::Cancelled,
+            _ = timeout => ActorError::Timeout,
+        }
+    }
+
+    // Stops waiting for `call` when the deadline expires.
+    pub async fn enforce<T>(
+        &self,
+        call: impl Future<Output = Result<T, ActorError>>,
+    ) -> Result<T, ActorError> {
+        self.check()?;
+        tokio::select! {
+            result = call => result,
+            err = self.expired() => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::actors::{
+        clock::{TokioClock, VirtualClock},
+        ActorError,
+    };
+
+    use super::{CancellationToken, Deadline};
+
+    #[tokio::test]
+    async fn err_calls_stop_waiting_when_expired() {
+        let never = std::future::pending::<Result<(), ActorError>>;
+
+        let deadline = Deadline::after(Arc::new(TokioClock), Duration::from_millis(10));
+        assert!(!deadline.is_expired());
+        let result = deadline.enforce(never()).await;
+        assert!(matches!(result, Err(ActorError::Timeout)));
+        assert_eq!(deadline.remaining(), Some(Duration::ZERO));
+
+        // Only expires when its own clock says so
+        let clock = VirtualClock::new();
+        let deadline = Deadline::after(Arc::new(clock.clone()), Duration::from_millis(10));
+        let waiting = deadline.enforce(never());
+        tokio::pin!(waiting);
+        let polled = tokio::time::timeout(Duration::ZERO, &mut waiting).await;
+        assert!(polled.is_err());
+        clock.advance(Duration::from_millis(5));
+        assert!(!deadline.is_expired());
+        clock.advance(Duration::from_millis(5));
+        assert!(deadline.is_expired());
+        assert!(matches!(waiting.await, Err(ActorError::Timeout)));
+
+        let cancellation = CancellationToken::new();
+        let deadline = Deadline::never().with_cancellation(cancellation.clone());
+        let waiting = deadline.enforce(never());
+        tokio::pin!(waiting);
+        // Polled once, so it is already waiting when cancelled
+        let polled = tokio::time::timeout(Duration::ZERO, &mut waiting).await;
+        assert!(polled.is_err());
+        cancellation.cancel();
+        assert!(matches!(waiting.await, Err(ActorError::Cancelled)));
+
+        let deadline = Deadline::never();
+        assert!(matches!(deadline.enforce(async { Ok(1) }).await, Ok(1)));
+    }
+}
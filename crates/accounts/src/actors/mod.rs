@@ -2,10 +2,86 @@ pub mod account;
 pub mod account_manager;
 pub mod account_shard;
 pub mod aggregators;
+pub mod clock;
+pub mod deadline;
+pub mod remote;
+pub mod supervisor;
+#[cfg(test)]
+mod test_utils;
+
+use crate::{domain::account::AccountErrors, store::StoreError};
+
+use self::deadline::Deadline;
+
+// Why a request failed, whatever actor it went through.
+#[derive(Clone, Debug)]
+pub enum ActorError {
+    // The actor was gone before the request was sent
+    MailboxClosed,
+    // The actor stopped without responding
+    ActorStopped,
+    Timeout,
+    // The caller cancelled the request
+    Cancelled,
+    // No manager can take requests for the account
+    ShardUnavailable,
+    Domain(AccountErrors),
+    // Accounts could not be loaded or saved
+    Store(String),
+    // The actor refuses requests like this one
+    InvalidRequest(String),
+    // A response that does not belong to the request
+    UnexpectedResponse(String),
+    // The actor gave up after its children kept crashing.
+    // Whoever sent the request decides what to do with it.
+    Escalated(String),
+}
+
+// Lifecycle messages every actor understands. Both are answered once the
+// actor finished everything pending, and everything it owns did the same.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lifecycle {
+    Drain,
+    // Drains and stops. Requests sent afterwards fail.
+    Stop,
+}
+
+// Requests that can carry a lifecycle message.
+pub trait LifecycleRequest {
+    fn lifecycle(&self) -> Option<Lifecycle>;
+}
+
+impl From<AccountErrors> for ActorError {
+    fn from(err: AccountErrors) -> Self {
+        ActorError::Domain(err)
+    }
+}
+
+impl From<StoreError> for ActorError {
+    fn from(err: StoreError) -> Self {
+        ActorError::Store(format!("{:?}", err))
+    }
+}
 
 pub struct CommandEnvelope<TRequest: std::fmt::Debug, TResponse> {
     payload: TRequest,
     callback: flume::Sender<TResponse>,
+    deadline: Deadline,
+}
+
+impl<TRequest: std::fmt::Debug, TResponse> CommandEnvelope<TRequest, TResponse> {
+    pub fn new(payload: TRequest, callback: flume::Sender<TResponse>) -> Self {
+        Self {
+            payload,
+            callback,
+            deadline: Deadline::never(),
+        }
+    }
+
+    pub fn with_deadline(mut self, deadline: Deadline) -> Self {
+        self.deadline = deadline;
+        self
+    }
 }
 
 impl<TRequest: std::fmt::Debug, TResponse> std::fmt::Debug
@@ -15,87 +91,407 @@ impl<TRequest: std::fmt::Debug, TResponse> std::fmt::Debug
         f.debug_struct("CommandEnvelope")
             .field("payload", &self.payload)
             .field("callback", &"...")
+            .field("deadline", &self.deadline)
             .finish()
     }
 }
 
+/// Generates the messages of an actor and the methods of its client.
+///
+/// Each method becomes a `*Request` variant and, unless it is fire and forget
+/// (no return type), a `*Response` variant. Methods with more than one argument
+/// carry them as a tuple and need named arguments.
+///
+/// Every method also has a `send_*_before_async` version taking a [`Deadline`].
+/// Calls stop waiting when their deadline expires, and actors skip requests
+/// that expired while waiting in their mailbox.
+///
+/// Clients also get `send_drain_async` and `send_stop_async`, see [`Lifecycle`].
+///
+/// ```
+/// use std::{sync::Arc, time::Duration};
+///
+/// use accounts::{
+///     actors::{clock::TokioClock, deadline::Deadline, ActorError},
+///     gen_client_extension_methods,
+/// };
+///
+/// #[derive(Clone, Debug)]
+/// pub struct Reset;
+///
+/// #[derive(Clone)]
+/// pub struct CounterClient(flume::Sender<Envelope>);
+///
+/// gen_client_extension_methods! {
+///     impl Counter for CounterClient {
+///         /// Adds to the counter and returns its new value
+///         fn add(_: u64) -> u64;
+///         fn add_both(first: u32, second: u32) -> (u64, u64);
+///         fn reset(_: Reset);
+///     }
+/// }
+///
+/// async fn use_counter(counter: CounterClient) {
+///     let _: Result<u64, ActorError> = counter.send_add_async(1u64).await;
+///     let _: Result<(u64, u64), ActorError> = counter.send_add_both_async(1, 2).await;
+///     let _: Result<(), ActorError> = counter.send_reset_async(Reset).await;
+///
+///     let deadline = Deadline::after(Arc::new(TokioClock), Duration::from_millis(100));
+///     let _: Result<u64, ActorError> = counter.send_add_before_async(1u64, deadline.clone()).await;
+///     let _: Result<(u64, u64), ActorError> = counter.send_add_both_before_async(1, 2, deadline).await;
+///
+///     let _: Result<(), ActorError> = counter.send_stop_async().await;
+/// }
+/// ```
+///
+/// Messages cross threads, so payloads that are not `Send` are rejected:
+///
+/// ```compile_fail,E0277
+/// use accounts::gen_client_extension_methods;
+///
+/// #[derive(Clone)]
+/// pub struct CounterClient(flume::Sender<Envelope>);
+///
+/// gen_client_extension_methods! {
+///     impl Counter for CounterClient {
+///         fn add(_: std::rc::Rc<u64>) -> u64;
+///     }
+/// }
+/// ```
+///
+/// ```compile_fail,E0277
+/// use accounts::gen_client_extension_methods;
+///
+/// #[derive(Clone)]
+/// pub struct CounterClient(flume::Sender<Envelope>);
+///
+/// gen_client_extension_methods! {
+///     impl Counter for CounterClient {
+///         fn get(_: u64) -> std::rc::Rc<u64>;
+///     }
+/// }
+/// ```
 #[macro_export]
 macro_rules! gen_client_extension_methods {
+    // Messages also go on the wire, so the actor can be served to other processes.
+    // Every request and response type must implement `Wire`.
+    (remote impl $trait_name:tt for $client_name:tt {
+        $( $(#[$meta:meta])* fn $fn_name:ident ( $($arg_name:tt: $arg_ty:ty),* ) $(-> $return_ty:ty)?; )*
+    }) => {
+        $crate::gen_client_extension_methods! {
+            impl $trait_name for $client_name {
+                $( $(#[$meta])* fn $fn_name ( $($arg_name: $arg_ty),* ) $(-> $return_ty)?; )*
+            }
+        }
+
+        paste::paste! {
+            impl $crate::actors::remote::wire::Wire for [<$trait_name:camel Requests>] {
+                #[allow(unused_assignments)]
+                fn encode(&self, bytes: &mut Vec<u8>) {
+                    let mut tag = 0u8;
+                    $(
+                        if let [<$trait_name:camel Requests>]::[<$fn_name:camel Request>](x) = self {
+                            bytes.push(tag);
+                            $crate::actors::remote::wire::Wire::encode(x, bytes);
+                            return;
+                        }
+                        tag += 1;
+                    )*
+                    if let [<$trait_name:camel Requests>]::Lifecycle(x) = self {
+                        bytes.push(tag);
+                        $crate::actors::remote::wire::Wire::encode(x, bytes);
+                    }
+                }
+
+                #[allow(unused_assignments)]
+                fn decode(reader: &mut $crate::store::codec::Reader) -> Result<Self, $crate::store::StoreError> {
+                    let tag = reader.u8()?;
+                    let mut i = 0u8;
+                    $(
+                        if tag == i {
+                            let x: $crate::gen_client_extension_methods!(@payload $($arg_ty),*) =
+                                $crate::actors::remote::wire::Wire::decode(reader)?;
+                            return Ok([<$trait_name:camel Requests>]::[<$fn_name:camel Request>](x));
+                        }
+                        i += 1;
+                    )*
+                    if tag == i {
+                        let x = $crate::actors::remote::wire::Wire::decode(reader)?;
+                        return Ok([<$trait_name:camel Requests>]::Lifecycle(x));
+                    }
+                    Err($crate::store::StoreError::Corrupted(format!("unknown request {}", tag)))
+                }
+            }
+
+            // Lifecycle messages and errors are the last tags
+            impl $crate::actors::remote::wire::Wire for [<$trait_name:camel Responses>] {
+                #[allow(unused_assignments)]
+                fn encode(&self, bytes: &mut Vec<u8>) {
+                    let mut tag = 0u8;
+                    $($(
+                        if let [<$trait_name:camel Responses>]::[<$fn_name:camel Response>](x) = self {
+                            bytes.push(tag);
+                            <$return_ty as $crate::actors::remote::wire::Wire>::encode(x, bytes);
+                            return;
+                        }
+                        tag += 1;
+                    )?)*
+                    if let [<$trait_name:camel Responses>]::Lifecycle(x) = self {
+                        bytes.push(tag);
+                        $crate::actors::remote::wire::Wire::encode(x, bytes);
+                        return;
+                    }
+                    tag += 1;
+                    if let [<$trait_name:camel Responses>]::Error(err) = self {
+                        bytes.push(tag);
+                        $crate::actors::remote::wire::Wire::encode(err, bytes);
+                    }
+                }
+
+                #[allow(unused_assignments)]
+                fn decode(reader: &mut $crate::store::codec::Reader) -> Result<Self, $crate::store::StoreError> {
+                    let tag = reader.u8()?;
+                    let mut i = 0u8;
+                    $($(
+                        if tag == i {
+                            let x = <$return_ty as $crate::actors::remote::wire::Wire>::decode(reader)?;
+                            return Ok([<$trait_name:camel Responses>]::[<$fn_name:camel Response>](x));
+                        }
+                        i += 1;
+                    )?)*
+                    if tag == i {
+                        let x = $crate::actors::remote::wire::Wire::decode(reader)?;
+                        return Ok([<$trait_name:camel Responses>]::Lifecycle(x));
+                    }
+                    i += 1;
+                    if tag == i {
+                        return Ok([<$trait_name:camel Responses>]::Error(<$crate::actors::ActorError as $crate::actors::remote::wire::Wire>::decode(reader)?));
+                    }
+                    Err($crate::store::StoreError::Corrupted(format!("unknown response {}", tag)))
+                }
+            }
+
+            impl $client_name {
+                // Serves the actor behind this client to clients in other processes.
+                // Their requests are decoded with `context`.
+                pub async fn serve<C: $crate::actors::remote::wire::Context>(
+                    &self,
+                    listener: $crate::actors::remote::Listener,
+                    context: C,
+                ) -> std::io::Result<()> {
+                    $crate::actors::remote::serve(listener, self.0.clone(), context).await
+                }
+            }
+        }
+    };
     (impl $trait_name:tt for $client_name:tt {
-        $(fn $fn_name:tt ( $arg_name:tt: $arg_ty:ty ) -> $return_ty:ty; )*
+        $( $(#[$meta:meta])* fn $fn_name:ident ( $($arg_name:tt: $arg_ty:ty),* ) $(-> $return_ty:ty)?; )*
     }) => {
         paste::paste! {
             #[derive(Clone, Debug)]
             pub enum [<$trait_name:camel Requests>] {
-                $([<$fn_name:camel Request>] ($arg_ty),)*
+                $(
+                    $(#[$meta])*
+                    [<$fn_name:camel Request>] ($crate::gen_client_extension_methods!(@payload $($arg_ty),*)),
+                )*
+                Lifecycle($crate::actors::Lifecycle),
             }
 
-            unsafe impl Send for [<$trait_name:camel Requests>] {}
+            impl $crate::actors::LifecycleRequest for [<$trait_name:camel Requests>] {
+                fn lifecycle(&self) -> Option<$crate::actors::Lifecycle> {
+                    match self {
+                        [<$trait_name:camel Requests>]::Lifecycle(lifecycle) => Some(*lifecycle),
+                        #[allow(unreachable_patterns)]
+                        _ => None,
+                    }
+                }
+            }
 
             $(
-                impl From<$arg_ty> for [<$trait_name:camel Requests>] {
-                    fn from(item: $arg_ty) -> Self {
+                impl From<$crate::gen_client_extension_methods!(@payload $($arg_ty),*)> for [<$trait_name:camel Requests>] {
+                    fn from(item: $crate::gen_client_extension_methods!(@payload $($arg_ty),*)) -> Self {
                         [<$trait_name:camel Requests>]::[<$fn_name:camel Request>](item)
                     }
                 }
             )*
 
-
             #[derive(Clone, Debug)]
             pub enum [<$trait_name:camel Responses>] {
-                $([<$fn_name:camel Response>] ($return_ty),)*
-                Error(String)
+                $($([<$fn_name:camel Response>] ($return_ty),)?)*
+                // Answers a lifecycle message
+                Lifecycle($crate::actors::Lifecycle),
+                Error($crate::actors::ActorError)
             }
 
-            unsafe impl Send for [<$trait_name:camel Responses>] {}
+            impl From<$crate::actors::Lifecycle> for [<$trait_name:camel Responses>] {
+                fn from(item: $crate::actors::Lifecycle) -> Self {
+                    [<$trait_name:camel Responses>]::Lifecycle(item)
+                }
+            }
 
-            $(
+            $($(
                 impl From<$return_ty> for [<$trait_name:camel Responses>] {
                     fn from(item: $return_ty) -> Self {
                         [<$trait_name:camel Responses>]::[<$fn_name:camel Response>](item)
                     }
                 }
-            )*
+            )?)*
 
-            pub type Envelope = CommandEnvelope<[<$trait_name:camel Requests>], [<$trait_name:camel Responses>]>;
+            // Actors run on any thread, so their messages must be Send
+            const _: fn() = || {
+                fn assert_send<T: Send>() {}
+                assert_send::<[<$trait_name:camel Requests>]>();
+                assert_send::<[<$trait_name:camel Responses>]>();
+            };
+
+            pub type Envelope = $crate::actors::CommandEnvelope<[<$trait_name:camel Requests>], [<$trait_name:camel Responses>]>;
 
             impl $client_name {
+                pub async fn send_async(&self, payload: [<$trait_name:camel Requests>]) -> Result<[<$trait_name:camel Responses>], $crate::actors::ActorError> {
+                    self.send_before_async(payload, $crate::actors::deadline::Deadline::never()).await
+                }
+
                 #[tracing::instrument(skip(self))]
-                pub async fn send_async(&self, payload: [<$trait_name:camel Requests>]) -> Result<[<$trait_name:camel Responses>], String> {
+                pub async fn send_before_async(&self, payload: [<$trait_name:camel Requests>], deadline: $crate::actors::deadline::Deadline) -> Result<[<$trait_name:camel Responses>], $crate::actors::ActorError> {
+                    deadline.enforce(async {
+                        let receiver = self.enqueue_before_async(payload, deadline.clone()).await?;
+                        receiver.recv_async().await.map_err(|_| $crate::actors::ActorError::ActorStopped)
+                    }).await
+                }
+
+                // Only waits until the request is in the mailbox, so requests sent
+                // afterwards are handled after it. The response arrives in the receiver.
+                pub async fn enqueue_async(&self, payload: [<$trait_name:camel Requests>]) -> Result<flume::Receiver<[<$trait_name:camel Responses>]>, $crate::actors::ActorError> {
+                    self.enqueue_before_async(payload, $crate::actors::deadline::Deadline::never()).await
+                }
+
+                // Waits until everything sent before was accepted.
+                pub async fn send_drain_async(&self) -> Result<(), $crate::actors::ActorError> {
+                    self.send_lifecycle_async($crate::actors::Lifecycle::Drain).await
+                }
+
+                pub async fn send_stop_async(&self) -> Result<(), $crate::actors::ActorError> {
+                    self.send_lifecycle_async($crate::actors::Lifecycle::Stop).await
+                }
+
+                async fn send_lifecycle_async(&self, lifecycle: $crate::actors::Lifecycle) -> Result<(), $crate::actors::ActorError> {
+                    match self.send_async([<$trait_name:camel Requests>]::Lifecycle(lifecycle)).await? {
+                        [<$trait_name:camel Responses>]::Lifecycle(_) => Ok(()),
+                        [<$trait_name:camel Responses>]::Error(err) => Err(err),
+                        #[allow(unreachable_patterns)]
+                        response => Err($crate::actors::ActorError::UnexpectedResponse(format!("{:?}", response))),
+                    }
+                }
+
+                pub async fn enqueue_before_async(&self, payload: [<$trait_name:camel Requests>], deadline: $crate::actors::deadline::Deadline) -> Result<flume::Receiver<[<$trait_name:camel Responses>]>, $crate::actors::ActorError> {
                     let (callback, receiver) = flume::bounded(1);
-                    let envelope = Envelope {
-                        payload,
-                        callback
-                    };
-                    let _ = self.0.send_async(envelope).await;
-                    receiver.recv_async().await.map_err(|err| format!("{:?}", err))
+                    self.0
+                        .send_async(Envelope::new(payload, callback).with_deadline(deadline))
+                        .await
+                        .map_err(|_| $crate::actors::ActorError::MailboxClosed)?;
+                    Ok(receiver)
                 }
 
                 $(
-                    #[tracing::instrument(skip(self))]
-                    pub fn [<send_ $fn_name:snake _async>](&self, payload: impl Into<$arg_ty> + std::fmt::Debug) -> impl std::future::Future<Output = Result<$return_ty, String>> {
-                        async fn [<send_ $fn_name:snake _impl>](s: $client_name, payload: impl Into<$arg_ty> + std::fmt::Debug) -> Result<$return_ty, String> {
-                            let payload = payload.into();
-
-                            let (callback, receiver) = flume::bounded(1);
-                            let envelope = Envelope {
-                                payload: [<$trait_name:camel Requests>]::[<$fn_name:camel Request>](payload),
-                                callback
-                            };
-
-                            let _ = s.0.send_async(envelope).await;
-                            let response = receiver.recv_async().await;
-                            match response {
-                                Ok([<$trait_name:camel Responses>]::[<$fn_name:camel Response>](x)) => Ok(x),
-                                err => Err(format!("{:?}", err))
-                            }
-                        }
-                        [<send_ $fn_name:snake _impl>](self.clone(), payload)
+                    $crate::gen_client_extension_methods! {
+                        @method
+                        [<$trait_name:camel Requests>]::[<$fn_name:camel Request>],
+                        [<$trait_name:camel Responses>]::[<$fn_name:camel Response>],
+                        [<send_ $fn_name:snake _async>],
+                        [<send_ $fn_name:snake _before_async>],
+                        $(#[$meta])*
+                        ($($arg_name: $arg_ty),*) $(-> $return_ty)?
                     }
                 )*
             }
         }
     };
+
+    // What a request carries: its only argument, or all of them in a tuple
+    (@payload) => { () };
+    (@payload $arg_ty:ty) => { $arg_ty };
+    (@payload $($arg_ty:ty),+) => { ($($arg_ty),+) };
+
+    // One argument, anything that converts into it
+    (@method $requests:ident::$request:ident, $responses:ident::$response:ident, $send:ident, $send_before:ident,
+        $(#[$meta:meta])* ($arg_name:tt: $arg_ty:ty) -> $return_ty:ty) => {
+        $(#[$meta])*
+        pub fn $send(&self, payload: impl Into<$arg_ty> + std::fmt::Debug) -> impl std::future::Future<Output = Result<$return_ty, $crate::actors::ActorError>> {
+            self.$send_before(payload, $crate::actors::deadline::Deadline::never())
+        }
+
+        $(#[$meta])*
+        #[tracing::instrument(skip(self))]
+        pub fn $send_before(&self, payload: impl Into<$arg_ty> + std::fmt::Debug, deadline: $crate::actors::deadline::Deadline) -> impl std::future::Future<Output = Result<$return_ty, $crate::actors::ActorError>> {
+            let client = self.clone();
+            let payload = $requests::$request(payload.into());
+            async move {
+                match client.send_before_async(payload, deadline).await? {
+                    $responses::$response(x) => Ok(x),
+                    $responses::Error(err) => Err(err),
+                    #[allow(unreachable_patterns)]
+                    response => Err($crate::actors::ActorError::UnexpectedResponse(format!("{:?}", response))),
+                }
+            }
+        }
+    };
+    // Fire and forget. Only waits until the request is in the mailbox.
+    (@method $requests:ident::$request:ident, $responses:ident::$response:ident, $send:ident, $send_before:ident,
+        $(#[$meta:meta])* ($arg_name:tt: $arg_ty:ty)) => {
+        $(#[$meta])*
+        pub async fn $send(&self, payload: impl Into<$arg_ty> + std::fmt::Debug) -> Result<(), $crate::actors::ActorError> {
+            self.$send_before(payload, $crate::actors::deadline::Deadline::never()).await
+        }
+
+        $(#[$meta])*
+        #[tracing::instrument(skip(self))]
+        pub async fn $send_before(&self, payload: impl Into<$arg_ty> + std::fmt::Debug, deadline: $crate::actors::deadline::Deadline) -> Result<(), $crate::actors::ActorError> {
+            let (callback, _) = flume::bounded(1);
+            let envelope = $crate::actors::CommandEnvelope::new($requests::$request(payload.into()), callback);
+            deadline.enforce(async {
+                self.0.send_async(envelope.with_deadline(deadline.clone())).await.map_err(|_| $crate::actors::ActorError::MailboxClosed)
+            }).await
+        }
+    };
+    (@method $requests:ident::$request:ident, $responses:ident::$response:ident, $send:ident, $send_before:ident,
+        $(#[$meta:meta])* ($($arg_name:ident: $arg_ty:ty),*) -> $return_ty:ty) => {
+        $(#[$meta])*
+        pub fn $send(&self, $($arg_name: $arg_ty),*) -> impl std::future::Future<Output = Result<$return_ty, $crate::actors::ActorError>> {
+            self.$send_before($($arg_name,)* $crate::actors::deadline::Deadline::never())
+        }
+
+        $(#[$meta])*
+        #[tracing::instrument(skip(self))]
+        pub fn $send_before(&self, $($arg_name: $arg_ty,)* deadline: $crate::actors::deadline::Deadline) -> impl std::future::Future<Output = Result<$return_ty, $crate::actors::ActorError>> {
+            let client = self.clone();
+            let payload = $requests::$request(($($arg_name,)*));
+            async move {
+                match client.send_before_async(payload, deadline).await? {
+                    $responses::$response(x) => Ok(x),
+                    $responses::Error(err) => Err(err),
+                    #[allow(unreachable_patterns)]
+                    response => Err($crate::actors::ActorError::UnexpectedResponse(format!("{:?}", response))),
+                }
+            }
+        }
+    };
+    (@method $requests:ident::$request:ident, $responses:ident::$response:ident, $send:ident, $send_before:ident,
+        $(#[$meta:meta])* ($($arg_name:ident: $arg_ty:ty),*)) => {
+        $(#[$meta])*
+        pub async fn $send(&self, $($arg_name: $arg_ty),*) -> Result<(), $crate::actors::ActorError> {
+            self.$send_before($($arg_name,)* $crate::actors::deadline::Deadline::never()).await
+        }
+
+        $(#[$meta])*
+        #[tracing::instrument(skip(self))]
+        pub async fn $send_before(&self, $($arg_name: $arg_ty,)* deadline: $crate::actors::deadline::Deadline) -> Result<(), $crate::actors::ActorError> {
+            let (callback, _) = flume::bounded(1);
+            let envelope = $crate::actors::CommandEnvelope::new($requests::$request(($($arg_name,)*)), callback);
+            deadline.enforce(async {
+                self.0.send_async(envelope.with_deadline(deadline.clone())).await.map_err(|_| $crate::actors::ActorError::MailboxClosed)
+            }).await
+        }
+    };
 }
 
 #[cfg(test)]
@@ -130,7 +526,7 @@ where
     type Output = tokio::task::JoinHandle<TOutput>;
 
     fn spawn(self) -> Self::Output {
-        tokio::task::spawn(async move { self.await })
+        tokio::task::spawn(self)
     }
 }
 
@@ -138,8 +534,8 @@ where
 pub trait Actor<TRequest, TResponse>
 where
     Self: 'static + Send + Sized,
-    TRequest: 'static + Send + std::fmt::Debug,
-    TResponse: 'static + Send + std::fmt::Debug,
+    TRequest: 'static + Send + std::fmt::Debug + LifecycleRequest,
+    TResponse: 'static + Send + std::fmt::Debug + From<Lifecycle>,
 {
     type Client;
 
@@ -148,16 +544,36 @@ where
         sender: flume::Sender<CommandEnvelope<TRequest, TResponse>>,
     ) -> Self::Client;
 
-    fn spawn(mut self) -> Self::Client {
-        let (sender, receiver) = flume::unbounded();
+    fn spawn(self) -> Self::Client {
+        self.spawn_linked().0
+    }
+
+    // Also returns the task of the actor, so a supervisor can watch it.
+    fn spawn_linked(mut self) -> (Self::Client, tokio::task::JoinHandle<()>) {
+        let (sender, receiver) = match self.mailbox_capacity() {
+            Some(capacity) => flume::bounded(capacity),
+            None => flume::unbounded(),
+        };
 
         let client = self.new_client(sender.clone());
-        tokio::task::spawn(self.handle(sender, receiver));
-        client
+        let task = tokio::task::spawn(self.handle(sender, receiver));
+        (client, task)
     }
 
     fn set_sender(&mut self, _: flume::Sender<CommandEnvelope<TRequest, TResponse>>) {}
 
+    // Requests waiting in the mailbox. When full, senders wait,
+    // so slow actors slow down whoever sends them requests.
+    // Unbounded when None.
+    fn mailbox_capacity(&self) -> Option<usize> {
+        None
+    }
+
+    // Checked after each request. Stopped actors drop their mailbox.
+    fn is_stopped(&self) -> bool {
+        false
+    }
+
     async fn handle(
         mut self,
         sender: flume::Sender<CommandEnvelope<TRequest, TResponse>>,
@@ -166,10 +582,26 @@ where
         self.set_sender(sender.clone());
 
         loop {
-            let msg = receiver.recv_async().await;
+            let msg = self.next_request(&receiver).await;
             match msg {
-                Ok(CommandEnvelope { payload, callback }) => {
-                    self.handle_request(payload, callback).await;
+                Ok(CommandEnvelope {
+                    payload,
+                    callback,
+                    deadline,
+                }) => {
+                    if let Some(lifecycle) = payload.lifecycle() {
+                        self.handle_lifecycle(lifecycle).await;
+                        let _ = callback.send_async(lifecycle.into()).await;
+                        if lifecycle == Lifecycle::Stop {
+                            break;
+                        }
+                        continue;
+                    }
+
+                    self.handle_request(payload, callback, deadline).await;
+                    if self.is_stopped() {
+                        break;
+                    }
                 }
                 Err(err) => {
                     tracing::error!("{}", err);
@@ -180,5 +612,25 @@ where
         drop(sender);
     }
 
-    async fn handle_request(&mut self, request: TRequest, callback: flume::Sender<TResponse>);
+    // Actors with private channels, that nobody else can send to,
+    // handle their messages here while waiting for the next request.
+    async fn next_request(
+        &mut self,
+        receiver: &flume::Receiver<CommandEnvelope<TRequest, TResponse>>,
+    ) -> Result<CommandEnvelope<TRequest, TResponse>, flume::RecvError> {
+        receiver.recv_async().await
+    }
+
+    // Finishes everything pending before a lifecycle message is answered,
+    // and passes it on to the actors this one owns.
+    async fn handle_lifecycle(&mut self, _: Lifecycle) {}
+
+    // Requests that expired while waiting in the mailbox should be
+    // answered with the error from `deadline.check()`.
+    async fn handle_request(
+        &mut self,
+        request: TRequest,
+        callback: flume::Sender<TResponse>,
+        deadline: Deadline,
+    );
 }
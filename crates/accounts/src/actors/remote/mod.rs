@@ -0,0 +1,477 @@
+pub mod wire;
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+
+use self::wire::{Context, Wire};
+
+use super::{clock::TokioClock, deadline::Deadline, CommandEnvelope};
+
+// Clients of actors in other processes. Their requests are framed and sent over a
+// socket; a server forwards them to the actor and sends its responses back.
+//
+// A connection starts with:
+//
+//  magic       [u8; 4] ("ACTR")
+//  version     u8
+//
+// Then both sides exchange frames. Requests and their responses share an id,
+// because responses come back in the order they are ready.
+//
+//  len         u32     (of what follows)
+//  id          u64
+//  payload     [u8; len - 8]
+//
+// Requests start with the microseconds left until their deadline,
+// u64::MAX when they have none. Cancellations do not cross processes.
+//
+// Responses start with a status: 0 = the response follows,
+// 1 = the actor dropped the request without responding.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+const MAGIC: &[u8; 4] = b"ACTR";
+pub const VERSION: u8 = 6;
+const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+
+const RESPONSE: u8 = 0;
+const DROPPED: u8 = 1;
+
+type ReadHalf = Box<dyn AsyncRead + Unpin + Send>;
+type WriteHalf = Box<dyn AsyncWrite + Unpin + Send>;
+
+impl Address {
+    async fn connect(&self) -> std::io::Result<(ReadHalf, WriteHalf)> {
+        match self {
+            Address::Tcp(addr) => {
+                let stream = TcpStream::connect(addr).await?;
+                stream.set_nodelay(true)?;
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+            Address::Unix(path) => {
+                let (reader, writer) = UnixStream::connect(path).await?.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+        }
+    }
+}
+
+impl Listener {
+    pub async fn bind(address: &Address) -> std::io::Result<Self> {
+        match address {
+            Address::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            Address::Unix(path) => Ok(Listener::Unix(UnixListener::bind(path)?)),
+        }
+    }
+
+    // Where clients connect to. Useful when binding TCP port 0.
+    pub fn local_address(&self) -> std::io::Result<Address> {
+        match self {
+            Listener::Tcp(listener) => Ok(Address::Tcp(listener.local_addr()?)),
+            Listener::Unix(listener) => match listener.local_addr()?.as_pathname() {
+                Some(path) => Ok(Address::Unix(path.to_path_buf())),
+                None => Err(std::io::Error::other("unnamed unix socket")),
+            },
+        }
+    }
+
+    async fn accept(&self) -> std::io::Result<(ReadHalf, WriteHalf)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept().await?;
+                stream.set_nodelay(true)?;
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+        }
+    }
+}
+
+// Requests sent to the returned sender go to the actor served at `address`.
+// If the connection is lost, pending and later requests fail as if the actor had stopped.
+// Responses are decoded with `context`.
+pub async fn connect<TRequest, TResponse, C>(
+    address: &Address,
+    context: C,
+) -> std::io::Result<flume::Sender<CommandEnvelope<TRequest, TResponse>>>
+where
+    TRequest: 'static + Wire + Send + std::fmt::Debug,
+    TResponse: 'static + Wire + Send,
+    C: Context,
+{
+    let (mut reader, mut writer) = address.connect().await?;
+    writer.write_all(MAGIC).await?;
+    writer.write_all(&[VERSION]).await?;
+
+    let (sender, receiver) = flume::unbounded::<CommandEnvelope<TRequest, TResponse>>();
+    // None after the connection is lost
+    let callbacks = Arc::new(Mutex::new(Some(HashMap::new())));
+
+    tokio::task::spawn({
+        let callbacks = callbacks.clone();
+        async move {
+            let mut id = 0u64;
+            while let Ok(CommandEnvelope {
+                payload,
+                callback,
+                deadline,
+            }) = receiver.recv_async().await
+            {
+                id += 1;
+                match callbacks.lock().unwrap().as_mut() {
+                    Some(callbacks) => callbacks.insert(id, callback),
+                    None => break,
+                };
+                let remaining = deadline
+                    .remaining()
+                    .map_or(u64::MAX, |x| x.as_micros().min(u64::MAX as u128 - 1) as u64);
+                let mut bytes = remaining.to_bytes();
+                payload.encode(&mut bytes);
+                if let Err(err) = write_frame(&mut writer, id, &bytes).await {
+                    tracing::warn!("Connection lost: {:?}", err);
+                    break;
+                }
+            }
+        }
+    });
+
+    tokio::task::spawn(async move {
+        loop {
+            let (id, payload) = match read_frame(&mut reader).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(err) => {
+                    tracing::warn!("Connection lost: {:?}", err);
+                    break;
+                }
+            };
+
+            let callback = callbacks
+                .lock()
+                .unwrap()
+                .as_mut()
+                .and_then(|callbacks| callbacks.remove(&id));
+            let callback = match callback {
+                Some(callback) => callback,
+                None => {
+                    tracing::warn!("Response to unknown request {}", id);
+                    continue;
+                }
+            };
+
+            match payload.split_first() {
+                Some((&RESPONSE, response)) => {
+                    match TResponse::from_bytes_with(response, &context) {
+                        Ok(response) => {
+                            let _ = callback.send_async(response).await;
+                        }
+                        Err(err) => {
+                            tracing::warn!("Invalid response: {:?}", err);
+                            break;
+                        }
+                    }
+                }
+                // Dropping the callback fails the request
+                Some((&DROPPED, _)) => {}
+                _ => {
+                    tracing::warn!("Invalid response to request {}", id);
+                    break;
+                }
+            }
+        }
+        // Dropping the callbacks fails their requests
+        callbacks.lock().unwrap().take();
+    });
+
+    Ok(sender)
+}
+
+// Forwards requests from every connection to the actor behind `sender`.
+// Requests of each connection reach the actor in the order they were sent.
+// Requests are decoded with `context`.
+pub async fn serve<TRequest, TResponse, C>(
+    listener: Listener,
+    sender: flume::Sender<CommandEnvelope<TRequest, TResponse>>,
+    context: C,
+) -> std::io::Result<()>
+where
+    TRequest: 'static + Wire + Send + std::fmt::Debug,
+    TResponse: 'static + Wire + Send,
+    C: Context,
+{
+    loop {
+        match listener.accept().await {
+            Ok((reader, writer)) => {
+                let connection = handle_connection(reader, writer, sender.clone(), context.clone());
+                tokio::task::spawn(connection);
+            }
+            // Like running out of file descriptors, or a client giving up
+            // before being accepted. Only that connection is lost.
+            Err(err) => {
+                tracing::warn!("Connection not accepted: {:?}", err);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+async fn handle_connection<TRequest, TResponse, C>(
+    mut reader: ReadHalf,
+    mut writer: WriteHalf,
+    actor: flume::Sender<CommandEnvelope<TRequest, TResponse>>,
+    context: C,
+) where
+    TRequest: 'static + Wire + Send + std::fmt::Debug,
+    TResponse: 'static + Wire + Send,
+    C: Context,
+{
+    let mut handshake = [0u8; 5];
+    if let Err(err) = reader.read_exact(&mut handshake).await {
+        tracing::warn!("Connection lost: {:?}", err);
+        return;
+    }
+    if &handshake[..4] != MAGIC || handshake[4] != VERSION {
+        tracing::warn!("Unsupported client: {:?}", handshake);
+        return;
+    }
+
+    let (responses, responses_receiver) = flume::unbounded::<(u64, Vec<u8>)>();
+    tokio::task::spawn(async move {
+        while let Ok((id, payload)) = responses_receiver.recv_async().await {
+            if let Err(err) = write_frame(&mut writer, id, &payload).await {
+                tracing::warn!("Connection lost: {:?}", err);
+                break;
+            }
+        }
+    });
+
+    loop {
+        let (id, payload) = match read_frame(&mut reader).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(err) => {
+                tracing::warn!("Connection lost: {:?}", err);
+                break;
+            }
+        };
+        let request = match <(u64, TRequest)>::from_bytes_with(&payload, &context) {
+            Ok((u64::MAX, request)) => (request, Deadline::never()),
+            Ok((remaining, request)) => {
+                let remaining = Duration::from_micros(remaining);
+                (request, Deadline::after(Arc::new(TokioClock), remaining))
+            }
+            Err(err) => {
+                tracing::warn!("Invalid request: {:?}", err);
+                break;
+            }
+        };
+
+        let (request, deadline) = request;
+        let (callback, receiver) = flume::bounded(1);
+        let envelope = CommandEnvelope::new(request, callback).with_deadline(deadline);
+        if actor.send_async(envelope).await.is_err() {
+            break;
+        }
+
+        let responses = responses.clone();
+        tokio::task::spawn(async move {
+            let payload = match receiver.recv_async().await {
+                Ok(response) => {
+                    let mut payload = vec![RESPONSE];
+                    response.encode(&mut payload);
+                    payload
+                }
+                Err(_) => vec![DROPPED],
+            };
+            let _ = responses.send_async((id, payload)).await;
+        });
+    }
+}
+
+async fn write_frame(writer: &mut WriteHalf, id: u64, payload: &[u8]) -> std::io::Result<()> {
+    let mut frame = Vec::with_capacity(12 + payload.len());
+    frame.extend_from_slice(&(8 + payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&id.to_le_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await
+}
+
+// None when the other side closed the connection.
+async fn read_frame(reader: &mut ReadHalf) -> std::io::Result<Option<(u64, Vec<u8>)>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_le_bytes(len);
+    if !(8..=MAX_FRAME_LEN).contains(&len) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid frame length {}", len),
+        ));
+    }
+
+    let mut frame = vec![0u8; len as usize];
+    reader.read_exact(&mut frame).await?;
+    let id = u64::from_le_bytes(frame[..8].try_into().unwrap());
+    frame.drain(..8);
+    Ok(Some((id, frame)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        actors::{
+            account::{
+                AccountActorConfig, AccountResponses, DepositRequest, DepositResponse,
+                WithdrawRequest, WithdrawResponse,
+            },
+            account_manager::{
+                AccountManagerActor, AccountManagerClient, SnapshotAccountsRequest,
+                SnapshotAccountsResponse,
+            },
+            account_shard::AccountShardActor,
+            init_log, Actor,
+        },
+        broadcast::Broadcast,
+        domain::{
+            account::AccountErrors,
+            dispute_policy::{DepositsAndWithdrawals, DepositsOnly, DisputePolicy},
+            money::Currency::*,
+        },
+    };
+
+    use super::{Address, Listener};
+
+    // The manager lives here, its client talks to it through the socket.
+    async fn serve_manager(address: Address) -> AccountManagerClient {
+        let listener = Listener::bind(&address).await.unwrap();
+        let address = listener.local_address().unwrap();
+        let manager =
+            AccountManagerActor::new(0, Broadcast::new(), AccountActorConfig::default()).spawn();
+        let policy: Arc<dyn DisputePolicy> = Arc::new(DepositsOnly);
+        tokio::task::spawn(async move { manager.serve(listener, policy).await });
+
+        // Accounts this side gets can also be disputed when withdrawn
+        AccountManagerClient::connect(&address, 0, Arc::new(DepositsAndWithdrawals))
+            .await
+            .unwrap()
+    }
+
+    async fn ok_requests_cross_processes(address: Address) {
+        let manager = serve_manager(address).await;
+        let shard = AccountShardActor::new(vec![manager.clone()]).spawn();
+
+        let response = shard
+            .send_account_async(DepositRequest {
+                account_id: 1,
+                transaction_id: 1,
+                amount: 2.5 * Bitcoin,
+            })
+            .await;
+        assert!(matches!(
+            response,
+            Ok(AccountResponses::DepositResponse(DepositResponse::Ok))
+        ));
+
+        let response = manager
+            .send_account_async(WithdrawRequest {
+                account_id: 1,
+                transaction_id: 2,
+                amount: 3 * Bitcoin,
+            })
+            .await;
+        assert!(matches!(
+            response,
+            Ok(AccountResponses::WithdrawResponse(WithdrawResponse::Error(
+                AccountErrors::NegativeAmount
+            )))
+        ));
+
+        match manager
+            .send_snapshot_accounts_async(SnapshotAccountsRequest)
+            .await
+        {
+            Ok(SnapshotAccountsResponse::Ok(mut accounts)) => {
+                assert_eq!(accounts.len(), 1);
+                assert_eq!(accounts[0].total(), 2.5 * Bitcoin);
+                accounts[0].withdraw(3, 1 * Bitcoin).unwrap();
+                accounts[0].dispute(3).unwrap();
+            }
+            response => panic!("{:?}", response),
+        }
+    }
+
+    #[tokio::test]
+    pub async fn ok_requests_cross_unix_sockets() {
+        init_log();
+
+        let path =
+            std::env::temp_dir().join(format!("accounts-remote-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        ok_requests_cross_processes(Address::Unix(path.clone())).await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    pub async fn ok_requests_cross_tcp() {
+        init_log();
+
+        ok_requests_cross_processes(Address::Tcp("127.0.0.1:0".parse().unwrap())).await;
+    }
+
+    #[tokio::test]
+    pub async fn err_requests_fail_when_server_is_gone() {
+        init_log();
+
+        let listener = Listener::bind(&Address::Tcp("127.0.0.1:0".parse().unwrap()))
+            .await
+            .unwrap();
+        let address = listener.local_address().unwrap();
+        let server = tokio::task::spawn(async move {
+            // Accepts and drops the connection
+            let _ = listener.accept().await;
+        });
+
+        let manager = AccountManagerClient::connect(&address, 0, Arc::new(DepositsOnly))
+            .await
+            .unwrap();
+        server.await.unwrap();
+        let response = manager
+            .send_account_async(DepositRequest {
+                account_id: 1,
+                transaction_id: 1,
+                amount: 1 * Bitcoin,
+            })
+            .await;
+        assert!(response.is_err());
+    }
+}
@@ -0,0 +1,477 @@
+use std::sync::Arc;
+
+use rust_decimal::Decimal;
+
+use crate::{
+    actors::{
+        account::{
+            Accept, ChargebackRequest, ChargebackResponse, CloseRequest, CloseResponse,
+            DepositRequest, DepositResponse, DisputeRequest, DisputeResponse, FreezeRequest,
+            FreezeResponse, PassivateRequest, ResolveRequest, ResolveResponse, SnapshotRequest,
+            SnapshotResponse, UnfreezeRequest, UnfreezeResponse, WithdrawRequest, WithdrawResponse,
+        },
+        account_manager::{
+            AccountIdsRequest, AccountIdsResponse, Metrics, PassivateIdle, PassivationMetrics,
+            ReleaseAccountsRequest, ReleaseAccountsResponse, RestoreAccountsRequest,
+            RestoreAccountsResponse, SnapshotAccountsRequest, SnapshotAccountsResponse,
+        },
+        ActorError, Lifecycle,
+    },
+    domain::{
+        account::{Account, AccountErrors},
+        dispute_policy::DisputePolicy,
+        money::{Money, MoneyErrors},
+    },
+    store::{
+        codec::{self, corrupted, put_currency, Reader},
+        StoreError,
+    },
+};
+
+// Binary encoding of everything that crosses processes. Integers are
+// little endian, enums are a u8 tag followed by their fields.
+pub trait Wire: Sized {
+    fn encode(&self, bytes: &mut Vec<u8>);
+
+    fn decode(reader: &mut Reader) -> Result<Self, StoreError>;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.encode(&mut bytes);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, StoreError> {
+        Self::read(Reader::new(bytes))
+    }
+
+    // With what the receiver has to add to what it reads.
+    fn from_bytes_with<C: Context>(bytes: &[u8], context: &C) -> Result<Self, StoreError> {
+        Self::read(context.reader(bytes))
+    }
+
+    fn read(mut reader: Reader) -> Result<Self, StoreError> {
+        let value = Self::decode(&mut reader)?;
+        reader.end()?;
+        Ok(value)
+    }
+}
+
+// What is not on the wire but needed to decode it, given by whoever
+// receives it. Remote actors pass it along without looking at it.
+pub trait Context: Clone + Send + Sync + 'static {
+    fn reader<'a>(&self, bytes: &'a [u8]) -> Reader<'a>;
+}
+
+impl Context for () {
+    fn reader<'a>(&self, bytes: &'a [u8]) -> Reader<'a> {
+        Reader::new(bytes)
+    }
+}
+
+// Accounts in there get the dispute policy of whoever receives them.
+impl Context for Arc<dyn DisputePolicy> {
+    fn reader<'a>(&self, bytes: &'a [u8]) -> Reader<'a> {
+        Reader::new(bytes).with_dispute_policy(self.clone())
+    }
+}
+
+impl Wire for bool {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.push(*self as u8);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, StoreError> {
+        Ok(reader.u8()? != 0)
+    }
+}
+
+impl Wire for u32 {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, StoreError> {
+        reader.u32()
+    }
+}
+
+impl Wire for u64 {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, StoreError> {
+        reader.u64()
+    }
+}
+
+impl Wire for usize {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        (*self as u64).encode(bytes);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, StoreError> {
+        Ok(reader.u64()? as usize)
+    }
+}
+
+impl Wire for String {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        (self.len() as u32).encode(bytes);
+        bytes.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, StoreError> {
+        let len = reader.u32()?;
+        let bytes = reader.take_slice(len as usize)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| StoreError::Corrupted("string is not UTF-8".into()))
+    }
+}
+
+impl<T: Wire> Wire for Vec<T> {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        (self.len() as u32).encode(bytes);
+        for item in self.iter() {
+            item.encode(bytes);
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, StoreError> {
+        let len = reader.u32()?;
+        let mut items = vec![];
+        for _ in 0..len {
+            items.push(T::decode(reader)?);
+        }
+        Ok(items)
+    }
+}
+
+impl Wire for () {
+    fn encode(&self, _: &mut Vec<u8>) {}
+
+    fn decode(_: &mut Reader) -> Result<Self, StoreError> {
+        Ok(())
+    }
+}
+
+// Arguments of methods with more than one
+impl<A: Wire, B: Wire> Wire for (A, B) {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.0.encode(bytes);
+        self.1.encode(bytes);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, StoreError> {
+        Ok((A::decode(reader)?, B::decode(reader)?))
+    }
+}
+
+impl<A: Wire, B: Wire, C: Wire> Wire for (A, B, C) {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.0.encode(bytes);
+        self.1.encode(bytes);
+        self.2.encode(bytes);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, StoreError> {
+        Ok((A::decode(reader)?, B::decode(reader)?, C::decode(reader)?))
+    }
+}
+
+impl Wire for Decimal {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.serialize());
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, StoreError> {
+        reader.decimal()
+    }
+}
+
+impl Wire for Money {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        put_currency(bytes, self.currency);
+        self.amount.encode(bytes);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, StoreError> {
+        let currency = reader.currency()?;
+        Ok(reader.decimal()? * currency)
+    }
+}
+
+// Dispute policies do not travel. Accounts arrive with the one of the receiver.
+impl Wire for Account {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        let account = codec::encode(&self.to_parts());
+        (account.len() as u32).encode(bytes);
+        bytes.extend_from_slice(&account);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, StoreError> {
+        let len = reader.u32()?;
+        let parts = codec::decode(reader.take_slice(len as usize)?)?;
+        Ok(Account::from_parts(parts, reader.dispute_policy()?)?)
+    }
+}
+
+macro_rules! wire_struct {
+    ($name:ident) => {
+        impl Wire for $name {
+            fn encode(&self, _: &mut Vec<u8>) {}
+
+            fn decode(_: &mut Reader) -> Result<Self, StoreError> {
+                Ok($name)
+            }
+        }
+    };
+    ($name:ident { $($field:ident),* }) => {
+        impl Wire for $name {
+            fn encode(&self, bytes: &mut Vec<u8>) {
+                $(self.$field.encode(bytes);)*
+            }
+
+            fn decode(reader: &mut Reader) -> Result<Self, StoreError> {
+                Ok($name {
+                    $($field: Wire::decode(reader)?,)*
+                })
+            }
+        }
+    };
+}
+
+// Variants are unit or have exactly one field
+macro_rules! wire_enum {
+    ($name:ident { $($variant:ident $(($field:ident))?),* }) => {
+        impl Wire for $name {
+            #[allow(unused_assignments)]
+            fn encode(&self, bytes: &mut Vec<u8>) {
+                let mut tag = 0u8;
+                $(
+                    if let $name::$variant $(($field))? = self {
+                        bytes.push(tag);
+                        $($field.encode(bytes);)?
+                        return;
+                    }
+                    tag += 1;
+                )*
+            }
+
+            #[allow(unused_assignments)]
+            fn decode(reader: &mut Reader) -> Result<Self, StoreError> {
+                let tag = reader.u8()?;
+                let mut i = 0u8;
+                $(
+                    if tag == i {
+                        return Ok($name::$variant $(({
+                            let $field = Wire::decode(reader)?;
+                            $field
+                        }))?);
+                    }
+                    i += 1;
+                )*
+                Err(corrupted(stringify!($name), tag))
+            }
+        }
+    };
+}
+
+wire_enum!(MoneyErrors {
+    MismatchedCurrencies,
+    Overflow,
+    Underflow
+});
+wire_enum!(AccountErrors {
+    MoneyErrors(err),
+    NegativeAmount,
+    TransactionNotFound,
+    DuplicateTransactionId,
+    AlreadyDisputed,
+    NotDisputed,
+    AlreadyChargedBack,
+    DisputeNotAllowed,
+    AccountLocked,
+    AccountNotLocked,
+    AccountClosed,
+    LateArrival,
+    InconsistentBalances
+});
+
+wire_enum!(ActorError {
+    MailboxClosed,
+    ActorStopped,
+    Timeout,
+    Cancelled,
+    ShardUnavailable,
+    Domain(err),
+    Store(err),
+    InvalidRequest(err),
+    UnexpectedResponse(err),
+    Escalated(reason)
+});
+
+wire_struct!(DepositRequest {
+    account_id,
+    transaction_id,
+    amount
+});
+wire_struct!(WithdrawRequest {
+    account_id,
+    transaction_id,
+    amount
+});
+wire_struct!(DisputeRequest {
+    account_id,
+    transaction_id
+});
+wire_struct!(ResolveRequest {
+    account_id,
+    transaction_id
+});
+wire_struct!(ChargebackRequest {
+    account_id,
+    transaction_id
+});
+wire_struct!(FreezeRequest { account_id, reason });
+wire_struct!(UnfreezeRequest { account_id, reason });
+wire_struct!(CloseRequest { account_id });
+wire_struct!(PassivateRequest { account_id });
+wire_struct!(SnapshotRequest { account_id });
+wire_struct!(Accept);
+
+wire_enum!(DepositResponse { Ok, Error(err) });
+wire_enum!(WithdrawResponse { Ok, Error(err) });
+wire_enum!(DisputeResponse { Ok, Error(err) });
+wire_enum!(ResolveResponse { Ok, Error(err) });
+wire_enum!(ChargebackResponse { Ok, Error(err) });
+wire_enum!(FreezeResponse { Ok, Error(err) });
+wire_enum!(UnfreezeResponse { Ok, Error(err) });
+wire_enum!(CloseResponse { Ok, Error(err) });
+
+impl Wire for SnapshotResponse {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.0.encode(bytes);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, StoreError> {
+        Ok(SnapshotResponse(Wire::decode(reader)?))
+    }
+}
+
+wire_struct!(Metrics);
+wire_struct!(PassivateIdle);
+wire_struct!(SnapshotAccountsRequest);
+wire_struct!(AccountIdsRequest);
+wire_struct!(RestoreAccountsRequest { accounts });
+wire_struct!(ReleaseAccountsRequest { account_ids });
+wire_struct!(PassivationMetrics {
+    activations,
+    passivations,
+    restored,
+    restarts,
+    active
+});
+wire_enum!(Lifecycle { Drain, Stop });
+
+wire_enum!(SnapshotAccountsResponse {
+    Ok(accounts),
+    Error(err)
+});
+wire_enum!(RestoreAccountsResponse { Ok, Error(err) });
+wire_enum!(AccountIdsResponse {
+    Ok(account_ids),
+    Error(err)
+});
+wire_enum!(ReleaseAccountsResponse {
+    Ok(accounts),
+    Error(err)
+});
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        actors::{
+            account::{
+                AccountRequests, AccountResponses, DepositRequest, FreezeRequest, SnapshotResponse,
+                WithdrawResponse,
+            },
+            ActorError, Lifecycle,
+        },
+        domain::{
+            account::{Account, AccountErrors},
+            dispute_policy::{DepositsOnly, DisputePolicy},
+            money::{Currency::*, MoneyErrors},
+        },
+        store::{codec, StoreError},
+    };
+
+    use super::Wire;
+
+    fn policy() -> Arc<dyn DisputePolicy> {
+        Arc::new(DepositsOnly)
+    }
+
+    fn roundtrip<T: Wire + std::fmt::Debug>(value: T) {
+        let bytes = value.to_bytes();
+        let decoded = T::from_bytes_with(&bytes, &policy()).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", value));
+
+        if !bytes.is_empty() {
+            assert!(matches!(
+                T::from_bytes_with(&bytes[..bytes.len() - 1], &policy()),
+                Err(StoreError::Corrupted(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn ok_messages_roundtrip() {
+        roundtrip(AccountRequests::from(DepositRequest {
+            account_id: 1,
+            transaction_id: 2,
+            amount: 1.5 * Other { code: 3 },
+        }));
+        roundtrip(AccountRequests::from(FreezeRequest {
+            account_id: 1,
+            reason: "fraud".into(),
+        }));
+        roundtrip(AccountResponses::from(WithdrawResponse::Error(
+            AccountErrors::MoneyErrors(MoneyErrors::Underflow),
+        )));
+        roundtrip(AccountResponses::Error(ActorError::Domain(
+            AccountErrors::AccountLocked,
+        )));
+        roundtrip(AccountRequests::Lifecycle(Lifecycle::Stop));
+        roundtrip(AccountResponses::from(Lifecycle::Drain));
+
+        let mut account = Account::new(7);
+        let _ = account.deposit(1, 10 * Bitcoin);
+        let _ = account.dispute(1);
+        roundtrip(AccountResponses::from(SnapshotResponse(account)));
+    }
+
+    #[test]
+    fn err_invalid_accounts_are_rejected() {
+        let mut account = Account::new(7);
+        let _ = account.deposit(1, 10 * Bitcoin);
+        let bytes = account.to_bytes();
+        assert!(matches!(
+            Account::from_bytes(&bytes),
+            Err(StoreError::Corrupted(_))
+        ));
+
+        let mut parts = account.to_parts();
+        parts.total = 11 * Bitcoin;
+        let parts = codec::encode(&parts);
+        let mut bytes = (parts.len() as u32).to_bytes();
+        bytes.extend_from_slice(&parts);
+        assert!(matches!(
+            Account::from_bytes_with(&bytes, &policy()),
+            Err(StoreError::Corrupted(_))
+        ));
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{task::JoinHandle, time::Instant};
+
+use super::clock::Clock;
+
+// One for one supervision: a crashed child is restarted on its own, without
+// touching its siblings. A child that crashes more than `max_restarts` times
+// within `window` is not restarted again; the failure is escalated instead.
+#[derive(Clone, Copy, Debug)]
+pub struct RestartPolicy {
+    pub max_restarts: usize,
+    pub window: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 3,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+// How the task of an actor ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Exit {
+    Stopped,
+    Crashed(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    Restart,
+    // Give up on the child and fail the supervisor itself,
+    // so its own parent decides what to do.
+    Escalate,
+}
+
+#[derive(Debug)]
+pub struct Supervisor<TChild> {
+    policy: RestartPolicy,
+    // Times the restart window
+    clock: Arc<dyn Clock>,
+    restarts: HashMap<TChild, VecDeque<Instant>>,
+}
+
+impl<TChild: Hash + Eq> Supervisor<TChild> {
+    pub fn new(policy: RestartPolicy, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            policy,
+            clock,
+            restarts: HashMap::new(),
+        }
+    }
+
+    // What to do with a child that just crashed.
+    pub fn crashed(&mut self, child: TChild) -> Decision {
+        let now = self.clock.now();
+        let restarts = self.restarts.entry(child).or_default();
+        while restarts
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= self.policy.window)
+        {
+            restarts.pop_front();
+        }
+
+        if restarts.len() >= self.policy.max_restarts {
+            return Decision::Escalate;
+        }
+        restarts.push_back(now);
+        Decision::Restart
+    }
+
+    // The child is gone for good, so its crashes no longer matter.
+    pub fn forget(&mut self, child: &TChild) {
+        self.restarts.remove(child);
+    }
+}
+
+// Waits for the task of an actor to end.
+pub async fn exit(task: JoinHandle<()>) -> Exit {
+    match task.await {
+        Ok(()) => Exit::Stopped,
+        Err(err) if err.is_panic() => {
+            let panic = err.into_panic();
+            let reason = match panic.downcast_ref::<&str>() {
+                Some(reason) => reason.to_string(),
+                None => match panic.downcast_ref::<String>() {
+                    Some(reason) => reason.clone(),
+                    None => "panicked".into(),
+                },
+            };
+            Exit::Crashed(reason)
+        }
+        Err(err) => Exit::Crashed(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::actors::clock::VirtualClock;
+
+    use super::{Decision, RestartPolicy, Supervisor};
+
+    #[tokio::test]
+    async fn ok_crashes_outside_the_window_are_forgotten() {
+        let clock = VirtualClock::new();
+        let policy = RestartPolicy {
+            max_restarts: 1,
+            window: Duration::from_secs(60),
+        };
+        let mut supervisor = Supervisor::new(policy, Arc::new(clock.clone()));
+
+        assert_eq!(supervisor.crashed(1), Decision::Restart);
+        assert_eq!(supervisor.crashed(2), Decision::Restart);
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(supervisor.crashed(1), Decision::Escalate);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(supervisor.crashed(1), Decision::Restart);
+        assert_eq!(supervisor.crashed(2), Decision::Restart);
+    }
+}
@@ -0,0 +1,41 @@
+// Shared by the tests of the actors.
+
+use std::{path::PathBuf, sync::Arc};
+
+use crate::{
+    actors::account::{AccountActorConfig, Scheduling},
+    domain::{
+        dispute_policy::{DisputePolicy, DisputeTreatment},
+        transaction::TransactionKind,
+    },
+    store::journal::Journal,
+};
+
+// Disputing a withdrawal crashes the account actor
+#[derive(Debug)]
+pub struct CrashOnWithdrawals;
+
+impl DisputePolicy for CrashOnWithdrawals {
+    fn treatment(&self, kind: TransactionKind) -> Option<DisputeTreatment> {
+        match kind {
+            TransactionKind::Deposit => Some(DisputeTreatment::Hold),
+            TransactionKind::Withdraw => panic!("withdrawal disputed"),
+        }
+    }
+}
+
+// A file of this process in the temp dir, gone if a previous run left it.
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("accounts-{}-{}.log", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+// Deterministic accounts that journal their requests.
+pub fn journaled_config(journal: Journal) -> AccountActorConfig {
+    AccountActorConfig {
+        scheduling: Scheduling::Deterministic,
+        journal: Some(Arc::new(journal)),
+        ..Default::default()
+    }
+}
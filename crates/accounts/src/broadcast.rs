@@ -1,29 +1,228 @@
-use tokio::task::JoinHandle;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    hash::Hash,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+};
 
-pub struct Broadcast<T> {
-    sender: tokio::sync::broadcast::Sender<T>,
-    receiver: tokio::sync::broadcast::Receiver<T>,
+use tokio::{sync::Notify, task::JoinHandle};
+
+use crate::domain::events::{AllEvents, EventKind};
+
+// Lossless event bus. Every subscription gets the events matching its
+// filter that were broadcast after it subscribed, in order, exactly once.
+// Each subscription has its own queue; publishers wait for `ready` before
+// producing more, so a slow subscriber slows down the publishers of the
+// events it gets instead of losing events.
+pub struct Broadcast<T: Topic> {
+    bus: Arc<Bus<T>>,
+    // Tags the events of this publisher
+    manager: Option<u64>,
 }
 
-impl<T: Clone> Default for Broadcast<T> {
-    fn default() -> Self {
-        let (sender, receiver) = tokio::sync::broadcast::channel(1024); //TODO magic number
-        Self { sender, receiver }
+// What the bus routes events by.
+pub trait Topic {
+    type Kind: 'static + Send + Sync + Copy + Eq + Hash + std::fmt::Debug;
+
+    fn kind(&self) -> Self::Kind;
+    fn account_id(&self) -> u32;
+}
+
+impl Topic for AllEvents {
+    type Kind = EventKind;
+
+    fn kind(&self) -> EventKind {
+        AllEvents::kind(self)
     }
+
+    fn account_id(&self) -> u32 {
+        self.get_account_id()
+    }
+}
+
+// An event as subscribers get it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Published<T> {
+    // Position in the bus, the same for every subscription
+    pub offset: u64,
+    // Increases by one with every event of the same account
+    pub sequence: u64,
+    // The account manager of the publisher, if it has one
+    pub manager: Option<u64>,
+    pub event: T,
 }
 
-impl<T> Clone for Broadcast<T> {
+// Which events a subscription gets. Every condition set must match.
+#[derive(Clone, Debug)]
+pub struct Filter<K> {
+    kinds: Option<HashSet<K>>,
+    accounts: Option<RangeInclusive<u32>>,
+    manager: Option<u64>,
+}
+
+impl<K: Copy + Eq + Hash> Filter<K> {
+    pub fn all() -> Self {
+        Self {
+            kinds: None,
+            accounts: None,
+            manager: None,
+        }
+    }
+
+    pub fn with_kinds(mut self, kinds: impl IntoIterator<Item = K>) -> Self {
+        self.kinds = Some(kinds.into_iter().collect());
+        self
+    }
+
+    pub fn with_accounts(mut self, accounts: RangeInclusive<u32>) -> Self {
+        self.accounts = Some(accounts);
+        self
+    }
+
+    pub fn with_manager(mut self, manager: u64) -> Self {
+        self.manager = Some(manager);
+        self
+    }
+
+    fn matches<T: Topic<Kind = K>>(&self, event: &T, manager: Option<u64>) -> bool {
+        self.kinds
+            .as_ref()
+            .is_none_or(|kinds| kinds.contains(&event.kind()))
+            && self.may_match(event.account_id(), manager)
+    }
+
+    // Whether some event of the account could match, whatever its kind.
+    fn may_match(&self, account_id: u32, manager: Option<u64>) -> bool {
+        self.accounts
+            .as_ref()
+            .is_none_or(|accounts| accounts.contains(&account_id))
+            && self.manager.is_none_or(|x| manager == Some(x))
+    }
+}
+
+struct Bus<T: Topic> {
+    state: Mutex<BusState<T>>,
+    // Signalled when a subscription reads an event
+    consumed: Notify,
+    // How many events a subscription may have unread
+    capacity: usize,
+}
+
+struct BusState<T: Topic> {
+    // Offset of the next event
+    next: u64,
+    // Sequence number of the next event of each account
+    sequences: HashMap<u32, u64>,
+    subscriptions: HashMap<u64, Queue<T>>,
+    next_subscription: u64,
+}
+
+impl<T: Topic> BusState<T> {
+    fn lag(&self) -> usize {
+        self.subscriptions
+            .values()
+            .map(|queue| queue.events.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Lag of the subscriptions that may get events of the account.
+    fn lag_of(&self, account_id: u32, manager: Option<u64>) -> usize {
+        self.subscriptions
+            .values()
+            .filter(|queue| queue.filter.may_match(account_id, manager))
+            .map(|queue| queue.events.len())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+struct Queue<T: Topic> {
+    filter: Filter<T::Kind>,
+    events: VecDeque<Published<T>>,
+    // Offset after the last event read
+    cursor: u64,
+    published: Arc<Notify>,
+}
+
+impl<T: Topic> Clone for Broadcast<T> {
     fn clone(&self) -> Self {
         Self {
-            sender: self.sender.clone(),
-            receiver: self.sender.subscribe(),
+            bus: self.bus.clone(),
+            manager: self.manager,
         }
     }
 }
 
-#[derive(Clone)]
-pub struct Sender<T> {
-    sender: tokio::sync::broadcast::Sender<T>,
+impl<T: Clone + Topic> Default for Broadcast<T> {
+    fn default() -> Self {
+        Self::with_capacity(1024)
+    }
+}
+
+// Handle of a subscription. Dropping it unsubscribes too.
+pub struct Subscription<T: Topic> {
+    id: u64,
+    bus: Arc<Bus<T>>,
+    published: Arc<Notify>,
+}
+
+impl<T: Topic> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+impl<T: Topic> Subscription<T> {
+    fn remove(&self) -> Option<Queue<T>> {
+        let queue = self
+            .bus
+            .state
+            .lock()
+            .unwrap()
+            .subscriptions
+            .remove(&self.id);
+        self.bus.consumed.notify_waiters();
+        queue
+    }
+
+    // Offset after the last event read.
+    pub fn cursor(&self) -> u64 {
+        self.bus.state.lock().unwrap().subscriptions[&self.id].cursor
+    }
+
+    pub fn try_recv(&mut self) -> Option<Published<T>> {
+        let mut state = self.bus.state.lock().unwrap();
+        let queue = state.subscriptions.get_mut(&self.id)?;
+        let published = queue.events.pop_front()?;
+        queue.cursor = published.offset + 1;
+        drop(state);
+        self.bus.consumed.notify_waiters();
+        Some(published)
+    }
+
+    // Cancel safe: the cursor only moves when an event is returned.
+    pub async fn recv_async(&mut self) -> Published<T> {
+        let published = self.published.clone();
+        loop {
+            let notified = published.notified();
+            tokio::pin!(notified);
+            // Registered before checking, so an event in between is not missed
+            notified.as_mut().enable();
+            if let Some(event) = self.try_recv() {
+                return event;
+            }
+            notified.await;
+        }
+    }
+
+    // Stops getting events. Returns the ones not read yet.
+    pub fn unsubscribe(self) -> Vec<Published<T>> {
+        match self.remove() {
+            Some(queue) => queue.events.into(),
+            None => vec![],
+        }
+    }
 }
 
 pub struct BroadcastRecorder<T> {
@@ -38,44 +237,137 @@ impl<T> BroadcastRecorder<T> {
     }
 }
 
-impl<T: Clone> Broadcast<T> {
+impl<T: Clone + Topic> Broadcast<T> {
     pub fn new() -> Self {
         Self::default()
     }
 
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            bus: Arc::new(Bus {
+                state: Mutex::new(BusState {
+                    next: 0,
+                    sequences: HashMap::new(),
+                    subscriptions: HashMap::new(),
+                    next_subscription: 0,
+                }),
+                consumed: Notify::new(),
+                capacity,
+            }),
+            manager: None,
+        }
+    }
+
+    // Events of the returned publisher are tagged with `manager`.
+    pub fn with_manager(mut self, manager: u64) -> Self {
+        self.manager = Some(manager);
+        self
+    }
+
+    // Sequence number of the next event of the account.
+    pub fn sequence(&self, account_id: u32) -> u64 {
+        let state = self.bus.state.lock().unwrap();
+        state.sequences.get(&account_id).copied().unwrap_or(0)
+    }
+
+    // Continues the sequence of an account that was outside of memory.
+    // It never goes back, even if the account was saved before its last events.
+    pub fn resume(&self, account_id: u32, sequence: u64) {
+        let mut state = self.bus.state.lock().unwrap();
+        let next = state.sequences.entry(account_id).or_default();
+        *next = sequence.max(*next);
+    }
+
+    // Events are numbered even without subscriptions,
+    // so the sequence of an account never goes back.
     pub fn broadcast_all(&self, events: impl Iterator<Item = T>) {
+        let mut state = self.bus.state.lock().unwrap();
+        let state = &mut *state;
         for event in events {
-            let _ = self.sender.send(event);
+            let offset = state.next;
+            state.next += 1;
+            let sequence = state.sequences.entry(event.account_id()).or_default();
+            let published = Published {
+                offset,
+                sequence: *sequence,
+                manager: self.manager,
+                event,
+            };
+            *sequence += 1;
+
+            for queue in state.subscriptions.values_mut() {
+                if queue.filter.matches(&published.event, self.manager) {
+                    queue.events.push_back(published.clone());
+                    queue.published.notify_waiters();
+                }
+            }
         }
     }
 
-    pub async fn recv_async(&mut self) -> Result<T, tokio::sync::broadcast::error::RecvError> {
-        self.receiver.recv().await
+    // Gets the matching events broadcast from now on.
+    pub fn subscribe(&self, filter: Filter<T::Kind>) -> Subscription<T> {
+        let mut state = self.bus.state.lock().unwrap();
+        let id = state.next_subscription;
+        state.next_subscription += 1;
+        let published = Arc::new(Notify::new());
+        let queue = Queue {
+            filter,
+            events: VecDeque::new(),
+            cursor: state.next,
+            published: published.clone(),
+        };
+        state.subscriptions.insert(id, queue);
+        Subscription {
+            id,
+            bus: self.bus.clone(),
+            published,
+        }
     }
 
-    pub fn to_sender(self) -> tokio::sync::broadcast::Sender<T> {
-        self.sender
+    // How many events the slowest subscription has not read yet.
+    pub fn lag(&self) -> usize {
+        self.bus.state.lock().unwrap().lag()
     }
 
-    pub fn to_receiver(self) -> tokio::sync::broadcast::Receiver<T> {
-        self.receiver
+    // Waits until every subscription that may get events of the account
+    // from this publisher has less than the capacity unread.
+    // Publishers call it before producing more events of the account.
+    pub async fn ready(&self, account_id: u32) {
+        loop {
+            let consumed = self.bus.consumed.notified();
+            tokio::pin!(consumed);
+            consumed.as_mut().enable();
+            let lag = self
+                .bus
+                .state
+                .lock()
+                .unwrap()
+                .lag_of(account_id, self.manager);
+            if lag < self.bus.capacity {
+                return;
+            }
+            consumed.await;
+        }
     }
 
     pub fn spawn_recorder(self) -> BroadcastRecorder<T>
     where
         T: 'static + Send,
     {
-        let Broadcast { mut receiver, .. } = self;
+        let mut subscription = self.subscribe(Filter::all());
         let (cancel_sender, cancel_receiver) = flume::bounded(1);
 
         let handle = tokio::task::spawn(async move {
             let mut events = vec![];
             loop {
                 tokio::select! {
-                    event = receiver.recv() => events.push(event.unwrap()),
+                    published = subscription.recv_async() => events.push(published.event),
                     _ = cancel_receiver.recv_async() => break,
                 }
             }
+            // Events broadcast before stopping are recorded too
+            let unread = subscription.unsubscribe();
+            events.extend(unread.into_iter().map(|published| published.event));
             events
         });
 
@@ -85,3 +377,139 @@ impl<T: Clone> Broadcast<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::events::{AllEvents, EventKind};
+
+    use super::{Broadcast, Filter, Published};
+
+    fn closed(account_id: u32) -> AllEvents {
+        AllEvents::AccountClosed { account_id }
+    }
+
+    fn frozen(account_id: u32) -> AllEvents {
+        AllEvents::AccountFrozen {
+            account_id,
+            reason: "fraud".into(),
+        }
+    }
+
+    fn account_ids(published: &[Published<AllEvents>]) -> Vec<u32> {
+        published.iter().map(|x| x.event.get_account_id()).collect()
+    }
+
+    #[tokio::test]
+    async fn ok_slow_subscribers_get_every_event() {
+        let broadcast = Broadcast::with_capacity(4);
+        let mut slow = broadcast.subscribe(Filter::all());
+        let mut fast = broadcast.subscribe(Filter::all());
+
+        let publisher = broadcast.clone();
+        let published = tokio::task::spawn(async move {
+            for account_id in 0..100 {
+                publisher.ready(account_id).await;
+                publisher.broadcast_all(std::iter::once(closed(account_id)));
+            }
+        });
+
+        let mut received = vec![];
+        while received.len() < 4 {
+            received.push(fast.recv_async().await);
+        }
+        assert_eq!(account_ids(&received), vec![0, 1, 2, 3]);
+        // The publisher waits for the slow subscriber instead of dropping events.
+        // Polled once, readiness would not wait if the bus had room.
+        let ready = tokio::time::timeout(std::time::Duration::ZERO, broadcast.ready(4));
+        assert!(ready.await.is_err());
+        assert_eq!(broadcast.lag(), 4);
+        assert!(fast.try_recv().is_none());
+        assert!(!published.is_finished());
+
+        let fast = tokio::task::spawn(async move {
+            while received.len() < 100 {
+                received.push(fast.recv_async().await);
+            }
+            received
+        });
+        let mut received = vec![];
+        while received.len() < 100 {
+            received.push(slow.recv_async().await);
+        }
+        assert_eq!(account_ids(&received), (0..100).collect::<Vec<_>>());
+        assert_eq!(
+            account_ids(&fast.await.unwrap()),
+            (0..100).collect::<Vec<_>>()
+        );
+        assert_eq!(slow.cursor(), 100);
+        published.await.unwrap();
+        assert_eq!(broadcast.lag(), 0);
+    }
+
+    #[tokio::test]
+    async fn ok_subscriptions_get_matching_events() {
+        let broadcast = Broadcast::new();
+        let first = broadcast.clone().with_manager(1);
+        let second = broadcast.clone().with_manager(2);
+
+        let kinds = Filter::all().with_kinds([EventKind::AccountFrozen]);
+        let frozen_only = broadcast.subscribe(kinds);
+        let some_accounts = broadcast.subscribe(Filter::all().with_accounts(10..=19));
+        let mut second_only = broadcast.subscribe(Filter::all().with_manager(2));
+
+        first.broadcast_all([closed(1), frozen(10), frozen(1)].into_iter());
+        second.broadcast_all([frozen(20), closed(10)].into_iter());
+
+        assert_eq!(account_ids(&frozen_only.unsubscribe()), vec![10, 1, 20]);
+
+        // Sequences are per account, whoever publishes
+        let published = some_accounts.unsubscribe();
+        assert_eq!(account_ids(&published), vec![10, 10]);
+        let tags: Vec<_> = published.iter().map(|x| (x.sequence, x.manager)).collect();
+        assert_eq!(tags, vec![(0, Some(1)), (1, Some(2))]);
+
+        let published = second_only.try_recv().unwrap();
+        assert_eq!((published.offset, published.sequence), (3, 0));
+        assert_eq!(second_only.cursor(), 4);
+        assert_eq!(account_ids(&second_only.unsubscribe()), vec![10]);
+
+        // Nobody holds the publishers back anymore
+        first.broadcast_all(std::iter::once(closed(1)));
+        assert_eq!(broadcast.lag(), 0);
+    }
+
+    #[tokio::test]
+    async fn ok_slow_subscribers_only_hold_back_their_events() {
+        let broadcast = Broadcast::with_capacity(1);
+        let slow = broadcast.subscribe(Filter::all().with_accounts(10..=19));
+
+        broadcast.broadcast_all(std::iter::once(closed(10)));
+        for account_id in 0..10 {
+            broadcast.ready(account_id).await;
+            broadcast.broadcast_all(std::iter::once(closed(account_id)));
+        }
+
+        let ready = tokio::time::timeout(std::time::Duration::ZERO, broadcast.ready(10));
+        assert!(ready.await.is_err());
+        assert_eq!(account_ids(&slow.unsubscribe()), vec![10]);
+        broadcast.ready(10).await;
+    }
+
+    #[test]
+    fn ok_resumed_sequences_never_go_back() {
+        let broadcast = Broadcast::new();
+        let mut subscription = broadcast.subscribe(Filter::all());
+
+        broadcast.resume(1, 5);
+        broadcast.broadcast_all([closed(1), closed(2)].into_iter());
+        // Saved before its last event
+        broadcast.resume(1, 5);
+        broadcast.broadcast_all(std::iter::once(closed(1)));
+
+        let sequences: Vec<_> = std::iter::from_fn(|| subscription.try_recv())
+            .map(|x| (x.event.get_account_id(), x.sequence))
+            .collect();
+        assert_eq!(sequences, vec![(1, 5), (2, 0), (1, 6)]);
+        assert_eq!(broadcast.sequence(1), 7);
+    }
+}
@@ -1,172 +1,630 @@
-use std::collections::{BTreeMap, BTreeSet};
-
-use rust_decimal::Decimal;
+use std::{collections::BTreeMap, sync::Arc};
 
 use crate::domain::money::{Currency, Money, MoneyErrors};
 
-use super::{events::AllEvents, DomainResult};
+use super::{
+    dispute_policy::{DepositsOnly, DisputePolicy, DisputeTreatment},
+    events::{AccountBalances, AllEvents},
+    transaction::{Transaction, TransactionKind, TransactionOperation, TransactionState},
+    DomainResult,
+};
 
 #[derive(Clone, Debug)]
 pub struct Account {
     id: u32,
-    amount: Money,
-    ammounts: BTreeMap<u32, Decimal>,
-    in_dispute: BTreeSet<u32>,
+    available: Money,
+    held: Money,
+    total: Money,
+    transactions: BTreeMap<u32, Transaction>,
     locked: bool,
+    closed: bool,
+    // Sequence number of the next event published for the account
+    sequence: u64,
+    dispute_policy: Arc<dyn DisputePolicy>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum AccountErrors {
     MoneyErrors(MoneyErrors),
     NegativeAmount,
     TransactionNotFound,
+    DuplicateTransactionId,
+    AlreadyDisputed,
+    NotDisputed,
+    AlreadyChargedBack,
+    DisputeNotAllowed,
     AccountLocked,
+    AccountNotLocked,
+    AccountClosed,
+    LateArrival,
+    // Parts that no account could have ended up with
+    InconsistentBalances,
+}
+
+impl From<MoneyErrors> for AccountErrors {
+    fn from(err: MoneyErrors) -> Self {
+        AccountErrors::MoneyErrors(err)
+    }
+}
+
+// Everything an account is made of, except its dispute policy.
+// Used to store accounts outside of memory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccountParts {
+    pub id: u32,
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+    pub transactions: BTreeMap<u32, Transaction>,
+    pub locked: bool,
+    pub closed: bool,
+    pub sequence: u64,
 }
 
 type AccountDomainResult<T> = DomainResult<T, AccountErrors, AllEvents>;
 
+// New balances of an operation. They are all calculated before
+// anything is changed, so an operation either changes all of them or none.
+struct Balances {
+    available: Money,
+    held: Money,
+    total: Money,
+}
+
+impl From<Balances> for AccountBalances {
+    fn from(balances: Balances) -> Self {
+        AccountBalances {
+            available: balances.available.into(),
+            held: balances.held.into(),
+            total: balances.total.into(),
+        }
+    }
+}
+
 impl Account {
     pub fn new(id: u32) -> Self {
+        Self::with_dispute_policy(id, Arc::new(DepositsOnly))
+    }
+
+    pub fn with_dispute_policy(id: u32, dispute_policy: Arc<dyn DisputePolicy>) -> Self {
+        let zero = Currency::Bitcoin.zero();
         Self {
             id,
-            amount: Currency::Bitcoin.zero(),
-            ammounts: BTreeMap::new(),
-            in_dispute: BTreeSet::new(),
+            available: zero,
+            held: zero,
+            total: zero,
+            transactions: BTreeMap::new(),
             locked: false,
+            closed: false,
+            sequence: 0,
+            dispute_policy,
+        }
+    }
+
+    // Rebuilds an account folding all its events.
+    pub fn from_events<'a>(
+        id: u32,
+        dispute_policy: Arc<dyn DisputePolicy>,
+        events: impl IntoIterator<Item = &'a AllEvents>,
+    ) -> Self {
+        let mut account = Self::with_dispute_policy(id, dispute_policy);
+        for event in events {
+            account.apply(event);
+        }
+        account
+    }
+
+    // Parts come from outside of memory, so they are checked, not trusted.
+    pub fn from_parts(
+        parts: AccountParts,
+        dispute_policy: Arc<dyn DisputePolicy>,
+    ) -> Result<Self, AccountErrors> {
+        let AccountParts {
+            id,
+            available,
+            held,
+            total,
+            transactions,
+            locked,
+            closed,
+            sequence,
+        } = parts;
+        let account = Self {
+            id,
+            available,
+            held,
+            total,
+            transactions,
+            locked,
+            closed,
+            sequence,
+            dispute_policy,
+        };
+        let consistent = account.available.checked_add(account.held).ok() == Some(account.total)
+            && !account.held.is_negative();
+        if !consistent {
+            return Err(AccountErrors::InconsistentBalances);
+        }
+        Ok(account)
+    }
+
+    pub fn to_parts(&self) -> AccountParts {
+        AccountParts {
+            id: self.id,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            transactions: self.transactions.clone(),
+            locked: self.locked,
+            closed: self.closed,
+            sequence: self.sequence,
+        }
+    }
+
+    // Events are the only way to change an account. Commands only validate
+    // and decide which events to raise; this is where they take effect.
+    pub fn apply(&mut self, event: &AllEvents) {
+        debug_assert!(event.get_account_id() == self.id);
+
+        let currency = self.total.currency;
+        match event {
+            AllEvents::Deposited {
+                transaction_id,
+                amount,
+                balances,
+                ..
+            } => {
+                let transaction = Transaction::new(TransactionKind::Deposit, *amount * currency);
+                self.transactions.insert(*transaction_id, transaction);
+                self.set_balances(balances);
+            }
+            AllEvents::Withdrawn {
+                transaction_id,
+                amount,
+                balances,
+                ..
+            } => {
+                let transaction = Transaction::new(TransactionKind::Withdraw, *amount * currency);
+                self.transactions.insert(*transaction_id, transaction);
+                self.set_balances(balances);
+            }
+            AllEvents::DisputeOpened {
+                transaction_id,
+                balances,
+                ..
+            } => {
+                self.set_state(*transaction_id, TransactionState::Disputed);
+                self.set_balances(balances);
+            }
+            AllEvents::DisputeResolved {
+                transaction_id,
+                balances,
+                ..
+            } => {
+                self.set_state(*transaction_id, TransactionState::Resolved);
+                self.set_balances(balances);
+            }
+            AllEvents::ChargedBack {
+                transaction_id,
+                balances,
+                ..
+            } => {
+                self.set_state(*transaction_id, TransactionState::ChargedBack);
+                self.set_balances(balances);
+            }
+            AllEvents::AccountLocked { .. } | AllEvents::AccountFrozen { .. } => self.locked = true,
+            AllEvents::AccountUnfrozen { .. } => self.locked = false,
+            AllEvents::AccountClosed { .. } => self.closed = true,
+            AllEvents::OperationRejected { .. } | AllEvents::OutcomeChanged { .. } => {}
+            AllEvents::HistoryRewound {
+                balances,
+                transactions,
+                locked,
+                closed,
+                ..
+            } => {
+                self.set_balances(balances);
+                self.transactions = transactions.clone();
+                self.locked = *locked;
+                self.closed = *closed;
+            }
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn available(&self) -> Money {
+        self.available
+    }
+
+    pub fn held(&self) -> Money {
+        self.held
+    }
+
+    pub fn total(&self) -> Money {
+        self.total
+    }
+
+    pub fn balances(&self) -> AccountBalances {
+        AccountBalances {
+            available: self.available.into(),
+            held: self.held.into(),
+            total: self.total.into(),
         }
     }
 
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    // Kept with the account, so its events are numbered
+    // from where they were after it is stored and loaded again.
+    pub fn set_sequence(&mut self, sequence: u64) {
+        self.sequence = sequence;
+    }
+
+    pub fn get_transaction(&self, transaction_id: u32) -> Option<&Transaction> {
+        self.transactions.get(&transaction_id)
+    }
+
+    pub fn last_transaction_id(&self) -> Option<u32> {
+        self.transactions.keys().next_back().copied()
+    }
+
     pub fn deposit(&mut self, transaction_id: u32, amount: Money) -> AccountDomainResult<()> {
+        if let Some(result) = self.check_replay(transaction_id, TransactionKind::Deposit, amount) {
+            return result;
+        }
+
         if self.locked {
-            AccountDomainResult::Err(AccountErrors::AccountLocked)
-        } else {
-            let mut events = vec![];
-
-            match self.amount.checked_add(amount) {
-                Ok(amount) => {
-                    self.ammounts.insert(transaction_id, amount.as_decimal());
-                    self.amount = amount;
-                    self.raise_account_updated(&mut events, transaction_id);
-                    AccountDomainResult::Ok { data: (), events }
-                }
-                Err(err) => AccountDomainResult::Err(AccountErrors::MoneyErrors(err)),
-            }
+            return AccountDomainResult::Err(AccountErrors::AccountLocked);
+        }
+
+        if self.closed {
+            return AccountDomainResult::Err(AccountErrors::AccountClosed);
         }
+
+        let balances = match self.balances_after_deposit(amount) {
+            Ok(balances) => balances,
+            Err(err) => return AccountDomainResult::Err(err),
+        };
+
+        let events = vec![AllEvents::Deposited {
+            account_id: self.id,
+            transaction_id,
+            amount: amount.into(),
+            balances: balances.into(),
+        }];
+        self.emit(events)
     }
 
     pub fn withdraw(&mut self, transaction_id: u32, amount: Money) -> AccountDomainResult<()> {
+        if let Some(result) = self.check_replay(transaction_id, TransactionKind::Withdraw, amount) {
+            return result;
+        }
+
         if self.locked {
-            AccountDomainResult::Err(AccountErrors::AccountLocked)
-        } else {
-            let mut events = vec![];
-
-            match self.amount.checked_sub(amount) {
-                Ok(amount) => {
-                    if amount.is_negative() {
-                        AccountDomainResult::Err(AccountErrors::NegativeAmount)
-                    } else {
-                        self.ammounts
-                            .insert(transaction_id, amount.as_decimal() * Decimal::NEGATIVE_ONE);
-                        self.amount = amount;
-                        self.raise_account_updated(&mut events, transaction_id);
-                        AccountDomainResult::Ok { data: (), events }
-                    }
-                }
-                Err(err) => AccountDomainResult::Err(AccountErrors::MoneyErrors(err)),
-            }
+            return AccountDomainResult::Err(AccountErrors::AccountLocked);
         }
+
+        let balances = match self.balances_after_withdraw(amount) {
+            Ok(balances) => balances,
+            Err(err) => return AccountDomainResult::Err(err),
+        };
+
+        let events = vec![AllEvents::Withdrawn {
+            account_id: self.id,
+            transaction_id,
+            amount: amount.into(),
+            balances: balances.into(),
+        }];
+        self.emit(events)
     }
 
+    // Disputed funds are held until the dispute is resolved or charged back.
+    // How "available" and "total" move depends on the dispute policy.
     pub fn dispute(&mut self, transaction_id: u32) -> AccountDomainResult<()> {
         if self.locked {
-            AccountDomainResult::Err(AccountErrors::AccountLocked)
-        } else {
-            let mut events = vec![];
-
-            match self.ammounts.get(&transaction_id) {
-                Some(amount) => match self.amount.checked_sub(*amount * self.amount.currency) {
-                    Ok(amount) => {
-                        self.amount = amount;
-                        self.in_dispute.insert(transaction_id);
-                        self.raise_account_updated(&mut events, transaction_id);
-                        AccountDomainResult::Ok { data: (), events }
-                    }
-                    Err(err) => AccountDomainResult::Err(AccountErrors::MoneyErrors(err)),
-                },
-                None => AccountDomainResult::Err(AccountErrors::TransactionNotFound),
-            }
+            return AccountDomainResult::Err(AccountErrors::AccountLocked);
         }
+
+        let (treatment, amount) =
+            match self.next_state(transaction_id, TransactionOperation::Dispute) {
+                Ok(next) => next,
+                Err(err) => return AccountDomainResult::Err(err),
+            };
+
+        let balances = match self.balances_after_dispute(treatment, amount) {
+            Ok(balances) => balances,
+            Err(err) => return AccountDomainResult::Err(err),
+        };
+
+        let events = vec![AllEvents::DisputeOpened {
+            account_id: self.id,
+            transaction_id,
+            amount: amount.into(),
+            balances: balances.into(),
+        }];
+        self.emit(events)
     }
 
     pub fn resolve(&mut self, transaction_id: u32) -> AccountDomainResult<()> {
         if self.locked {
-            AccountDomainResult::Err(AccountErrors::AccountLocked)
-        } else {
-            let mut events = vec![];
-
-            if !self.in_dispute.remove(&transaction_id) {
-                AccountDomainResult::Err(AccountErrors::TransactionNotFound)
-            } else {
-                match self.ammounts.get(&transaction_id) {
-                    Some(amount) => match self.amount.checked_add(*amount * self.amount.currency) {
-                        Ok(amount) => {
-                            self.amount = amount;
-                            self.raise_account_updated(&mut events, transaction_id);
-                            AccountDomainResult::Ok { data: (), events }
-                        }
-                        Err(err) => AccountDomainResult::Err(AccountErrors::MoneyErrors(err)),
-                    },
-                    None => AccountDomainResult::Err(AccountErrors::TransactionNotFound),
-                }
-            }
+            return AccountDomainResult::Err(AccountErrors::AccountLocked);
         }
+
+        let (treatment, amount) =
+            match self.next_state(transaction_id, TransactionOperation::Resolve) {
+                Ok(next) => next,
+                Err(err) => return AccountDomainResult::Err(err),
+            };
+
+        let balances = match self.balances_after_resolve(treatment, amount) {
+            Ok(balances) => balances,
+            Err(err) => return AccountDomainResult::Err(err),
+        };
+
+        let events = vec![AllEvents::DisputeResolved {
+            account_id: self.id,
+            transaction_id,
+            amount: amount.into(),
+            balances: balances.into(),
+        }];
+        self.emit(events)
     }
 
+    // The dispute is settled in favour of the client and the account is locked.
     pub fn chargeback(&mut self, transaction_id: u32) -> AccountDomainResult<()> {
         if self.locked {
-            AccountDomainResult::Err(AccountErrors::AccountLocked)
-        } else {
-            let mut events = vec![];
+            return AccountDomainResult::Err(AccountErrors::AccountLocked);
+        }
+
+        let (treatment, amount) =
+            match self.next_state(transaction_id, TransactionOperation::Chargeback) {
+                Ok(next) => next,
+                Err(err) => return AccountDomainResult::Err(err),
+            };
+
+        let balances = match self.balances_after_chargeback(treatment, amount) {
+            Ok(balances) => balances,
+            Err(err) => return AccountDomainResult::Err(err),
+        };
+
+        let events = vec![
+            AllEvents::ChargedBack {
+                account_id: self.id,
+                transaction_id,
+                amount: amount.into(),
+                balances: balances.into(),
+            },
+            AllEvents::AccountLocked {
+                account_id: self.id,
+                transaction_id,
+            },
+        ];
+        self.emit(events)
+    }
+
+    // Administrative lock. Same effect as a chargeback lock:
+    // nothing can be done until the account is unfrozen.
+    pub fn freeze(&mut self, reason: String) -> AccountDomainResult<()> {
+        if self.locked {
+            return AccountDomainResult::Err(AccountErrors::AccountLocked);
+        }
+
+        let events = vec![AllEvents::AccountFrozen {
+            account_id: self.id,
+            reason,
+        }];
+        self.emit(events)
+    }
+
+    // Unlocks frozen and charged back accounts.
+    pub fn unfreeze(&mut self, reason: String) -> AccountDomainResult<()> {
+        if !self.locked {
+            return AccountDomainResult::Err(AccountErrors::AccountNotLocked);
+        }
+
+        let events = vec![AllEvents::AccountUnfrozen {
+            account_id: self.id,
+            reason,
+        }];
+        self.emit(events)
+    }
+
+    // Closed accounts do not accept new funds, but still
+    // allow the client to withdraw what is left.
+    pub fn close(&mut self) -> AccountDomainResult<()> {
+        if self.locked {
+            return AccountDomainResult::Err(AccountErrors::AccountLocked);
+        }
 
-            if !self.in_dispute.remove(&transaction_id) {
-                AccountDomainResult::Err(AccountErrors::TransactionNotFound)
+        if self.closed {
+            return AccountDomainResult::Err(AccountErrors::AccountClosed);
+        }
+
+        let events = vec![AllEvents::AccountClosed {
+            account_id: self.id,
+        }];
+        self.emit(events)
+    }
+
+    fn balances_after_deposit(&self, amount: Money) -> Result<Balances, AccountErrors> {
+        Ok(Balances {
+            available: self.available.checked_add(amount)?,
+            held: self.held,
+            total: self.total.checked_add(amount)?,
+        })
+    }
+
+    fn balances_after_withdraw(&self, amount: Money) -> Result<Balances, AccountErrors> {
+        let available = self.available.checked_sub(amount)?;
+        if available.is_negative() {
+            return Err(AccountErrors::NegativeAmount);
+        }
+
+        Ok(Balances {
+            available,
+            held: self.held,
+            total: self.total.checked_sub(amount)?,
+        })
+    }
+
+    fn balances_after_dispute(
+        &self,
+        treatment: DisputeTreatment,
+        amount: Money,
+    ) -> Result<Balances, AccountErrors> {
+        match treatment {
+            DisputeTreatment::Hold => Ok(Balances {
+                available: self.available.checked_sub(amount)?,
+                held: self.held.checked_add(amount)?,
+                total: self.total,
+            }),
+            DisputeTreatment::ReverseHold => Ok(Balances {
+                available: self.available,
+                held: self.held.checked_add(amount)?,
+                total: self.total.checked_add(amount)?,
+            }),
+        }
+    }
+
+    fn balances_after_resolve(
+        &self,
+        treatment: DisputeTreatment,
+        amount: Money,
+    ) -> Result<Balances, AccountErrors> {
+        match treatment {
+            DisputeTreatment::Hold => Ok(Balances {
+                available: self.available.checked_add(amount)?,
+                held: self.held.checked_sub(amount)?,
+                total: self.total,
+            }),
+            DisputeTreatment::ReverseHold => Ok(Balances {
+                available: self.available,
+                held: self.held.checked_sub(amount)?,
+                total: self.total.checked_sub(amount)?,
+            }),
+        }
+    }
+
+    fn balances_after_chargeback(
+        &self,
+        treatment: DisputeTreatment,
+        amount: Money,
+    ) -> Result<Balances, AccountErrors> {
+        match treatment {
+            DisputeTreatment::Hold => Ok(Balances {
+                available: self.available,
+                held: self.held.checked_sub(amount)?,
+                total: self.total.checked_sub(amount)?,
+            }),
+            DisputeTreatment::ReverseHold => Ok(Balances {
+                available: self.available.checked_add(amount)?,
+                held: self.held.checked_sub(amount)?,
+                total: self.total,
+            }),
+        }
+    }
+
+    fn emit(&mut self, events: Vec<AllEvents>) -> AccountDomainResult<()> {
+        for event in events.iter() {
+            self.apply(event);
+        }
+        AccountDomainResult::Ok { data: (), events }
+    }
+
+    // Replaying an already applied transaction is acknowledged without
+    // changing anything. Reusing its id for anything else is an error.
+    fn check_replay(
+        &self,
+        transaction_id: u32,
+        kind: TransactionKind,
+        amount: Money,
+    ) -> Option<AccountDomainResult<()>> {
+        self.transactions.get(&transaction_id).map(|transaction| {
+            if transaction.kind == kind && transaction.amount == amount {
+                AccountDomainResult::Ok {
+                    data: (),
+                    events: vec![],
+                }
             } else {
-                self.locked = true;
-                self.raise_account_updated(&mut events, transaction_id);
-                AccountDomainResult::Ok { data: (), events }
+                AccountDomainResult::Err(AccountErrors::DuplicateTransactionId)
             }
+        })
+    }
+
+    // Validates the transition without changing anything.
+    fn next_state(
+        &self,
+        transaction_id: u32,
+        operation: TransactionOperation,
+    ) -> Result<(DisputeTreatment, Money), AccountErrors> {
+        let transaction = self
+            .transactions
+            .get(&transaction_id)
+            .ok_or(AccountErrors::TransactionNotFound)?;
+        transaction.state.transition(operation)?;
+        let treatment = self
+            .dispute_policy
+            .treatment(transaction.kind)
+            .ok_or(AccountErrors::DisputeNotAllowed)?;
+        Ok((treatment, transaction.amount))
+    }
+
+    fn set_state(&mut self, transaction_id: u32, state: TransactionState) {
+        if let Some(transaction) = self.transactions.get_mut(&transaction_id) {
+            transaction.state = state;
         }
     }
 
-    fn raise_account_updated(&self, events: &mut Vec<AllEvents>, transaction_id: u32) {
-        let held = self
-            .in_dispute
-            .iter()
-            .filter_map(|x| self.ammounts.get(x))
-            .fold(Decimal::ZERO, |l, r| l + *r);
+    fn set_balances(&mut self, balances: &AccountBalances) {
+        let currency = self.total.currency;
+        self.available = balances.available * currency;
+        self.held = balances.held * currency;
+        self.total = balances.total * currency;
+        self.check_invariants();
+    }
 
-        events.push(AllEvents::AccountUpdated {
-            account_id: self.id,
-            transaction_id,
-            amount: self.amount.into(),
-            held,
-            locked: self.locked,
-        });
+    fn check_invariants(&self) {
+        debug_assert!(
+            self.available.checked_add(self.held).ok() == Some(self.total),
+            "total must be available + held: {:?}",
+            self
+        );
+        debug_assert!(
+            !self.held.is_negative(),
+            "held cannot be negative: {:?}",
+            self
+        );
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::domain::dispute_policy::DepositsAndWithdrawals;
     use crate::domain::money::Currency::Bitcoin;
     use crate::quicktest_utils::*;
+    use quickcheck::{Arbitrary, Gen};
     use quickcheck_macros::*;
+    use rust_decimal::Decimal;
 
     #[test]
     pub fn ok_deposit() {
         let mut account = Account::new(0);
         account.deposit(0, 1 * Bitcoin).unwrap();
-        assert!(account.amount == 1);
+        assert!(account.available == 1);
     }
 
     #[quickcheck]
@@ -175,7 +633,7 @@ mod tests {
         account.deposit(0, amount * Bitcoin).unwrap();
         account.withdraw(1, amount * Bitcoin).unwrap();
 
-        account.amount.is_zero()
+        account.available.is_zero()
     }
 
     #[quickcheck]
@@ -184,7 +642,7 @@ mod tests {
         account.deposit(0, values.big * Bitcoin).unwrap();
         account.withdraw(1, values.small * Bitcoin).unwrap();
 
-        account.amount.is_positive()
+        account.available.is_positive()
     }
 
     #[quickcheck]
@@ -201,13 +659,13 @@ mod tests {
         let mut account = Account::new(0);
 
         account.deposit(0, 1 * Bitcoin).unwrap();
-        assert!(account.amount.as_decimal() == Decimal::ONE);
+        assert!(account.available.as_decimal() == Decimal::ONE);
 
         account.dispute(0);
-        assert!(account.amount.is_zero());
+        assert!(account.available.is_zero());
 
         account.resolve(0);
-        assert!(account.amount.as_decimal() == Decimal::ONE);
+        assert!(account.available.as_decimal() == Decimal::ONE);
     }
 
     #[test]
@@ -215,13 +673,13 @@ mod tests {
         let mut account = Account::new(0);
 
         account.deposit(0, 1 * Bitcoin).unwrap();
-        assert!(account.amount.as_decimal() == Decimal::ONE);
+        assert!(account.available.as_decimal() == Decimal::ONE);
 
         account.dispute(0);
-        assert!(account.amount.is_zero());
+        assert!(account.available.is_zero());
 
         account.chargeback(0);
-        assert!(account.amount.is_zero());
+        assert!(account.available.is_zero());
         assert!(account.locked);
 
         // Locked account. Cannot do anything
@@ -247,5 +705,416 @@ mod tests {
         ));
     }
 
-    //TODO test events
+    #[test]
+    fn ok_rewind_restores_transactions() {
+        let mut rewound = Account::new(0);
+        rewound.deposit(0, 1 * Bitcoin).unwrap();
+
+        let mut account = rewound.clone();
+        account.dispute(0).unwrap();
+
+        let parts = rewound.to_parts();
+        account.apply(&AllEvents::HistoryRewound {
+            account_id: 0,
+            transaction_id: 0,
+            balances: rewound.balances(),
+            transactions: parts.transactions,
+            locked: false,
+            closed: false,
+        });
+
+        // The dispute happened after the rewind point
+        assert!(account.held() == 0);
+        assert!(matches!(account.dispute(0), DomainResult::Ok { .. }));
+    }
+
+    #[test]
+    fn ok_dispute_holds_only_disputed_amount() {
+        let mut account = Account::new(0);
+
+        account.deposit(0, 1 * Bitcoin).unwrap();
+        account.deposit(1, 2 * Bitcoin).unwrap();
+
+        let events = account.dispute(0).unwrap_events();
+        assert!(account.available() == 2);
+        assert!(account.held() == 1);
+        assert!(account.total() == 3);
+        assert!(account.get_transaction(0).unwrap().state == TransactionState::Disputed);
+        assert!(matches!(
+            events[..],
+            [AllEvents::DisputeOpened { amount, balances, .. }]
+                if amount == Decimal::ONE && balances.held == Decimal::ONE
+        ));
+    }
+
+    #[quickcheck]
+    fn doundo_dispute_resolve(values: BigSmall<u64>) -> bool {
+        let mut account = Account::new(0);
+        account.deposit(0, values.big * Bitcoin).unwrap();
+        account.deposit(1, values.small * Bitcoin).unwrap();
+        let before = account.available;
+
+        account.dispute(1).unwrap();
+        account.resolve(1).unwrap();
+
+        account.available == before
+            && account.get_transaction(1).unwrap().state == TransactionState::Resolved
+    }
+
+    #[quickcheck]
+    fn ok_dispute_chargeback_removes_disputed_amount(values: BigSmall<u64>) -> bool {
+        let mut account = Account::new(0);
+        account.deposit(0, values.big * Bitcoin).unwrap();
+        account.deposit(1, values.small * Bitcoin).unwrap();
+
+        account.dispute(1).unwrap();
+        account.chargeback(1).unwrap();
+
+        account.available == values.big
+            && account.locked
+            && account.get_transaction(1).unwrap().state == TransactionState::ChargedBack
+    }
+
+    #[test]
+    fn err_illegal_dispute_transitions() {
+        let mut account = Account::new(0);
+        account.deposit(0, 1 * Bitcoin).unwrap();
+
+        assert!(matches!(
+            account.dispute(1),
+            DomainResult::Err(AccountErrors::TransactionNotFound)
+        ));
+        assert!(matches!(
+            account.resolve(0),
+            DomainResult::Err(AccountErrors::NotDisputed)
+        ));
+        assert!(matches!(
+            account.chargeback(0),
+            DomainResult::Err(AccountErrors::NotDisputed)
+        ));
+
+        account.dispute(0).unwrap();
+        assert!(matches!(
+            account.dispute(0),
+            DomainResult::Err(AccountErrors::AlreadyDisputed)
+        ));
+        assert!(account.available().is_zero());
+        assert!(account.held() == 1);
+    }
+
+    #[test]
+    fn ok_replayed_transaction_is_acknowledged() {
+        let mut account = Account::new(0);
+        account.deposit(0, 2 * Bitcoin).unwrap();
+        account.withdraw(1, 1 * Bitcoin).unwrap();
+
+        assert!(account.deposit(0, 2 * Bitcoin).unwrap_events().is_empty());
+        assert!(account.withdraw(1, 1 * Bitcoin).unwrap_events().is_empty());
+        assert!(account.available() == 1);
+    }
+
+    #[test]
+    fn err_conflicting_transaction_id() {
+        let mut account = Account::new(0);
+        account.deposit(0, 2 * Bitcoin).unwrap();
+
+        assert!(matches!(
+            account.deposit(0, 1 * Bitcoin),
+            DomainResult::Err(AccountErrors::DuplicateTransactionId)
+        ));
+        assert!(matches!(
+            account.withdraw(0, 2 * Bitcoin),
+            DomainResult::Err(AccountErrors::DuplicateTransactionId)
+        ));
+        assert!(account.available() == 2);
+    }
+
+    #[test]
+    fn deposits_only_policy() {
+        let mut account = Account::with_dispute_policy(0, Arc::new(DepositsOnly));
+        account.deposit(0, 3 * Bitcoin).unwrap();
+        account.withdraw(1, 1 * Bitcoin).unwrap();
+
+        assert!(matches!(
+            account.dispute(1),
+            DomainResult::Err(AccountErrors::DisputeNotAllowed)
+        ));
+        assert!(account.available() == 2 && account.held() == 0 && account.total() == 2);
+
+        // Funds already withdrawn make "available" negative
+        account.dispute(0).unwrap();
+        assert!(account.available().is_negative());
+        assert!(account.held() == 3 && account.total() == 2);
+    }
+
+    #[test]
+    fn deposits_and_withdrawals_policy_resolve() {
+        let mut account = Account::with_dispute_policy(0, Arc::new(DepositsAndWithdrawals));
+        account.deposit(0, 3 * Bitcoin).unwrap();
+        account.withdraw(1, 1 * Bitcoin).unwrap();
+
+        // The withdrawn amount is held as a pending credit
+        account.dispute(1).unwrap();
+        assert!(account.available() == 2 && account.held() == 1 && account.total() == 3);
+
+        // The withdrawal stands
+        account.resolve(1).unwrap();
+        assert!(account.available() == 2 && account.held() == 0 && account.total() == 2);
+    }
+
+    #[test]
+    fn deposits_and_withdrawals_policy_chargeback() {
+        let mut account = Account::with_dispute_policy(0, Arc::new(DepositsAndWithdrawals));
+        account.deposit(0, 3 * Bitcoin).unwrap();
+        account.withdraw(1, 1 * Bitcoin).unwrap();
+
+        // The withdrawal is reversed
+        account.dispute(1).unwrap();
+        account.chargeback(1).unwrap();
+        assert!(account.available() == 3 && account.held() == 0 && account.total() == 3);
+        assert!(account.is_locked());
+    }
+
+    #[derive(Debug)]
+    struct NoDisputes;
+
+    impl DisputePolicy for NoDisputes {
+        fn treatment(&self, _: TransactionKind) -> Option<DisputeTreatment> {
+            None
+        }
+    }
+
+    #[test]
+    fn custom_policy() {
+        let mut account = Account::with_dispute_policy(0, Arc::new(NoDisputes));
+        account.deposit(0, 3 * Bitcoin).unwrap();
+
+        assert!(matches!(
+            account.dispute(0),
+            DomainResult::Err(AccountErrors::DisputeNotAllowed)
+        ));
+    }
+
+    #[test]
+    fn ok_freeze_unfreeze() {
+        let mut account = Account::new(0);
+        account.deposit(0, 1 * Bitcoin).unwrap();
+
+        let events = account.freeze("fraud investigation".into()).unwrap_events();
+        assert!(matches!(events[..], [AllEvents::AccountFrozen { .. }]));
+        assert!(matches!(
+            account.withdraw(1, 1 * Bitcoin),
+            DomainResult::Err(AccountErrors::AccountLocked)
+        ));
+
+        let events = account.unfreeze("cleared".into()).unwrap_events();
+        assert!(matches!(events[..], [AllEvents::AccountUnfrozen { .. }]));
+        assert!(matches!(
+            account.unfreeze("cleared".into()),
+            DomainResult::Err(AccountErrors::AccountNotLocked)
+        ));
+        account.withdraw(1, 1 * Bitcoin).unwrap();
+    }
+
+    #[test]
+    fn ok_unfreeze_after_chargeback() {
+        let mut account = Account::new(0);
+        account.deposit(0, 1 * Bitcoin).unwrap();
+        account.dispute(0).unwrap();
+        account.chargeback(0).unwrap();
+
+        account.unfreeze("chargeback reviewed".into()).unwrap();
+        assert!(matches!(
+            account.dispute(0),
+            DomainResult::Err(AccountErrors::AlreadyChargedBack)
+        ));
+        account.deposit(1, 1 * Bitcoin).unwrap();
+    }
+
+    #[test]
+    fn ok_close_allows_final_withdraw() {
+        let mut account = Account::new(0);
+        account.deposit(0, 2 * Bitcoin).unwrap();
+
+        let events = account.close().unwrap_events();
+        assert!(matches!(events[..], [AllEvents::AccountClosed { .. }]));
+        assert!(account.is_closed());
+
+        assert!(matches!(
+            account.deposit(1, 1 * Bitcoin),
+            DomainResult::Err(AccountErrors::AccountClosed)
+        ));
+        account.withdraw(2, 2 * Bitcoin).unwrap();
+        assert!(account.total().is_zero());
+    }
+
+    #[test]
+    fn ok_events() {
+        let mut account = Account::new(0);
+
+        let events = account.deposit(0, 2 * Bitcoin).unwrap_events();
+        assert!(matches!(
+            events[..],
+            [AllEvents::Deposited { transaction_id: 0, amount, balances, .. }]
+                if amount == Decimal::TWO && balances.total == Decimal::TWO
+        ));
+
+        let events = account.withdraw(1, 1 * Bitcoin).unwrap_events();
+        assert!(matches!(
+            events[..],
+            [AllEvents::Withdrawn { transaction_id: 1, amount, balances, .. }]
+                if amount == Decimal::ONE && balances.available == Decimal::ONE
+        ));
+
+        account.dispute(0).unwrap();
+        let events = account.chargeback(0).unwrap_events();
+        assert!(matches!(
+            events[..],
+            [
+                AllEvents::ChargedBack { transaction_id: 0, balances, .. },
+                AllEvents::AccountLocked { transaction_id: 0, .. }
+            ] if balances.held.is_zero() && balances.total == Decimal::NEGATIVE_ONE
+        ));
+    }
+
+    #[derive(Clone, Debug)]
+    enum Command {
+        Deposit(u32, u32),
+        Withdraw(u32, u32),
+        Dispute(u32),
+        Resolve(u32),
+        Chargeback(u32),
+        Unfreeze,
+    }
+
+    impl Arbitrary for Command {
+        fn arbitrary(g: &mut Gen) -> Self {
+            // Few transaction ids, so commands touch the same transactions
+            let transaction_id = u32::arbitrary(g) % 8;
+            match u8::arbitrary(g) % 6 {
+                0 => Command::Deposit(transaction_id, u32::arbitrary(g)),
+                1 => Command::Withdraw(transaction_id, u32::arbitrary(g)),
+                2 => Command::Dispute(transaction_id),
+                3 => Command::Resolve(transaction_id),
+                4 => Command::Chargeback(transaction_id),
+                _ => Command::Unfreeze,
+            }
+        }
+    }
+
+    fn run(account: &mut Account, command: Command) -> AccountDomainResult<()> {
+        match command {
+            Command::Deposit(id, amount) => account.deposit(id, amount as u64 * Bitcoin),
+            Command::Withdraw(id, amount) => account.withdraw(id, amount as u64 * Bitcoin),
+            Command::Dispute(id) => account.dispute(id),
+            Command::Resolve(id) => account.resolve(id),
+            Command::Chargeback(id) => account.chargeback(id),
+            Command::Unfreeze => account.unfreeze("".into()),
+        }
+    }
+
+    // What an account should end up with, written without events
+    // and in plain integers, with the rules of `DepositsAndWithdrawals`.
+    #[derive(Default)]
+    struct Model {
+        available: i128,
+        held: i128,
+        total: i128,
+        locked: bool,
+        transactions: BTreeMap<u32, (TransactionKind, i128, TransactionState)>,
+    }
+
+    impl Model {
+        // Whether the command is accepted.
+        fn run(&mut self, command: Command) -> bool {
+            use TransactionKind::*;
+            use TransactionState::*;
+            match command {
+                Command::Deposit(id, amount) | Command::Withdraw(id, amount) => {
+                    let kind = match command {
+                        Command::Deposit(..) => Deposit,
+                        _ => Withdraw,
+                    };
+                    let amount = amount as i128;
+                    if let Some(transaction) = self.transactions.get(&id) {
+                        return transaction.0 == kind && transaction.1 == amount;
+                    }
+                    if self.locked || (kind == Withdraw && self.available < amount) {
+                        return false;
+                    }
+                    let amount = if kind == Deposit { amount } else { -amount };
+                    self.available += amount;
+                    self.total += amount;
+                    self.transactions.insert(id, (kind, amount.abs(), Normal));
+                }
+                Command::Dispute(id) | Command::Resolve(id) | Command::Chargeback(id) => {
+                    let (kind, amount, state) = match self.transactions.get(&id) {
+                        Some(transaction) if !self.locked => *transaction,
+                        _ => return false,
+                    };
+                    let next = match (&command, state) {
+                        (Command::Dispute(_), Normal | Resolved) => Disputed,
+                        (Command::Resolve(_), Disputed) => Resolved,
+                        (Command::Chargeback(_), Disputed) => ChargedBack,
+                        _ => return false,
+                    };
+                    // Deposits leave "available"; withdrawals come back into "total"
+                    let (available, total) = match (kind, next) {
+                        (Deposit, Disputed) => (-amount, 0),
+                        (Deposit, Resolved) => (amount, 0),
+                        (Deposit, _) => (0, -amount),
+                        (Withdraw, Disputed) => (0, amount),
+                        (Withdraw, Resolved) => (0, -amount),
+                        (Withdraw, _) => (amount, 0),
+                    };
+                    self.available += available;
+                    self.total += total;
+                    self.held = self.total - self.available;
+                    self.locked = next == ChargedBack;
+                    self.transactions.get_mut(&id).unwrap().2 = next;
+                }
+                Command::Unfreeze => {
+                    if !self.locked {
+                        return false;
+                    }
+                    self.locked = false;
+                }
+            }
+            true
+        }
+    }
+
+    #[quickcheck]
+    fn replaying_events_rebuilds_account(commands: Vec<Command>) -> bool {
+        let policy: Arc<dyn DisputePolicy> = Arc::new(DepositsAndWithdrawals);
+        let mut account = Account::with_dispute_policy(0, policy.clone());
+        let mut model = Model::default();
+
+        let mut events = vec![];
+        for command in commands {
+            let accepted = model.run(command.clone());
+            match run(&mut account, command) {
+                DomainResult::Ok { events: new, .. } if accepted => events.extend(new),
+                DomainResult::Err(_) if !accepted => {}
+                _ => return false,
+            }
+        }
+
+        let replayed = Account::from_events(0, policy, events.iter());
+        let transactions: BTreeMap<_, _> = replayed
+            .transactions
+            .iter()
+            .map(|(id, x)| (*id, (x.kind, x.amount.as_decimal(), x.state)))
+            .collect();
+        let expected: BTreeMap<_, _> = model
+            .transactions
+            .iter()
+            .map(|(id, (kind, amount, state))| (*id, (*kind, Decimal::from(*amount), *state)))
+            .collect();
+        replayed.available().as_decimal() == Decimal::from(model.available)
+            && replayed.held().as_decimal() == Decimal::from(model.held)
+            && replayed.total().as_decimal() == Decimal::from(model.total)
+            && replayed.locked == model.locked
+            && transactions == expected
+    }
 }
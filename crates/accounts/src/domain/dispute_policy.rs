@@ -0,0 +1,42 @@
+use super::transaction::TransactionKind;
+
+// How balances move while a transaction is disputed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DisputeTreatment {
+    // The transacted amount leaves "available" and is held.
+    // A chargeback removes it from the account.
+    Hold,
+    // The transacted amount already left the account, so it is held
+    // as a pending credit. A chargeback gives it back to "available".
+    ReverseHold,
+}
+
+// Decides which transactions can be disputed and how.
+// Returning `None` rejects the dispute.
+pub trait DisputePolicy: std::fmt::Debug + Send + Sync {
+    fn treatment(&self, kind: TransactionKind) -> Option<DisputeTreatment>;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DepositsOnly;
+
+impl DisputePolicy for DepositsOnly {
+    fn treatment(&self, kind: TransactionKind) -> Option<DisputeTreatment> {
+        match kind {
+            TransactionKind::Deposit => Some(DisputeTreatment::Hold),
+            TransactionKind::Withdraw => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DepositsAndWithdrawals;
+
+impl DisputePolicy for DepositsAndWithdrawals {
+    fn treatment(&self, kind: TransactionKind) -> Option<DisputeTreatment> {
+        match kind {
+            TransactionKind::Deposit => Some(DisputeTreatment::Hold),
+            TransactionKind::Withdraw => Some(DisputeTreatment::ReverseHold),
+        }
+    }
+}
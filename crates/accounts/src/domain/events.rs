@@ -1,12 +1,152 @@
+use std::collections::BTreeMap;
+
 use rust_decimal::Decimal;
 
+use super::{account::AccountErrors, transaction::Transaction};
+
+// Balances of the account after the event was applied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccountBalances {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OperationKind {
+    Deposit,
+    Withdraw,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Freeze,
+    Unfreeze,
+    Close,
+}
+
+// The variant of an event, without its fields.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Deposited,
+    Withdrawn,
+    DisputeOpened,
+    DisputeResolved,
+    ChargedBack,
+    AccountLocked,
+    OperationRejected,
+    AccountFrozen,
+    AccountUnfrozen,
+    AccountClosed,
+    HistoryRewound,
+    OutcomeChanged,
+}
+
 #[derive(Clone, Debug)]
 pub enum AllEvents {
-    AccountUpdated {
+    Deposited {
+        account_id: u32,
+        transaction_id: u32,
+        amount: Decimal,
+        balances: AccountBalances,
+    },
+    Withdrawn {
+        account_id: u32,
+        transaction_id: u32,
+        amount: Decimal,
+        balances: AccountBalances,
+    },
+    DisputeOpened {
         account_id: u32,
         transaction_id: u32,
         amount: Decimal,
-        held: Decimal,
+        balances: AccountBalances,
+    },
+    DisputeResolved {
+        account_id: u32,
+        transaction_id: u32,
+        amount: Decimal,
+        balances: AccountBalances,
+    },
+    ChargedBack {
+        account_id: u32,
+        transaction_id: u32,
+        amount: Decimal,
+        balances: AccountBalances,
+    },
+    AccountLocked {
+        account_id: u32,
+        transaction_id: u32,
+    },
+    OperationRejected {
+        account_id: u32,
+        transaction_id: Option<u32>,
+        operation: OperationKind,
+        error: AccountErrors,
+    },
+    AccountFrozen {
+        account_id: u32,
+        reason: String,
+    },
+    AccountUnfrozen {
+        account_id: u32,
+        reason: String,
+    },
+    AccountClosed {
+        account_id: u32,
+    },
+    // A late request was inserted before `transaction_id`. Everything raised
+    // after that point is superseded by the events that follow this one,
+    // which start from the account as it is here.
+    HistoryRewound {
+        account_id: u32,
+        transaction_id: u32,
+        balances: AccountBalances,
+        transactions: BTreeMap<u32, Transaction>,
         locked: bool,
+        closed: bool,
     },
+    // Re-executed after a late request, an operation was accepted where it
+    // had been rejected or the other way around. `error` is the new outcome.
+    OutcomeChanged {
+        account_id: u32,
+        transaction_id: Option<u32>,
+        operation: OperationKind,
+        error: Option<AccountErrors>,
+    },
+}
+
+impl AllEvents {
+    pub fn get_account_id(&self) -> u32 {
+        match self {
+            AllEvents::Deposited { account_id, .. }
+            | AllEvents::Withdrawn { account_id, .. }
+            | AllEvents::DisputeOpened { account_id, .. }
+            | AllEvents::DisputeResolved { account_id, .. }
+            | AllEvents::ChargedBack { account_id, .. }
+            | AllEvents::AccountLocked { account_id, .. }
+            | AllEvents::OperationRejected { account_id, .. }
+            | AllEvents::AccountFrozen { account_id, .. }
+            | AllEvents::AccountUnfrozen { account_id, .. }
+            | AllEvents::AccountClosed { account_id }
+            | AllEvents::HistoryRewound { account_id, .. }
+            | AllEvents::OutcomeChanged { account_id, .. } => *account_id,
+        }
+    }
+
+    pub fn kind(&self) -> EventKind {
+        match self {
+            AllEvents::Deposited { .. } => EventKind::Deposited,
+            AllEvents::Withdrawn { .. } => EventKind::Withdrawn,
+            AllEvents::DisputeOpened { .. } => EventKind::DisputeOpened,
+            AllEvents::DisputeResolved { .. } => EventKind::DisputeResolved,
+            AllEvents::ChargedBack { .. } => EventKind::ChargedBack,
+            AllEvents::AccountLocked { .. } => EventKind::AccountLocked,
+            AllEvents::OperationRejected { .. } => EventKind::OperationRejected,
+            AllEvents::AccountFrozen { .. } => EventKind::AccountFrozen,
+            AllEvents::AccountUnfrozen { .. } => EventKind::AccountUnfrozen,
+            AllEvents::AccountClosed { .. } => EventKind::AccountClosed,
+            AllEvents::HistoryRewound { .. } => EventKind::HistoryRewound,
+            AllEvents::OutcomeChanged { .. } => EventKind::OutcomeChanged,
+        }
+    }
 }
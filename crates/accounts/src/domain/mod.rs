@@ -1,6 +1,8 @@
 pub mod account;
+pub mod dispute_policy;
 pub mod events;
 pub mod money;
+pub mod transaction;
 
 // A mix of the Result (Either) with Writer monad
 // https://adit.io/posts/2013-06-10-three-useful-monads.html
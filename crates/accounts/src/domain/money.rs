@@ -63,7 +63,7 @@ impl From<Money> for rust_decimal::Decimal {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum MoneyErrors {
     MismatchedCurrencies,
     Overflow,
@@ -0,0 +1,102 @@
+use super::{account::AccountErrors, money::Money};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransactionKind {
+    Deposit,
+    Withdraw,
+}
+
+// Lifecycle of a transaction:
+//
+//  Normal ──dispute──▶ Disputed ──chargeback──▶ ChargedBack
+//                       ▲    │
+//                dispute│    │resolve
+//                       │    ▼
+//                      Resolved
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransactionState {
+    Normal,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransactionOperation {
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+impl TransactionState {
+    pub fn transition(self, operation: TransactionOperation) -> Result<Self, AccountErrors> {
+        use TransactionOperation::*;
+        use TransactionState::*;
+        match (self, operation) {
+            (ChargedBack, _) => Err(AccountErrors::AlreadyChargedBack),
+
+            (Normal | Resolved, Dispute) => Ok(Disputed),
+            (Disputed, Dispute) => Err(AccountErrors::AlreadyDisputed),
+
+            (Disputed, Resolve) => Ok(Resolved),
+            (Disputed, Chargeback) => Ok(ChargedBack),
+            (Normal | Resolved, Resolve | Chargeback) => Err(AccountErrors::NotDisputed),
+        }
+    }
+}
+
+// One entry of the account ledger. We always keep the original amount
+// of the transaction, so disputes move exactly what was transacted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transaction {
+    pub kind: TransactionKind,
+    pub amount: Money,
+    pub state: TransactionState,
+}
+
+impl Transaction {
+    pub fn new(kind: TransactionKind, amount: Money) -> Self {
+        Self {
+            kind,
+            amount,
+            state: TransactionState::Normal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TransactionOperation::*;
+    use super::TransactionState::*;
+    use super::*;
+
+    #[test]
+    fn ok_legal_transitions() {
+        assert_eq!(Normal.transition(Dispute).unwrap(), Disputed);
+        assert_eq!(Disputed.transition(Resolve).unwrap(), Resolved);
+        assert_eq!(Disputed.transition(Chargeback).unwrap(), ChargedBack);
+        assert_eq!(Resolved.transition(Dispute).unwrap(), Disputed);
+    }
+
+    #[test]
+    fn err_illegal_transitions() {
+        assert!(matches!(
+            Disputed.transition(Dispute),
+            Err(AccountErrors::AlreadyDisputed)
+        ));
+        assert!(matches!(
+            Normal.transition(Resolve),
+            Err(AccountErrors::NotDisputed)
+        ));
+        assert!(matches!(
+            Resolved.transition(Chargeback),
+            Err(AccountErrors::NotDisputed)
+        ));
+        for operation in [Dispute, Resolve, Chargeback] {
+            assert!(matches!(
+                ChargedBack.transition(operation),
+                Err(AccountErrors::AlreadyChargedBack)
+            ));
+        }
+    }
+}
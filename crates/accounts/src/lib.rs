@@ -5,3 +5,4 @@ mod quicktest_utils;
 pub mod actors;
 pub mod broadcast;
 pub mod domain;
+pub mod store;
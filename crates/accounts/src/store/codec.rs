@@ -0,0 +1,281 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use rust_decimal::Decimal;
+
+use crate::domain::{
+    account::AccountParts,
+    dispute_policy::DisputePolicy,
+    money::{Currency, Money},
+    transaction::{Transaction, TransactionKind, TransactionState},
+};
+
+use super::StoreError;
+
+// Binary format of an account. All integers are little endian.
+//
+//  version     u8
+//  id          u32
+//  flags       u8      (1 = locked, 2 = closed)
+//  sequence    u64     (since version 2)
+//  currency    u8      (0 = Bitcoin, 1 = Other followed by its code as u64)
+//  available   [u8; 16]
+//  held        [u8; 16]
+//  total       [u8; 16]
+//  count       u32
+//  count times:
+//      id      u32
+//      kind    u8
+//      state   u8
+//      amount  [u8; 16]
+//
+// Amounts are serialized `Decimal`s in the account currency.
+// Version 1 accounts had no sequence; their events start at 0.
+pub const VERSION: u8 = 2;
+
+const LOCKED: u8 = 1;
+const CLOSED: u8 = 2;
+
+pub fn encode(parts: &AccountParts) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(64 + parts.transactions.len() * 22);
+    bytes.push(VERSION);
+    bytes.extend_from_slice(&parts.id.to_le_bytes());
+
+    let mut flags = 0;
+    if parts.locked {
+        flags |= LOCKED;
+    }
+    if parts.closed {
+        flags |= CLOSED;
+    }
+    bytes.push(flags);
+    bytes.extend_from_slice(&parts.sequence.to_le_bytes());
+
+    put_currency(&mut bytes, parts.total.currency);
+    bytes.extend_from_slice(&parts.available.amount.serialize());
+    bytes.extend_from_slice(&parts.held.amount.serialize());
+    bytes.extend_from_slice(&parts.total.amount.serialize());
+
+    bytes.extend_from_slice(&(parts.transactions.len() as u32).to_le_bytes());
+    for (id, transaction) in parts.transactions.iter() {
+        bytes.extend_from_slice(&id.to_le_bytes());
+        bytes.push(match transaction.kind {
+            TransactionKind::Deposit => 0,
+            TransactionKind::Withdraw => 1,
+        });
+        bytes.push(match transaction.state {
+            TransactionState::Normal => 0,
+            TransactionState::Disputed => 1,
+            TransactionState::Resolved => 2,
+            TransactionState::ChargedBack => 3,
+        });
+        bytes.extend_from_slice(&transaction.amount.amount.serialize());
+    }
+
+    bytes
+}
+
+pub fn decode(bytes: &[u8]) -> Result<AccountParts, StoreError> {
+    let mut reader = Reader::new(bytes);
+
+    let version = reader.u8()?;
+    if version != 1 && version != VERSION {
+        return Err(StoreError::UnsupportedVersion(version));
+    }
+
+    let id = reader.u32()?;
+    let flags = reader.u8()?;
+    let sequence = if version == 1 { 0 } else { reader.u64()? };
+    let currency = reader.currency()?;
+    let available = reader.decimal()? * currency;
+    let held = reader.decimal()? * currency;
+    let total = reader.decimal()? * currency;
+
+    let count = reader.u32()?;
+    let mut transactions = BTreeMap::new();
+    for _ in 0..count {
+        let transaction_id = reader.u32()?;
+        let kind = match reader.u8()? {
+            0 => TransactionKind::Deposit,
+            1 => TransactionKind::Withdraw,
+            tag => return Err(corrupted("transaction kind", tag)),
+        };
+        let state = match reader.u8()? {
+            0 => TransactionState::Normal,
+            1 => TransactionState::Disputed,
+            2 => TransactionState::Resolved,
+            3 => TransactionState::ChargedBack,
+            tag => return Err(corrupted("transaction state", tag)),
+        };
+        let amount: Money = reader.decimal()? * currency;
+        transactions.insert(
+            transaction_id,
+            Transaction {
+                kind,
+                amount,
+                state,
+            },
+        );
+    }
+
+    reader.end()?;
+
+    Ok(AccountParts {
+        id,
+        available,
+        held,
+        total,
+        transactions,
+        locked: flags & LOCKED != 0,
+        closed: flags & CLOSED != 0,
+        sequence,
+    })
+}
+
+pub(crate) fn corrupted(what: &str, tag: u8) -> StoreError {
+    StoreError::Corrupted(format!("unknown {} {}", what, tag))
+}
+
+pub(crate) fn put_currency(bytes: &mut Vec<u8>, currency: Currency) {
+    match currency {
+        Currency::Bitcoin => bytes.push(0),
+        Currency::Other { code } => {
+            bytes.push(1);
+            bytes.extend_from_slice(&code.to_le_bytes());
+        }
+    }
+}
+
+// Reads what the encoders here, in the journal and on the wire write.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    // Given to the accounts read, which do not carry theirs
+    dispute_policy: Option<Arc<dyn DisputePolicy>>,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            dispute_policy: None,
+        }
+    }
+
+    pub fn with_dispute_policy(mut self, dispute_policy: Arc<dyn DisputePolicy>) -> Self {
+        self.dispute_policy = Some(dispute_policy);
+        self
+    }
+
+    pub fn dispute_policy(&self) -> Result<Arc<dyn DisputePolicy>, StoreError> {
+        self.dispute_policy
+            .clone()
+            .ok_or_else(|| StoreError::Corrupted("no dispute policy to read accounts".into()))
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], StoreError> {
+        let bytes = self.take_slice(N)?;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    pub fn take_slice(&mut self, len: usize) -> Result<&'a [u8], StoreError> {
+        if self.bytes.len() < len {
+            return Err(StoreError::Corrupted("truncated record".into()));
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub fn end(&self) -> Result<(), StoreError> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(StoreError::Corrupted(format!(
+                "{} unexpected trailing bytes",
+                self.bytes.len()
+            )))
+        }
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StoreError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StoreError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StoreError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    pub fn decimal(&mut self) -> Result<Decimal, StoreError> {
+        Ok(Decimal::deserialize(self.take()?))
+    }
+
+    pub fn currency(&mut self) -> Result<Currency, StoreError> {
+        match self.u8()? {
+            0 => Ok(Currency::Bitcoin),
+            1 => Ok(Currency::Other { code: self.u64()? }),
+            tag => Err(corrupted("currency", tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        domain::{account::Account, money::Currency::*},
+        store::StoreError,
+    };
+
+    use super::{decode, encode};
+
+    fn account() -> Account {
+        let mut account = Account::new(7);
+        let _ = account.deposit(1, 10 * Bitcoin);
+        let _ = account.deposit(2, 0.25 * Bitcoin);
+        let _ = account.withdraw(3, 1.5 * Bitcoin);
+        let _ = account.dispute(1);
+        let _ = account.resolve(1);
+        let _ = account.dispute(2);
+        let _ = account.chargeback(2);
+        account
+    }
+
+    #[test]
+    fn ok_encode_decode_roundtrip() {
+        let mut account = account();
+        account.set_sequence(42);
+        let parts = account.to_parts();
+        assert!(parts.locked);
+        assert_eq!(decode(&encode(&parts)).unwrap(), parts);
+    }
+
+    #[test]
+    fn ok_decode_version_without_sequence() {
+        let parts = account().to_parts();
+        let mut bytes = encode(&parts);
+        // Version 1 had no sequence after the flags
+        bytes[0] = 1;
+        bytes.drain(6..14);
+        assert_eq!(decode(&bytes).unwrap(), parts);
+    }
+
+    #[test]
+    fn err_decode_truncated_or_unknown_version() {
+        let bytes = encode(&account().to_parts());
+        for len in 0..bytes.len() {
+            assert!(matches!(
+                decode(&bytes[..len]),
+                Err(StoreError::Corrupted(_))
+            ));
+        }
+
+        let mut bytes = bytes;
+        bytes[0] = 99;
+        assert!(matches!(
+            decode(&bytes),
+            Err(StoreError::UnsupportedVersion(99))
+        ));
+    }
+}
@@ -0,0 +1,412 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::domain::{account::Account, dispute_policy::DisputePolicy};
+
+use super::{codec, AccountStore, StoreError};
+
+// Append-only log of account records, indexed in memory by account id.
+//
+//  op          u8      (1 = save, 2 = delete)
+//  account_id  u32
+//  len         u32
+//  checksum    u32     (FNV-1a of the payload)
+//  payload     [u8; len]
+//
+// Saves and deletes only append. The log is rewritten with only the live
+// records when most of it is garbage.
+// A crash can leave a torn record at the end; it is dropped when opening.
+// Invalid records anywhere else fail opening instead, since what follows
+// them may not be there anymore.
+#[derive(Debug)]
+pub struct FileAccountStore {
+    path: PathBuf,
+    log: Mutex<Log>,
+}
+
+#[derive(Debug)]
+struct Log {
+    file: File,
+    len: u64,
+    // Where the payload of the last save of each account is
+    index: HashMap<u32, (u64, u32)>,
+    garbage: u64,
+}
+
+const SAVE: u8 = 1;
+const DELETE: u8 = 2;
+const HEADER_LEN: u64 = 13;
+// Longer records are corrupted. Not read, so they cannot exhaust memory.
+pub(super) const MAX_RECORD_LEN: u32 = 64 * 1024 * 1024;
+const MIN_COMPACTION_GARBAGE: u64 = 1024 * 1024;
+
+impl FileAccountStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let path = path.as_ref().to_path_buf();
+        let log = Log::open(&path)?;
+        Ok(Self {
+            path,
+            log: Mutex::new(log),
+        })
+    }
+
+    // Rewrites the log with only the live records.
+    pub fn compact(&self) -> Result<(), StoreError> {
+        let mut log = self.log.lock().unwrap();
+        self.compact_log(&mut log)
+    }
+
+    fn compact_log(&self, log: &mut Log) -> Result<(), StoreError> {
+        let tmp_path = self.path.with_extension("compacting");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            let mut ids: Vec<_> = log.index.keys().copied().collect();
+            ids.sort_unstable();
+            for account_id in ids {
+                let payload = log.read(account_id)?.expect("Indexed account");
+                tmp.write_all(&record(SAVE, account_id, &payload))?;
+            }
+            tmp.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, &self.path)?;
+
+        *log = Log::open(&self.path)?;
+        Ok(())
+    }
+
+    fn maybe_compact(&self, log: &mut Log) -> Result<(), StoreError> {
+        let live = log.len - log.garbage;
+        if log.garbage >= MIN_COMPACTION_GARBAGE && log.garbage > live {
+            self.compact_log(log)?;
+        }
+        Ok(())
+    }
+}
+
+impl Log {
+    fn open(path: &Path) -> Result<Self, StoreError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut log = Self {
+            file,
+            len: 0,
+            index: HashMap::new(),
+            garbage: 0,
+        };
+        log.recover()?;
+        Ok(log)
+    }
+
+    // Rebuilds the index and drops a torn record at the end, if any.
+    fn recover(&mut self) -> Result<(), StoreError> {
+        let file_len = self.file.metadata()?.len();
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(&self.file);
+
+        let mut offset = 0;
+        loop {
+            let mut header = [0u8; HEADER_LEN as usize];
+            if read_full(&mut reader, &mut header)? < header.len() {
+                break;
+            }
+            let op = header[0];
+            let account_id = u32::from_le_bytes(header[1..5].try_into().unwrap());
+            let len = u32::from_le_bytes(header[5..9].try_into().unwrap());
+            let checksum = u32::from_le_bytes(header[9..13].try_into().unwrap());
+
+            let record_len = HEADER_LEN + len as u64;
+            let valid = len <= MAX_RECORD_LEN && offset + record_len <= file_len && {
+                let mut payload = vec![0u8; len as usize];
+                read_full(&mut reader, &mut payload)? == payload.len()
+                    && fnv1a(&payload) == checksum
+                    && matches!(op, SAVE | DELETE)
+            };
+            if !valid {
+                check_torn(offset, record_len, file_len)?;
+                break;
+            }
+
+            let old = match op {
+                SAVE => self.index.insert(account_id, (offset + HEADER_LEN, len)),
+                _ => {
+                    self.garbage += record_len;
+                    self.index.remove(&account_id)
+                }
+            };
+            if let Some((_, old_len)) = old {
+                self.garbage += HEADER_LEN + old_len as u64;
+            }
+            offset += record_len;
+        }
+
+        if offset < file_len {
+            tracing::warn!("Dropping {} bytes of torn records", file_len - offset);
+            self.file.set_len(offset)?;
+        }
+        self.len = offset;
+        Ok(())
+    }
+
+    fn append(&mut self, op: u8, account_id: u32, payload: &[u8]) -> Result<u64, StoreError> {
+        check_len(payload)?;
+        let offset = self.len;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&record(op, account_id, payload))?;
+        self.len += HEADER_LEN + payload.len() as u64;
+        Ok(offset + HEADER_LEN)
+    }
+
+    fn read(&mut self, account_id: u32) -> Result<Option<Vec<u8>>, StoreError> {
+        let (offset, len) = match self.index.get(&account_id) {
+            Some(x) => *x,
+            None => return Ok(None),
+        };
+        let mut payload = vec![0u8; len as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut payload)?;
+        Ok(Some(payload))
+    }
+}
+
+impl AccountStore for FileAccountStore {
+    fn load(
+        &self,
+        account_id: u32,
+        dispute_policy: &Arc<dyn DisputePolicy>,
+    ) -> Result<Option<Account>, StoreError> {
+        let mut log = self.log.lock().unwrap();
+        match log.read(account_id)? {
+            Some(payload) => {
+                let parts = codec::decode(&payload)?;
+                Ok(Some(Account::from_parts(parts, dispute_policy.clone())?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, account: &Account) -> Result<(), StoreError> {
+        let payload = codec::encode(&account.to_parts());
+
+        let mut log = self.log.lock().unwrap();
+        let offset = log.append(SAVE, account.id(), &payload)?;
+        let old = log
+            .index
+            .insert(account.id(), (offset, payload.len() as u32));
+        if let Some((_, old_len)) = old {
+            log.garbage += HEADER_LEN + old_len as u64;
+        }
+        self.maybe_compact(&mut log)
+    }
+
+    fn delete(&self, account_id: u32) -> Result<(), StoreError> {
+        let mut log = self.log.lock().unwrap();
+        if let Some((_, old_len)) = log.index.remove(&account_id) {
+            log.append(DELETE, account_id, &[])?;
+            log.garbage += 2 * HEADER_LEN + old_len as u64;
+        }
+        self.maybe_compact(&mut log)
+    }
+
+    fn scan(
+        &self,
+        dispute_policy: &Arc<dyn DisputePolicy>,
+        visit: &mut dyn FnMut(Account),
+    ) -> Result<(), StoreError> {
+        let mut log = self.log.lock().unwrap();
+        let ids: Vec<_> = log.index.keys().copied().collect();
+        for account_id in ids {
+            if let Some(payload) = log.read(account_id)? {
+                let parts = codec::decode(&payload)?;
+                visit(Account::from_parts(parts, dispute_policy.clone())?);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn record(op: u8, account_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN as usize + payload.len());
+    bytes.push(op);
+    bytes.extend_from_slice(&account_id.to_le_bytes());
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&fnv1a(payload).to_le_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+// Records that could not be read back are not written.
+pub(super) fn check_len(payload: &[u8]) -> Result<(), StoreError> {
+    if payload.len() > MAX_RECORD_LEN as usize {
+        let reason = format!("record of {} bytes is too long", payload.len());
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, reason).into());
+    }
+    Ok(())
+}
+
+// An invalid record is torn when nothing follows it.
+pub(super) fn check_torn(offset: u64, record_len: u64, file_len: u64) -> Result<(), StoreError> {
+    if offset + record_len < file_len {
+        return Err(StoreError::Corrupted(format!(
+            "invalid record at offset {}, followed by {} bytes",
+            offset,
+            file_len - offset - record_len
+        )));
+    }
+    Ok(())
+}
+
+pub(super) fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+// Like `read_exact`, but returns how much was read instead of failing at the end.
+pub(super) fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize, StoreError> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Write, path::PathBuf, sync::Arc};
+
+    use rust_decimal::prelude::ToPrimitive;
+
+    use crate::{
+        domain::{
+            account::Account,
+            dispute_policy::{DepositsOnly, DisputePolicy},
+            money::Currency::*,
+        },
+        store::{AccountStore, StoreError},
+    };
+
+    use super::FileAccountStore;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("accounts-{}-{}.log", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn account(id: u32, amount: u64) -> Account {
+        let mut account = Account::new(id);
+        let _ = account.deposit(1, amount * Bitcoin);
+        account
+    }
+
+    fn policy() -> Arc<dyn DisputePolicy> {
+        Arc::new(DepositsOnly)
+    }
+
+    fn load_total(store: &FileAccountStore, account_id: u32) -> Option<u64> {
+        store
+            .load(account_id, &policy())
+            .unwrap()
+            .map(|account| account.total().amount.to_u64().unwrap())
+    }
+
+    #[test]
+    fn ok_accounts_survive_reopening() {
+        let path = temp_path("reopen");
+        {
+            let store = FileAccountStore::open(&path).unwrap();
+            store.save(&account(1, 10)).unwrap();
+            store.save(&account(2, 20)).unwrap();
+            store.save(&account(1, 11)).unwrap();
+            store.delete(2).unwrap();
+        }
+
+        let store = FileAccountStore::open(&path).unwrap();
+        assert_eq!(load_total(&store, 1), Some(11));
+        assert_eq!(load_total(&store, 2), None);
+
+        let mut ids = vec![];
+        store
+            .scan(&policy(), &mut |account| ids.push(account.id()))
+            .unwrap();
+        assert_eq!(ids, vec![1]);
+
+        store.compact().unwrap();
+        assert_eq!(load_total(&store, 1), Some(11));
+        drop(store);
+
+        let store = FileAccountStore::open(&path).unwrap();
+        assert_eq!(load_total(&store, 1), Some(11));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn ok_torn_record_is_dropped() {
+        let path = temp_path("torn");
+        {
+            let store = FileAccountStore::open(&path).unwrap();
+            store.save(&account(1, 10)).unwrap();
+        }
+
+        // A crash in the middle of the second save
+        let len = std::fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[1, 2, 0, 0, 0, 200, 0]).unwrap();
+        drop(file);
+
+        let store = FileAccountStore::open(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(load_total(&store, 1), Some(10));
+
+        store.save(&account(2, 20)).unwrap();
+        drop(store);
+        let store = FileAccountStore::open(&path).unwrap();
+        assert_eq!(load_total(&store, 2), Some(20));
+        drop(store);
+
+        // Torn in the middle of a header claiming a huge record
+        let len = std::fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[1, 3, 0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 0])
+            .unwrap();
+        drop(file);
+        let store = FileAccountStore::open(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(load_total(&store, 2), Some(20));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn err_corrupted_record_is_kept() {
+        let path = temp_path("corrupted");
+        {
+            let store = FileAccountStore::open(&path).unwrap();
+            store.save(&account(1, 10)).unwrap();
+            store.save(&account(2, 20)).unwrap();
+        }
+
+        // The first record is damaged, the second one is fine
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[20] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let opened = FileAccountStore::open(&path);
+        assert!(matches!(opened, Err(StoreError::Corrupted(_))));
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        let _ = std::fs::remove_file(&path);
+    }
+}
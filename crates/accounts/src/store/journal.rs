@@ -0,0 +1,662 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{
+    actors::account::{
+        AccountRequests, ChargebackRequest, CloseRequest, DepositRequest, DisputeRequest,
+        FreezeRequest, ResolveRequest, UnfreezeRequest, WithdrawRequest,
+    },
+    domain::{account::AccountParts, money::Money},
+};
+
+use super::{
+    codec::{self, corrupted, put_currency, Reader},
+    file::{check_len, check_torn, fnv1a, read_full, MAX_RECORD_LEN},
+    StoreError,
+};
+
+// When appended requests are flushed to disk.
+// Whatever is not synced can be lost if the machine crashes,
+// but not if only the process does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    Always,
+    EveryN(u32),
+    Never,
+}
+
+// Append-only log of requests accepted by account actors, in the
+// order they were applied. Replaying it rebuilds the exact same state.
+// Accounts saved or released by their manager are marked, so replaying
+// them starts there and compaction drops what was journaled before.
+//
+//  len         u32
+//  checksum    u32     (FNV-1a of the payload)
+//  payload     [u8; len]
+//
+// The payload is the request:
+//
+//  kind        u8      (0 = deposit, 1 = withdraw, 2 = dispute, 3 = resolve,
+//                       4 = chargeback, 5 = freeze, 6 = unfreeze, 7 = close,
+//                       8 = saved, 9 = released)
+//  account_id  u32
+//  deposits, withdraws and disputes:
+//      transaction_id  u32
+//  deposits and withdraws:
+//      currency        u8 (same as accounts)
+//      amount          [u8; 16]
+//  freezes and unfreezes:
+//      len             u32
+//      reason          [u8; len] (UTF-8)
+//  saved:
+//      account         [u8; len - 5] (same as the account store)
+#[derive(Debug)]
+pub struct Journal {
+    policy: FsyncPolicy,
+    // Obsolete records that trigger a compaction. Never compacted when None.
+    compaction: Option<u64>,
+    file: Mutex<JournalFile>,
+}
+
+#[derive(Debug)]
+struct JournalFile {
+    file: File,
+    path: PathBuf,
+    // Where the last whole record ends
+    len: u64,
+    unsynced: u32,
+    records: Records,
+}
+
+// An account as the journal has it.
+#[derive(Debug, Default)]
+pub struct JournaledAccount {
+    // As it was last saved, if it was
+    pub saved: Option<AccountParts>,
+    // Applied since then, in order
+    pub requests: Vec<AccountRequests>,
+}
+
+enum Record {
+    Request(AccountRequests),
+    Saved(AccountParts),
+    Released(u32),
+}
+
+// Records still needed to replay each account, and the ones that are not.
+#[derive(Debug, Default)]
+struct Records {
+    live: HashMap<u32, u64>,
+    obsolete: u64,
+}
+
+impl Records {
+    fn add(&mut self, kind: u8, account_id: u32) {
+        match kind {
+            SAVED => {
+                self.obsolete += self.live.insert(account_id, 1).unwrap_or(0);
+            }
+            RELEASED => {
+                self.obsolete += self.live.remove(&account_id).unwrap_or(0) + 1;
+            }
+            _ => *self.live.entry(account_id).or_default() += 1,
+        }
+    }
+}
+
+const HEADER_LEN: usize = 8;
+const SAVED: u8 = 8;
+const RELEASED: u8 = 9;
+
+impl Journal {
+    // Opens the journal and drops a torn record at the end, if any.
+    pub fn open(path: impl AsRef<Path>, policy: FsyncPolicy) -> Result<Self, StoreError> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let mut records = Records::default();
+        let len = Self::scan(&file, &mut |payload| {
+            let (kind, account_id) = header(payload)?;
+            records.add(kind, account_id);
+            Ok(())
+        })?;
+        if len < file.metadata()?.len() {
+            tracing::warn!("Dropping torn journal records");
+            file.set_len(len)?;
+        }
+
+        Ok(Self {
+            policy,
+            compaction: None,
+            file: Mutex::new(JournalFile {
+                file,
+                path,
+                len,
+                unsynced: 0,
+                records,
+            }),
+        })
+    }
+
+    // Compacts the journal once this many records are no longer needed.
+    pub fn with_compaction(mut self, obsolete: u64) -> Self {
+        self.compaction = Some(obsolete.max(1));
+        self
+    }
+
+    // Requests must be journaled before they are applied.
+    pub fn append(&self, request: &AccountRequests) -> Result<(), StoreError> {
+        self.write(encode(request))
+    }
+
+    // The account was saved as it is now. Replaying it starts here.
+    pub fn saved(&self, account: &AccountParts) -> Result<(), StoreError> {
+        let mut payload = vec![SAVED];
+        payload.extend_from_slice(&account.id.to_le_bytes());
+        payload.extend_from_slice(&codec::encode(account));
+        self.write(payload)
+    }
+
+    // The account is no longer ours, so it is not replayed anymore.
+    pub fn released(&self, account_id: u32) -> Result<(), StoreError> {
+        let mut payload = vec![RELEASED];
+        payload.extend_from_slice(&account_id.to_le_bytes());
+        self.write(payload)
+    }
+
+    fn write(&self, payload: Vec<u8>) -> Result<(), StoreError> {
+        let (kind, account_id) = header(&payload)?;
+        check_len(&payload)?;
+        let mut journal = self.file.lock().unwrap();
+        let unsynced = journal.unsynced + 1;
+        let sync = match self.policy {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryN(n) => unsynced >= n,
+            FsyncPolicy::Never => false,
+        };
+        // The caller is told the request was not journaled, so it must not be
+        // replayed either. Cutting it off also keeps a partial record from
+        // ending up in the middle of the journal.
+        if let Err(err) = journal.append(&frame(&payload), sync) {
+            let len = journal.len;
+            journal.file.set_len(len)?;
+            return Err(err);
+        }
+        journal.unsynced = if sync { 0 } else { unsynced };
+        journal.records.add(kind, account_id);
+
+        if self
+            .compaction
+            .is_some_and(|obsolete| journal.records.obsolete >= obsolete)
+        {
+            Self::compact_file(&mut journal)?;
+        }
+        Ok(())
+    }
+
+    // Rewrites the journal without the records no account needs anymore.
+    pub fn compact(&self) -> Result<(), StoreError> {
+        let mut journal = self.file.lock().unwrap();
+        Self::compact_file(&mut journal)
+    }
+
+    fn compact_file(journal: &mut JournalFile) -> Result<(), StoreError> {
+        // Where replaying each account starts
+        let mut starts: HashMap<u32, u64> = HashMap::new();
+        let mut index = 0;
+        Self::scan(&journal.file, &mut |payload| {
+            match header(payload)? {
+                (SAVED, account_id) => starts.insert(account_id, index),
+                (RELEASED, account_id) => starts.insert(account_id, index + 1),
+                _ => None,
+            };
+            index += 1;
+            Ok(())
+        })?;
+
+        let path = journal.path.with_extension("compacting");
+        let mut writer = BufWriter::new(File::create(&path)?);
+        let mut index = 0;
+        Self::scan(&journal.file, &mut |payload| {
+            let (_, account_id) = header(payload)?;
+            if index >= starts.get(&account_id).copied().unwrap_or(0) {
+                writer.write_all(&frame(payload))?;
+            }
+            index += 1;
+            Ok(())
+        })?;
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        drop(file);
+
+        std::fs::rename(&path, &journal.path)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&journal.path)?;
+        journal.len = file.metadata()?.len();
+        journal.file = file;
+        journal.unsynced = 0;
+        journal.records.obsolete = 0;
+        Ok(())
+    }
+
+    pub fn sync(&self) -> Result<(), StoreError> {
+        let mut journal = self.file.lock().unwrap();
+        journal.file.sync_data()?;
+        journal.unsynced = 0;
+        Ok(())
+    }
+
+    // Every journaled request, in the order they were appended.
+    // Compacted ones are gone.
+    pub fn requests(&self) -> Result<Vec<AccountRequests>, StoreError> {
+        let mut requests = vec![];
+        self.visit(&mut |record| {
+            if let Record::Request(request) = record {
+                requests.push(request);
+            }
+        })?;
+        Ok(requests)
+    }
+
+    // What is needed to replay each account.
+    pub fn accounts(&self) -> Result<BTreeMap<u32, JournaledAccount>, StoreError> {
+        let mut accounts: BTreeMap<u32, JournaledAccount> = BTreeMap::new();
+        self.visit(&mut |record| match record {
+            Record::Request(request) => accounts
+                .entry(request.get_account_id())
+                .or_default()
+                .requests
+                .push(request),
+            Record::Saved(account) => {
+                let account_id = account.id;
+                let saved = JournaledAccount {
+                    saved: Some(account),
+                    requests: vec![],
+                };
+                accounts.insert(account_id, saved);
+            }
+            Record::Released(account_id) => {
+                accounts.remove(&account_id);
+            }
+        })?;
+        Ok(accounts)
+    }
+
+    // Same as `accounts`, for only one of them.
+    pub fn account(&self, account_id: u32) -> Result<Option<JournaledAccount>, StoreError> {
+        let mut account: Option<JournaledAccount> = None;
+        self.visit(&mut |record| match record {
+            Record::Request(request) if request.get_account_id() == account_id => account
+                .get_or_insert_with(Default::default)
+                .requests
+                .push(request),
+            Record::Saved(saved) if saved.id == account_id => {
+                account = Some(JournaledAccount {
+                    saved: Some(saved),
+                    requests: vec![],
+                })
+            }
+            Record::Released(id) if id == account_id => account = None,
+            _ => {}
+        })?;
+        Ok(account)
+    }
+
+    fn visit(&self, visit: &mut dyn FnMut(Record)) -> Result<(), StoreError> {
+        let journal = self.file.lock().unwrap();
+        Self::scan(&journal.file, &mut |payload| {
+            visit(decode(payload)?);
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    // Visits the valid records and returns where they end.
+    // Only a torn record at the end may be invalid.
+    fn scan(
+        file: &File,
+        visit: &mut dyn FnMut(&[u8]) -> Result<(), StoreError>,
+    ) -> Result<u64, StoreError> {
+        let mut file = file;
+        let file_len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(file);
+
+        let mut len = 0;
+        loop {
+            let mut header = [0u8; HEADER_LEN];
+            if read_full(&mut reader, &mut header)? < HEADER_LEN {
+                break;
+            }
+            let payload_len = u32::from_le_bytes(header[0..4].try_into().unwrap());
+            let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+
+            let record_len = (HEADER_LEN as u64) + payload_len as u64;
+            if payload_len > MAX_RECORD_LEN || len + record_len > file_len {
+                check_torn(len, record_len, file_len)?;
+                break;
+            }
+            let mut payload = vec![0u8; payload_len as usize];
+            if read_full(&mut reader, &mut payload)? < payload.len() || fnv1a(&payload) != checksum
+            {
+                check_torn(len, record_len, file_len)?;
+                break;
+            }
+
+            visit(&payload)?;
+            len += record_len;
+        }
+        Ok(len)
+    }
+}
+
+impl JournalFile {
+    fn append(&mut self, bytes: &[u8], sync: bool) -> Result<(), StoreError> {
+        self.file.seek(SeekFrom::Start(self.len))?;
+        self.file.write_all(bytes)?;
+        if sync {
+            self.file.sync_data()?;
+        }
+        self.len += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&fnv1a(payload).to_le_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+// Kind and account of a record.
+fn header(payload: &[u8]) -> Result<(u8, u32), StoreError> {
+    let mut reader = Reader::new(payload);
+    Ok((reader.u8()?, reader.u32()?))
+}
+
+fn encode(request: &AccountRequests) -> Vec<u8> {
+    use AccountRequests::*;
+
+    let mut bytes = vec![];
+    let kind = match request {
+        DepositRequest(_) => 0,
+        WithdrawRequest(_) => 1,
+        DisputeRequest(_) => 2,
+        ResolveRequest(_) => 3,
+        ChargebackRequest(_) => 4,
+        FreezeRequest(_) => 5,
+        UnfreezeRequest(_) => 6,
+        CloseRequest(_) => 7,
+        AcceptRequestRequest(_) | PassivateRequest(_) | SnapshotRequest(_) | Lifecycle(_) => {
+            unreachable!("Only account operations are journaled")
+        }
+    };
+    bytes.push(kind);
+    bytes.extend_from_slice(&request.get_account_id().to_le_bytes());
+
+    let put_money = |bytes: &mut Vec<u8>, money: Money| {
+        put_currency(bytes, money.currency);
+        bytes.extend_from_slice(&money.amount.serialize());
+    };
+    let put_string = |bytes: &mut Vec<u8>, s: &str| {
+        bytes.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(s.as_bytes());
+    };
+
+    match request {
+        DepositRequest(r) => {
+            bytes.extend_from_slice(&r.transaction_id.to_le_bytes());
+            put_money(&mut bytes, r.amount);
+        }
+        WithdrawRequest(r) => {
+            bytes.extend_from_slice(&r.transaction_id.to_le_bytes());
+            put_money(&mut bytes, r.amount);
+        }
+        DisputeRequest(_) | ResolveRequest(_) | ChargebackRequest(_) => {
+            bytes.extend_from_slice(&request.get_transaction_id().to_le_bytes());
+        }
+        FreezeRequest(r) => put_string(&mut bytes, &r.reason),
+        UnfreezeRequest(r) => put_string(&mut bytes, &r.reason),
+        _ => {}
+    }
+
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Result<Record, StoreError> {
+    let mut reader = Reader::new(bytes);
+
+    let kind = reader.u8()?;
+    let account_id = reader.u32()?;
+    match kind {
+        SAVED => {
+            let account = codec::decode(&bytes[5..])?;
+            if account.id != account_id {
+                return Err(StoreError::Corrupted("saved account id mismatch".into()));
+            }
+            return Ok(Record::Saved(account));
+        }
+        RELEASED => {
+            reader.end()?;
+            return Ok(Record::Released(account_id));
+        }
+        _ => {}
+    }
+
+    let money = |reader: &mut Reader| -> Result<Money, StoreError> {
+        let currency = reader.currency()?;
+        Ok(reader.decimal()? * currency)
+    };
+    let string = |reader: &mut Reader| -> Result<String, StoreError> {
+        let len = reader.u32()?;
+        let bytes = reader.take_slice(len as usize)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| StoreError::Corrupted("reason is not UTF-8".into()))
+    };
+
+    let request = match kind {
+        0 => DepositRequest {
+            account_id,
+            transaction_id: reader.u32()?,
+            amount: money(&mut reader)?,
+        }
+        .into(),
+        1 => WithdrawRequest {
+            account_id,
+            transaction_id: reader.u32()?,
+            amount: money(&mut reader)?,
+        }
+        .into(),
+        2 => DisputeRequest {
+            account_id,
+            transaction_id: reader.u32()?,
+        }
+        .into(),
+        3 => ResolveRequest {
+            account_id,
+            transaction_id: reader.u32()?,
+        }
+        .into(),
+        4 => ChargebackRequest {
+            account_id,
+            transaction_id: reader.u32()?,
+        }
+        .into(),
+        5 => FreezeRequest {
+            account_id,
+            reason: string(&mut reader)?,
+        }
+        .into(),
+        6 => UnfreezeRequest {
+            account_id,
+            reason: string(&mut reader)?,
+        }
+        .into(),
+        7 => CloseRequest { account_id }.into(),
+        tag => return Err(corrupted("request kind", tag)),
+    };
+
+    reader.end()?;
+    Ok(Record::Request(request))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Write};
+
+    use crate::{
+        actors::account::{
+            AccountRequests, CloseRequest, DepositRequest, DisputeRequest, FreezeRequest,
+            WithdrawRequest,
+        },
+        domain::{account::Account, money::Currency::*},
+        store::StoreError,
+    };
+
+    use super::{FsyncPolicy, Journal};
+
+    fn requests() -> Vec<AccountRequests> {
+        vec![
+            DepositRequest {
+                account_id: 1,
+                transaction_id: 1,
+                amount: 1.5 * Bitcoin,
+            }
+            .into(),
+            WithdrawRequest {
+                account_id: 1,
+                transaction_id: 2,
+                amount: 0.5 * Other { code: 7 },
+            }
+            .into(),
+            DisputeRequest {
+                account_id: 1,
+                transaction_id: 1,
+            }
+            .into(),
+            FreezeRequest {
+                account_id: 2,
+                reason: "fraud investigation".into(),
+            }
+            .into(),
+            CloseRequest { account_id: 2 }.into(),
+        ]
+    }
+
+    #[test]
+    fn ok_journal_survives_reopening_and_torn_records() {
+        let path =
+            std::env::temp_dir().join(format!("accounts-journal-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        {
+            let journal = Journal::open(&path, FsyncPolicy::EveryN(2)).unwrap();
+            for request in requests() {
+                journal.append(&request).unwrap();
+            }
+        }
+
+        // A crash in the middle of an append
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[20, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let journal = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        let read = journal.requests().unwrap();
+        assert_eq!(format!("{:?}", read), format!("{:?}", requests()));
+
+        journal.append(&requests()[0]).unwrap();
+        assert_eq!(journal.requests().unwrap().len(), requests().len() + 1);
+        drop(journal);
+
+        // Damaged before other records, so it is not dropped
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[10] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+        let opened = Journal::open(&path, FsyncPolicy::Always);
+        assert!(matches!(opened, Err(StoreError::Corrupted(_))));
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn ok_append_replaces_partial_records() {
+        let path = std::env::temp_dir().join(format!(
+            "accounts-journal-partial-{}.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let journal = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        journal.append(&requests()[0]).unwrap();
+
+        // What a failed append leaves behind
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[20, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        journal.append(&requests()[1]).unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        let read = journal.requests().unwrap();
+        assert_eq!(format!("{:?}", read), format!("{:?}", &requests()[..2]));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn ok_compaction_keeps_what_accounts_need() {
+        let path = std::env::temp_dir().join(format!(
+            "accounts-journal-compaction-{}.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let journal = Journal::open(&path, FsyncPolicy::Never)
+            .unwrap()
+            .with_compaction(4);
+        for request in requests() {
+            journal.append(&request).unwrap();
+        }
+        let mut account = Account::new(1);
+        let _ = account.deposit(1, 1.5 * Bitcoin);
+        journal.saved(&account.to_parts()).unwrap();
+        // Not compacted yet
+        assert_eq!(journal.requests().unwrap().len(), requests().len());
+
+        journal.released(2).unwrap();
+        let withdraw: AccountRequests = WithdrawRequest {
+            account_id: 1,
+            transaction_id: 4,
+            amount: 1 * Bitcoin,
+        }
+        .into();
+        journal.append(&withdraw).unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&path, FsyncPolicy::Never).unwrap();
+        let read = journal.requests().unwrap();
+        assert_eq!(format!("{:?}", read), format!("{:?}", vec![withdraw]));
+
+        let accounts = journal.accounts().unwrap();
+        assert_eq!(accounts.keys().copied().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(accounts[&1].saved, Some(account.to_parts()));
+        assert_eq!(accounts[&1].requests.len(), 1);
+        assert!(journal.account(2).unwrap().is_none());
+        let _ = std::fs::remove_file(&path);
+    }
+}
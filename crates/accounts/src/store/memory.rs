@@ -0,0 +1,59 @@
+use std::{collections::HashMap, sync::Arc, sync::Mutex};
+
+use crate::domain::{account::Account, dispute_policy::DisputePolicy};
+
+use super::{codec, AccountStore, StoreError};
+
+// Keeps accounts encoded, so it behaves like any other store,
+// but loses everything when dropped.
+#[derive(Debug, Default)]
+pub struct InMemoryAccountStore {
+    accounts: Mutex<HashMap<u32, Vec<u8>>>,
+}
+
+impl InMemoryAccountStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AccountStore for InMemoryAccountStore {
+    fn load(
+        &self,
+        account_id: u32,
+        dispute_policy: &Arc<dyn DisputePolicy>,
+    ) -> Result<Option<Account>, StoreError> {
+        let accounts = self.accounts.lock().unwrap();
+        match accounts.get(&account_id) {
+            Some(bytes) => {
+                let parts = codec::decode(bytes)?;
+                Ok(Some(Account::from_parts(parts, dispute_policy.clone())?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, account: &Account) -> Result<(), StoreError> {
+        let bytes = codec::encode(&account.to_parts());
+        self.accounts.lock().unwrap().insert(account.id(), bytes);
+        Ok(())
+    }
+
+    fn delete(&self, account_id: u32) -> Result<(), StoreError> {
+        self.accounts.lock().unwrap().remove(&account_id);
+        Ok(())
+    }
+
+    fn scan(
+        &self,
+        dispute_policy: &Arc<dyn DisputePolicy>,
+        visit: &mut dyn FnMut(Account),
+    ) -> Result<(), StoreError> {
+        let accounts = self.accounts.lock().unwrap();
+        for bytes in accounts.values() {
+            let parts = codec::decode(bytes)?;
+            visit(Account::from_parts(parts, dispute_policy.clone())?);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+pub mod codec;
+pub mod file;
+pub mod journal;
+pub mod memory;
+pub mod snapshot;
+
+use std::sync::Arc;
+
+use crate::domain::{
+    account::{Account, AccountErrors},
+    dispute_policy::DisputePolicy,
+};
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(std::io::Error),
+    Corrupted(String),
+    UnsupportedVersion(u8),
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(err: std::io::Error) -> Self {
+        StoreError::Io(err)
+    }
+}
+
+// Decoded parts that do not make an account
+impl From<AccountErrors> for StoreError {
+    fn from(err: AccountErrors) -> Self {
+        StoreError::Corrupted(format!("invalid account: {:?}", err))
+    }
+}
+
+// Where accounts live when they are not in memory.
+// Dispute policies are not stored; they are given back when loading.
+pub trait AccountStore: std::fmt::Debug + Send + Sync {
+    fn load(
+        &self,
+        account_id: u32,
+        dispute_policy: &Arc<dyn DisputePolicy>,
+    ) -> Result<Option<Account>, StoreError>;
+
+    fn save(&self, account: &Account) -> Result<(), StoreError>;
+
+    fn delete(&self, account_id: u32) -> Result<(), StoreError>;
+
+    // Visits every stored account, in no particular order.
+    fn scan(
+        &self,
+        dispute_policy: &Arc<dyn DisputePolicy>,
+        visit: &mut dyn FnMut(Account),
+    ) -> Result<(), StoreError>;
+}
@@ -0,0 +1,204 @@
+use std::{io::Write, path::Path, sync::Arc};
+
+use crate::{
+    actors::aggregators::accounts_state_aggregator::{AccountState, AccountsStateAggregator},
+    domain::{account::Account, dispute_policy::DisputePolicy},
+};
+
+use super::{codec, codec::Reader, StoreError};
+
+// The whole system state: every account and what the aggregator knows.
+//
+//  magic       [u8; 4] ("LDGR")
+//  version     u8
+//  count       u32
+//  count times:
+//      len     u32
+//      account [u8; len] (see codec)
+//  count       u32
+//  count times:
+//      client      u32
+//      available   [u8; 16]
+//      held        [u8; 16]
+//      total       [u8; 16]
+//      locked      u8
+#[derive(Clone, Debug)]
+pub struct LedgerSnapshot {
+    pub accounts: Vec<Account>,
+    pub aggregator: AccountsStateAggregator,
+}
+
+const MAGIC: &[u8; 4] = b"LDGR";
+pub const VERSION: u8 = 1;
+
+impl LedgerSnapshot {
+    // Written to a temporary file first, so a crash never leaves half a snapshot.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), StoreError> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("saving");
+        {
+            let mut file = std::fs::File::create(&tmp_path)?;
+            file.write_all(&self.encode())?;
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn load(
+        path: impl AsRef<Path>,
+        dispute_policy: &Arc<dyn DisputePolicy>,
+    ) -> Result<Self, StoreError> {
+        let bytes = std::fs::read(path)?;
+        Self::decode(&bytes, dispute_policy)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+
+        bytes.extend_from_slice(&(self.accounts.len() as u32).to_le_bytes());
+        for account in self.accounts.iter() {
+            let account = codec::encode(&account.to_parts());
+            bytes.extend_from_slice(&(account.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&account);
+        }
+
+        let mut states: Vec<_> = self.aggregator.accounts.values().collect();
+        states.sort_by_key(|state| state.client);
+        bytes.extend_from_slice(&(states.len() as u32).to_le_bytes());
+        for state in states {
+            bytes.extend_from_slice(&state.client.to_le_bytes());
+            bytes.extend_from_slice(&state.available.serialize());
+            bytes.extend_from_slice(&state.held.serialize());
+            bytes.extend_from_slice(&state.total.serialize());
+            bytes.push(state.locked as u8);
+        }
+
+        bytes
+    }
+
+    fn decode(bytes: &[u8], dispute_policy: &Arc<dyn DisputePolicy>) -> Result<Self, StoreError> {
+        let mut reader = Reader::new(bytes);
+        if reader.take_slice(MAGIC.len())? != MAGIC {
+            return Err(StoreError::Corrupted("not a ledger snapshot".into()));
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(StoreError::UnsupportedVersion(version));
+        }
+
+        let count = reader.u32()?;
+        let mut accounts = vec![];
+        for _ in 0..count {
+            let len = reader.u32()?;
+            let parts = codec::decode(reader.take_slice(len as usize)?)?;
+            accounts.push(Account::from_parts(parts, dispute_policy.clone())?);
+        }
+
+        let count = reader.u32()?;
+        let mut aggregator = AccountsStateAggregator::default();
+        for _ in 0..count {
+            let state = AccountState {
+                client: reader.u32()?,
+                available: reader.decimal()?,
+                held: reader.decimal()?,
+                total: reader.decimal()?,
+                locked: reader.u8()? != 0,
+            };
+            aggregator.accounts.insert(state.client, state);
+        }
+
+        reader.end()?;
+        Ok(Self {
+            accounts,
+            aggregator,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        actors::aggregators::{accounts_state_aggregator::AccountsStateAggregator, Aggregator},
+        domain::{
+            account::Account,
+            dispute_policy::{DepositsOnly, DisputePolicy},
+            events::AllEvents,
+            money::Currency::*,
+            DomainResult,
+        },
+        store::{codec, StoreError},
+    };
+
+    use super::{LedgerSnapshot, MAGIC, VERSION};
+
+    #[test]
+    fn ok_snapshot_roundtrip() {
+        let mut aggregator = AccountsStateAggregator::default();
+        let mut accounts = vec![];
+        for id in 0..3 {
+            let mut account = Account::new(id);
+            let mut events: Vec<AllEvents> = vec![];
+            for result in [
+                account.deposit(1, (id as u64 + 1) * Bitcoin),
+                account.deposit(2, 0.5 * Bitcoin),
+                account.dispute(2),
+            ] {
+                if let DomainResult::Ok { events: e, .. } = result {
+                    events.extend(e);
+                }
+            }
+            if id == 2 {
+                if let DomainResult::Ok { events: e, .. } = account.chargeback(2) {
+                    events.extend(e);
+                }
+            }
+            for event in events {
+                aggregator.handle(event);
+            }
+            accounts.push(account);
+        }
+        let snapshot = LedgerSnapshot {
+            accounts,
+            aggregator,
+        };
+
+        let path =
+            std::env::temp_dir().join(format!("accounts-ledger-{}.snap", std::process::id()));
+        snapshot.save(&path).unwrap();
+        let policy: Arc<dyn DisputePolicy> = Arc::new(DepositsOnly);
+        let loaded = LedgerSnapshot::load(&path, &policy).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(loaded.aggregator, snapshot.aggregator);
+        assert!(loaded.aggregator.accounts[&2].locked);
+        let parts = |snapshot: &LedgerSnapshot| -> Vec<_> {
+            snapshot.accounts.iter().map(Account::to_parts).collect()
+        };
+        assert_eq!(parts(&loaded), parts(&snapshot));
+
+        assert!(matches!(
+            LedgerSnapshot::decode(b"NOPE", &policy),
+            Err(StoreError::Corrupted(_))
+        ));
+
+        // Held more than the total
+        let mut account = snapshot.accounts[0].to_parts();
+        account.held = 100 * Bitcoin;
+        let account = codec::encode(&account);
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&(account.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&account);
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(
+            LedgerSnapshot::decode(&bytes, &policy),
+            Err(StoreError::Corrupted(_))
+        ));
+    }
+}
@@ -1,9 +1,22 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
 use accounts::actors::{
-    account::{ChargebackRequest, DepositRequest, DisputeRequest, ResolveRequest, WithdrawRequest},
+    account::{
+        AccountRequests, ChargebackRequest, DepositRequest, DisputeRequest, ResolveRequest,
+        WithdrawRequest,
+    },
     account_shard::AccountShardClient,
+    clock::TokioClock,
+    deadline::Deadline,
 };
 use csv::{ReaderBuilder, Trim};
 use serde::Deserialize;
+use tokio::task::JoinSet;
 
 #[derive(Debug, Deserialize)]
 struct CsvRecord {
@@ -14,7 +27,7 @@ struct CsvRecord {
     amount: Option<f64>,
 }
 
-async fn process_line(shard: AccountShardClient, record: CsvRecord) {
+async fn process_line(shard: AccountShardClient, record: CsvRecord, deadline: Deadline) {
     let CsvRecord {
         t,
         client,
@@ -25,55 +38,85 @@ async fn process_line(shard: AccountShardClient, record: CsvRecord) {
     let t = t.to_ascii_lowercase();
 
     use accounts::domain::money::Currency::*;
-    match t.as_str() {
-        "deposit" => {
-            let _ = shard
-                .send_account_async(DepositRequest {
-                    account_id: client,
-                    transaction_id: tx,
-                    amount: amount.unwrap() * Bitcoin,
-                })
-                .await;
+    let request: AccountRequests = match t.as_str() {
+        "deposit" => DepositRequest {
+            account_id: client,
+            transaction_id: tx,
+            amount: amount.unwrap() * Bitcoin,
         }
-        "withdrawal" => {
-            let _ = shard
-                .send_account_async(WithdrawRequest {
-                    account_id: client,
-                    transaction_id: tx,
-                    amount: amount.unwrap() * Bitcoin,
-                })
-                .await;
+        .into(),
+        "withdrawal" => WithdrawRequest {
+            account_id: client,
+            transaction_id: tx,
+            amount: amount.unwrap() * Bitcoin,
         }
-        "dispute" => {
-            let _ = shard
-                .send_account_async(DisputeRequest {
-                    account_id: client,
-                    transaction_id: tx,
-                })
-                .await;
+        .into(),
+        "dispute" => DisputeRequest {
+            account_id: client,
+            transaction_id: tx,
         }
-        "resolve" => {
-            let _ = shard
-                .send_account_async(ResolveRequest {
-                    account_id: client,
-                    transaction_id: tx,
-                })
-                .await;
+        .into(),
+        "resolve" => ResolveRequest {
+            account_id: client,
+            transaction_id: tx,
         }
-        "chargeback" => {
-            let _ = shard
-                .send_account_async(ChargebackRequest {
-                    account_id: client,
-                    transaction_id: tx,
-                })
-                .await;
+        .into(),
+        "chargeback" => ChargebackRequest {
+            account_id: client,
+            transaction_id: tx,
+        }
+        .into(),
+        t => {
+            tracing::warn!("Unkown command: {}", t);
+            return;
         }
-        t => tracing::warn!("Unkown command: {}", t),
     };
+
+    // Operations past their deadline are skipped
+    if let Err(err) = shard.send_account_before_async(request, deadline).await {
+        tracing::warn!("{} {} of client {} failed: {:?}", t, tx, client, err);
+    }
+}
+
+// Operations already in the journal were applied before a crash.
+// Sending them again could, for example, dispute a transaction twice.
+fn skip_journaled(
+    records: impl Iterator<Item = CsvRecord>,
+    journaled: &[AccountRequests],
+) -> impl Iterator<Item = CsvRecord> {
+    let mut applied: HashMap<(String, u32, u32), usize> = HashMap::new();
+    for request in journaled {
+        let t = match request {
+            AccountRequests::DepositRequest(_) => "deposit",
+            AccountRequests::WithdrawRequest(_) => "withdrawal",
+            AccountRequests::DisputeRequest(_) => "dispute",
+            AccountRequests::ResolveRequest(_) => "resolve",
+            AccountRequests::ChargebackRequest(_) => "chargeback",
+            _ => continue,
+        };
+        let key = (
+            t.to_string(),
+            request.get_account_id(),
+            request.get_transaction_id(),
+        );
+        *applied.entry(key).or_default() += 1;
+    }
+
+    records.filter(move |record| {
+        let key = (record.t.to_ascii_lowercase(), record.client, record.tx);
+        match applied.get_mut(&key) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                false
+            }
+            _ => true,
+        }
+    })
 }
 
-pub async fn process(shard: AccountShardClient, input: String) {
-    let mut reader = ReaderBuilder::new()
+// Records are read as they are consumed.
+fn read_records(input: String, journaled: &[AccountRequests]) -> impl Iterator<Item = CsvRecord> {
+    let reader = ReaderBuilder::new()
         .delimiter(b',')
         .has_headers(true)
         .trim(Trim::All)
@@ -81,19 +124,108 @@ pub async fn process(shard: AccountShardClient, input: String) {
         .from_path(input)
         .unwrap(); //TODO unwrap
 
-    let mut tasks = vec![];
-    for result in reader.deserialize() {
-        let record: CsvRecord = result.unwrap(); //TODO unwrap
+    let records = reader.into_deserialize().map(|result| result.unwrap()); //TODO unwrap
+    skip_journaled(records, journaled)
+}
+
+// Each operation has `timeout` to be applied, if any.
+// With `max_in_flight`, the file is only read as operations are applied,
+// so memory does not grow with its size.
+pub async fn process(
+    shard: AccountShardClient,
+    input: String,
+    journaled: &[AccountRequests],
+    timeout: Option<Duration>,
+    max_in_flight: Option<usize>,
+) {
+    let mut tasks = JoinSet::new();
+    for record in read_records(input, journaled) {
+        if let Some(max_in_flight) = max_in_flight {
+            while tasks.len() >= max_in_flight.max(1) {
+                let _ = tasks.join_next().await;
+            }
+        }
 
         let shard = shard.clone();
+        tasks.spawn(process_line(shard, record, deadline(timeout)));
+    }
+
+    while tasks.join_next().await.is_some() {}
+}
 
-        let t = tokio::task::spawn(async move {
-            let _ = process_line(shard, record).await;
-        });
-        tasks.push(t);
+fn deadline(timeout: Option<Duration>) -> Deadline {
+    timeout.map_or(Deadline::never(), |timeout| {
+        Deadline::after(Arc::new(TokioClock), timeout)
+    })
+}
+
+// How many operations wait to be ordered by default.
+pub const REORDER_WINDOW: usize = 64 * 1024;
+
+// Sends one operation at a time, in the same order accounts would
+// reorder them: by transaction id, the operation before its disputes,
+// then file order. Only `window` operations wait to be ordered, so
+// one further away than that from its place is sent late.
+pub async fn process_in_order(
+    shard: AccountShardClient,
+    input: String,
+    journaled: &[AccountRequests],
+    timeout: Option<Duration>,
+    window: usize,
+) {
+    for record in in_order(read_records(input, journaled), window) {
+        process_line(shard.clone(), record, deadline(timeout)).await;
+    }
+}
+
+fn in_order(
+    mut records: impl Iterator<Item = CsvRecord>,
+    window: usize,
+) -> impl Iterator<Item = CsvRecord> {
+    let mut pending = BinaryHeap::new();
+    let mut line = 0;
+    std::iter::from_fn(move || {
+        while pending.len() < window.max(1) {
+            let record = match records.next() {
+                Some(record) => record,
+                None => break,
+            };
+            let is_dispute = !matches!(
+                record.t.to_ascii_lowercase().as_str(),
+                "deposit" | "withdrawal"
+            );
+            pending.push(Reverse(Pending {
+                key: (record.tx, is_dispute, line),
+                record,
+            }));
+            line += 1;
+        }
+        pending.pop().map(|Reverse(pending)| pending.record)
+    })
+}
+
+// A record waiting to be ordered.
+struct Pending {
+    key: (u32, bool, usize),
+    record: CsvRecord,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
     }
+}
 
-    for t in tasks {
-        let _ = t.await;
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key.cmp(&other.key)
     }
 }
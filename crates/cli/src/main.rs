@@ -1,16 +1,28 @@
 mod csv;
 
+use accounts::actors::account::{AccountActorConfig, LateArrivalPolicy, Scheduling};
+use accounts::actors::account_manager::{SnapshotAccountsRequest, SnapshotAccountsResponse};
 use accounts::actors::aggregators::accounts_state_aggregator::{
     AccountState, AccountsStateActor, AccountsStateAggregator,
 };
 use accounts::actors::aggregators::AggregatorRequests;
+use accounts::actors::clock::TokioClock;
+use accounts::actors::deadline::Deadline;
 use accounts::actors::Actor;
 use accounts::actors::{account_manager::AccountManagerActor, account_shard::AccountShardActor};
 use accounts::broadcast::Broadcast;
+use accounts::domain::dispute_policy::{DepositsOnly, DisputePolicy};
+use accounts::store::journal::{FsyncPolicy, Journal};
+use accounts::store::snapshot::LedgerSnapshot;
 use argh::FromArgs;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
+// How long reading the aggregated results may take.
+const RESULTS_TIMEOUT: Duration = Duration::from_secs(60);
+
 #[derive(FromArgs, PartialEq, Debug)]
 /// Aggregate accounts final positions
 struct Args {
@@ -21,6 +33,51 @@ struct Args {
     /// log verbosity
     #[argh(switch, short = 'v')]
     verbose: bool,
+
+    /// process operations one at a time ordered by transaction id,
+    /// without waiting for out of order operations
+    #[argh(switch, short = 'd')]
+    deterministic: bool,
+
+    /// journal of applied operations (eg: somefolder/journal.log).
+    /// After a crash, run again with the same file and journal to resume
+    #[argh(option, short = 'j')]
+    journal: Option<String>,
+
+    /// ledger snapshot to start from, instead of empty accounts
+    #[argh(option, short = 'r')]
+    restore: Option<String>,
+
+    /// where to save a ledger snapshot after processing the file.
+    /// Restore it to process more files on top of this one
+    #[argh(option, short = 's')]
+    snapshot: Option<String>,
+
+    /// milliseconds each operation has to be applied; operations
+    /// not applied in time are skipped. Waits forever by default
+    #[argh(option, short = 't')]
+    timeout: Option<u64>,
+
+    /// what happens to operations older than ones already applied:
+    /// "apply" them as they come, "reject" them once checkpointed or
+    /// "replay" what came after them. Replay with -j, apply otherwise
+    #[argh(option, short = 'l', from_str_fn(late_arrival))]
+    late_arrival: Option<LateArrivalPolicy>,
+
+    /// stream the file keeping at most this many operations in flight,
+    /// with mailboxes of the same size, so memory stays bounded.
+    /// With -d, how many operations wait to be ordered (65536 by default)
+    #[argh(option, short = 'f')]
+    in_flight: Option<usize>,
+}
+
+fn late_arrival(value: &str) -> Result<LateArrivalPolicy, String> {
+    match value {
+        "apply" => Ok(LateArrivalPolicy::ApplyAnyway),
+        "reject" => Ok(LateArrivalPolicy::Reject),
+        "replay" => Ok(LateArrivalPolicy::Replay),
+        _ => Err(format!("unknown late arrival policy {}", value)),
+    }
 }
 
 fn print_accounts_state(state: &AccountsStateAggregator) {
@@ -52,14 +109,115 @@ async fn main() {
 
     let broadcast = Broadcast::new();
 
-    let aggregator = AccountsStateActor::new(broadcast.clone()).spawn();
+    let dispute_policy: Arc<dyn DisputePolicy> = Arc::new(DepositsOnly);
+    let restored = args
+        .restore
+        .map(|path| LedgerSnapshot::load(path, &dispute_policy).unwrap()); //TODO unwrap
+
+    let aggregator = match &restored {
+        Some(snapshot) => {
+            AccountsStateActor::with_state(snapshot.aggregator.clone(), broadcast.clone())
+        }
+        None => AccountsStateActor::new(broadcast.clone()),
+    }
+    .spawn();
+
+    let mut config = if args.deterministic {
+        AccountActorConfig {
+            scheduling: Scheduling::Deterministic,
+            ..Default::default()
+        }
+    } else {
+        AccountActorConfig::default()
+    };
+
+    let mut journaled = vec![];
+    let mut resuming = false;
+    if let Some(path) = args.journal {
+        // Not compacted, since resuming skips every operation it has
+        let journal = Journal::open(path, FsyncPolicy::Always).unwrap(); //TODO unwrap
+        journaled = journal.requests().unwrap(); //TODO unwrap
+        resuming = !journal.accounts().unwrap().is_empty(); //TODO unwrap
+        config.journal = Some(Arc::new(journal));
+        // Operations resent after a crash are late. They must
+        // still end up where they would have been.
+        config.late_arrival = LateArrivalPolicy::Replay;
+    }
+    if let Some(late_arrival) = args.late_arrival {
+        config.late_arrival = late_arrival;
+    }
+    config.mailbox_capacity = args.in_flight;
+
+    let mut manager =
+        AccountManagerActor::new(0, broadcast, config).with_dispute_policy(dispute_policy);
+    match restored {
+        // The journal already starts from the snapshot, restoring it
+        // again would drop what was applied before the crash
+        Some(_) if resuming => {}
+        Some(snapshot) => manager.restore_accounts(snapshot.accounts).await.unwrap(), //TODO unwrap
+        None => {}
+    }
+    let mut manager = manager.recover().await.unwrap(); //TODO unwrap
+    if let Some(in_flight) = args.in_flight {
+        manager = manager.with_mailbox_capacity(in_flight);
+    }
+    let mut shard = AccountShardActor::new(vec![manager.spawn()]);
+    if let Some(in_flight) = args.in_flight {
+        shard = shard.with_mailbox_capacity(in_flight);
+    }
+    let shard = shard.spawn();
+
+    let timeout = args.timeout.map(Duration::from_millis);
+    if args.deterministic {
+        let window = args.in_flight.unwrap_or(crate::csv::REORDER_WINDOW);
+        crate::csv::process_in_order(shard.clone(), args.input, &journaled, timeout, window).await;
+    } else {
+        let in_flight = args.in_flight;
+        crate::csv::process(shard.clone(), args.input, &journaled, timeout, in_flight).await;
+    }
+
+    // Every request is accepted and every event aggregated before reading the results
+    if let Err(err) = shard.send_drain_async().await {
+        tracing::error!("Accounts not drained: {:?}", err);
+    }
+    if let Err(err) = aggregator.send_drain_async().await {
+        tracing::error!("Events not aggregated: {:?}", err);
+    }
 
-    let manager = AccountManagerActor::new(0, broadcast).spawn();
-    let shard = AccountShardActor::new(vec![manager.clone()]).spawn();
+    let results = || Deadline::after(Arc::new(TokioClock), RESULTS_TIMEOUT);
+    if let Some(path) = args.snapshot {
+        let accounts = match shard
+            .send_snapshot_accounts_async(SnapshotAccountsRequest)
+            .await
+        {
+            Ok(SnapshotAccountsResponse::Ok(accounts)) => accounts,
+            Ok(SnapshotAccountsResponse::Error(err)) | Err(err) => {
+                panic!("Cannot snapshot accounts: {:?}", err) //TODO panic
+            }
+        };
+        let aggregator = aggregator.get_state_before(results()).await.unwrap(); //TODO unwrap
+        LedgerSnapshot {
+            accounts,
+            aggregator,
+        }
+        .save(path)
+        .unwrap(); //TODO unwrap
+    }
 
-    crate::csv::process(shard, args.input).await;
+    if let Err(err) = aggregator
+        .send_before_async(
+            AggregatorRequests::Call(Box::new(print_accounts_state)),
+            results(),
+        )
+        .await
+    {
+        tracing::error!("Accounts not printed: {:?}", err);
+    }
 
-    let _response = aggregator
-        .send_async(AggregatorRequests::Call(Box::new(print_accounts_state)))
-        .await;
+    if let Err(err) = shard.send_stop_async().await {
+        tracing::error!("Accounts not stopped: {:?}", err);
+    }
+    if let Err(err) = aggregator.send_stop_async().await {
+        tracing::error!("Aggregator not stopped: {:?}", err);
+    }
 }